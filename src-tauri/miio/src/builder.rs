@@ -0,0 +1,213 @@
+use rand::Rng;
+use reqwest::{redirect, Client, ClientBuilder, Proxy};
+use std::{iter, sync::RwLock, time::Duration};
+
+use crate::{MiCloudProtocol, MiioError, RegionRegistry, Result, RetryPolicy};
+
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+pub const DEFAULT_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Configures a `MiCloudProtocol` and the HTTP client it shares between all of
+/// its requests, so connections (and their TLS sessions) are reused.
+///
+/// ```
+/// use std::time::Duration;
+/// use miio::MiCloudProtocol;
+///
+/// let mi = MiCloudProtocol::builder()
+///     .connect_timeout(Duration::from_secs(5))
+///     .read_timeout(Duration::from_secs(15))
+///     .locale("de")
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct MiCloudProtocolBuilder {
+    regions: RegionRegistry,
+    retry_policy: RetryPolicy,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    timeout: Duration,
+    pool_idle_timeout: Option<Duration>,
+    pool_max_idle_per_host: usize,
+    proxy: Option<String>,
+    user_agent: Option<String>,
+    locale: String,
+}
+
+impl Default for MiCloudProtocolBuilder {
+    fn default() -> Self {
+        MiCloudProtocolBuilder {
+            regions: RegionRegistry::default(),
+            retry_policy: RetryPolicy::default(),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            timeout: DEFAULT_TIMEOUT,
+            pool_idle_timeout: Some(DEFAULT_POOL_IDLE_TIMEOUT),
+            pool_max_idle_per_host: usize::MAX,
+            proxy: None,
+            user_agent: None,
+            locale: "en".to_string(),
+        }
+    }
+}
+
+impl MiCloudProtocolBuilder {
+    pub fn regions(mut self, regions: RegionRegistry) -> Self {
+        self.regions = regions;
+        self
+    }
+
+    /// How failed requests are retried; `RetryPolicy::none()` sends each request once.
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// Time allowed for establishing a connection, including the TLS handshake.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Time allowed between two reads of a response, so a server that stops
+    /// answering can't hold the caller forever.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+
+    /// Time allowed for a whole request, from connecting to reading the last
+    /// byte of the response, so a server trickling out a response can't hold
+    /// the caller forever either. `DEFAULT_TIMEOUT` unless set.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How long unused connections are kept open; `None` keeps them forever.
+    pub fn pool_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.pool_idle_timeout = timeout;
+        self
+    }
+
+    /// Maximum number of unused connections kept open per host; `0` disables pooling.
+    pub fn pool_max_idle_per_host(mut self, max: usize) -> Self {
+        self.pool_max_idle_per_host = max;
+        self
+    }
+
+    /// Sends every request through a proxy, e.g. `http://127.0.0.1:8080` or
+    /// `socks5://127.0.0.1:1080`.
+    pub fn proxy(mut self, url: impl Into<String>) -> Self {
+        self.proxy = Some(url.into());
+        self
+    }
+
+    /// Replaces the default user agent, which mimics the Mi Home Android app.
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = Some(user_agent.into());
+        self
+    }
+
+    /// Language of the texts returned by Mi Cloud, e.g. `en` or `de`.
+    pub fn locale(mut self, locale: impl Into<String>) -> Self {
+        self.locale = locale.into();
+        self
+    }
+
+    fn client_builder(&self) -> Result<ClientBuilder> {
+        let mut builder = Client::builder()
+            .connect_timeout(self.connect_timeout)
+            .read_timeout(self.read_timeout)
+            .timeout(self.timeout)
+            .pool_idle_timeout(self.pool_idle_timeout)
+            .pool_max_idle_per_host(self.pool_max_idle_per_host);
+        if let Some(proxy) = &self.proxy {
+            let proxy = Proxy::all(proxy)
+                .map_err(|e| MiioError::Invalid(format!("Invalid proxy '{}': {}", proxy, e)))?;
+            builder = builder.proxy(proxy);
+        }
+        Ok(builder)
+    }
+
+    /// Fails with `MiioError::Invalid` for an unusable proxy url.
+    pub fn build(self) -> Result<MiCloudProtocol> {
+        let client = self.client_builder()?.build()?;
+        // Two-factor verification reads the `Location` header of a redirect
+        let no_redirect_client = self
+            .client_builder()?
+            .redirect(redirect::Policy::none())
+            .build()?;
+
+        let mut rng = rand::thread_rng();
+        let user_agent = self.user_agent.unwrap_or_else(|| {
+            let agent_id: String =
+                iter::repeat_with(|| b"ABCDEF"[rng.gen_range(0..b"ABCDEF".len())] as char)
+                    .take(13)
+                    .collect();
+            format!(
+                "Android-7.1.1-1.0.0-ONEPLUS A3010-136-{} APP/xiaomi.smarthome APPV/62830",
+                agent_id
+            )
+        });
+        let client_id: String = iter::repeat_with(|| {
+            b"ABCDEFGHIJKLMNOPQRSTUVWXYZ"[rng.gen_range(0..b"ABCDEFGHIJKLMNOPQRSTUVWXYZ".len())]
+                as char
+        })
+        .take(6)
+        .collect();
+
+        Ok(MiCloudProtocol {
+            regions: self.regions,
+            client,
+            no_redirect_client,
+            retry_policy: self.retry_policy,
+            username: None,
+            password_md5: None,
+            country: "cn".to_string(),
+            user_id: None,
+            tokens: RwLock::new(None),
+            device_regions: RwLock::new(Default::default()),
+            pass_token: RwLock::new(None),
+            refresh_lock: tokio::sync::Mutex::new(()),
+            session_listener: None,
+            user_agent,
+            client_id,
+            locale: self.locale,
+            pending_login: None,
+        })
+    }
+}
+
+impl MiCloudProtocol {
+    pub fn builder() -> MiCloudProtocolBuilder {
+        MiCloudProtocolBuilder::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_with_options() {
+        let mi = MiCloudProtocol::builder()
+            .user_agent("toolkit/1.0")
+            .locale("de")
+            .proxy("http://127.0.0.1:8080")
+            .build()
+            .unwrap();
+        assert_eq!(mi.user_agent, "toolkit/1.0");
+        assert!(mi.get_cookie().contains("locale=de"));
+
+        let err = MiCloudProtocol::builder()
+            .proxy("not a url")
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), "invalid");
+    }
+}
@@ -0,0 +1,204 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::{
+    net::UdpSocket,
+    time::{timeout_at, Instant},
+};
+
+use crate::{
+    error::{Context, MiioError, Result},
+    local::{hello_packet, parse_header, MIIO_PORT},
+    Device,
+};
+
+/// A device that answered the miIO hello broadcast.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredDevice {
+    pub device_id: u32,
+    pub stamp: u32,
+    pub ip: IpAddr,
+}
+
+/// How a cloud device relates to what was found on the LAN.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LocalStatus {
+    /// Answered from the `localip` the cloud reports.
+    Reachable,
+    /// Answered, but from a different address than `localip`.
+    StaleIp,
+    /// Did not answer the broadcast.
+    NotFound,
+}
+
+/// Result of joining the cloud device list with discovery replies.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeviceReachability {
+    pub did: String,
+    pub name: String,
+    pub model: String,
+    pub cloud_ip: String,
+    pub discovered_ip: Option<IpAddr>,
+    pub status: LocalStatus,
+}
+
+/// Broadcasts the miIO hello packet on the local network and collects replies
+/// until `wait` elapses.
+pub async fn discover(wait: Duration) -> Result<Vec<DiscoveredDevice>> {
+    discover_on(
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), MIIO_PORT),
+        wait,
+    )
+    .await
+}
+
+/// Sends the hello packet to `target` (a broadcast or a single address) and
+/// collects replies until `wait` elapses. Replies are deduplicated by device id.
+pub async fn discover_on(target: SocketAddr, wait: Duration) -> Result<Vec<DiscoveredDevice>> {
+    let socket = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .map_err(MiioError::transport)
+        .with_context(|| "Discovery failed: Cannot bind UDP socket")?;
+    socket
+        .set_broadcast(true)
+        .map_err(MiioError::transport)
+        .with_context(|| "Discovery failed: Cannot enable broadcast")?;
+    socket
+        .send_to(&hello_packet(), target)
+        .await
+        .map_err(MiioError::transport)
+        .with_context(|| format!("Discovery failed: Cannot send hello to {}", target))?;
+
+    let deadline = Instant::now() + wait;
+    let mut found: Vec<DiscoveredDevice> = vec![];
+    let mut buf = [0u8; 1024];
+    while let Ok(received) = timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        // Windows reports an ICMP port unreachable for the broadcast as a
+        // connection reset on the next receive; keep listening until the deadline.
+        // Any other receive error would repeat, so it ends the discovery
+        let (len, peer) = match received {
+            Ok(received) => received,
+            Err(e) if e.kind() == ErrorKind::ConnectionReset => continue,
+            Err(e) => {
+                return Err(MiioError::transport(e))
+                    .with_context(|| "Discovery failed: Cannot receive replies")
+            }
+        };
+        // Anything that is not a hello reply (e.g. our own broadcast) is ignored
+        let header = match parse_header(&buf[..len]) {
+            Ok(header) if header.device_id != u32::MAX => header,
+            _ => continue,
+        };
+        if found.iter().any(|d| d.device_id == header.device_id) {
+            continue;
+        }
+        found.push(DiscoveredDevice {
+            device_id: header.device_id,
+            stamp: header.stamp,
+            ip: peer.ip(),
+        });
+    }
+
+    Ok(found)
+}
+
+/// Joins the cloud device list with discovery replies by `did`, reporting which
+/// devices are reachable locally and which `localip` values are stale.
+pub fn match_discovered(
+    devices: &[Device],
+    discovered: &[DiscoveredDevice],
+) -> Vec<DeviceReachability> {
+    let by_id: HashMap<u32, &DiscoveredDevice> =
+        discovered.iter().map(|d| (d.device_id, d)).collect();
+
+    devices
+        .iter()
+        .map(|device| {
+            let found = device.did.parse::<u32>().ok().and_then(|id| by_id.get(&id));
+            let status = match found {
+                Some(d) if d.ip.to_string() == device.localip => LocalStatus::Reachable,
+                Some(_) => LocalStatus::StaleIp,
+                None => LocalStatus::NotFound,
+            };
+            DeviceReachability {
+                did: device.did.clone(),
+                name: device.name.clone(),
+                model: device.model.clone(),
+                cloud_ip: device.localip.clone(),
+                discovered_ip: found.map(|d| d.ip),
+                status,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::device;
+
+    #[tokio::test]
+    async fn discover_on_collects_replies() {
+        let stand_in = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = stand_in.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let (_, peer) = stand_in.recv_from(&mut buf).await.unwrap();
+            let mut reply = hello_packet();
+            reply[8..12].copy_from_slice(&4321u32.to_be_bytes());
+            reply[12..16].copy_from_slice(&77u32.to_be_bytes());
+            // Answer twice, the duplicate must be dropped
+            stand_in.send_to(&reply, peer).await.unwrap();
+            stand_in.send_to(&reply, peer).await.unwrap();
+        });
+
+        let found = discover_on(target, Duration::from_millis(200))
+            .await
+            .unwrap();
+        assert_eq!(
+            found,
+            vec![DiscoveredDevice {
+                device_id: 4321,
+                stamp: 77,
+                ip: "127.0.0.1".parse().unwrap(),
+            }]
+        );
+    }
+
+    #[test]
+    fn match_discovered() {
+        let devices = vec![
+            device("1", "192.168.1.10"),
+            device("2", "192.168.1.11"),
+            device("3", "192.168.1.12"),
+        ];
+        let discovered = vec![
+            DiscoveredDevice {
+                device_id: 1,
+                stamp: 5,
+                ip: "192.168.1.10".parse().unwrap(),
+            },
+            DiscoveredDevice {
+                device_id: 2,
+                stamp: 5,
+                ip: "192.168.1.50".parse().unwrap(),
+            },
+        ];
+
+        let result = super::match_discovered(&devices, &discovered);
+        assert_eq!(result[0].status, LocalStatus::Reachable);
+        assert_eq!(result[1].status, LocalStatus::StaleIp);
+        assert_eq!(
+            result[1].discovered_ip,
+            Some("192.168.1.50".parse().unwrap())
+        );
+        assert_eq!(result[2].status, LocalStatus::NotFound);
+        assert_eq!(result[2].discovered_ip, None);
+    }
+}
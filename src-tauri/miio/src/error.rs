@@ -0,0 +1,165 @@
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+use std::fmt::Display;
+use thiserror::Error;
+
+pub type Result<T, E = MiioError> = std::result::Result<T, E>;
+
+/// Errors returned by the `miio` crate.
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum MiioError {
+    /// The request never got an answer: connection refused, DNS, TLS, timeouts or UDP socket errors.
+    #[error("{0}")]
+    Transport(String),
+    /// The server answered with a non-success HTTP status.
+    #[error("Request error: Status {status}")]
+    Http { status: u16 },
+    /// No session is available, `login` has to be called first.
+    #[error("Request error: Not logged in")]
+    NotLoggedIn,
+    /// The service token was rejected; the session has to be refreshed or the user logged in again.
+    #[error("Request error: Authentication expired")]
+    AuthExpired,
+    /// The requested server region is unknown.
+    #[error("Request error: Server Location {0} is not supported")]
+    UnsupportedRegion(String),
+    /// Mi Cloud (or a device over the LAN) answered with an error code.
+    #[error("{message}")]
+    Cloud { code: i64, message: String },
+    /// A response could not be parsed.
+    #[error("{0}")]
+    Parse(String),
+    /// The login flow was rejected or returned something unexpected.
+    #[error("{0}")]
+    Login(String),
+    /// Caller supplied input that cannot be used (malformed token, address, model...).
+    #[error("{0}")]
+    Invalid(String),
+    /// Reading or writing local files failed.
+    #[error("{0}")]
+    Io(String),
+}
+
+impl MiioError {
+    /// Short machine-readable name of the variant, e.g. `"auth_expired"`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            MiioError::Transport(_) => "transport",
+            MiioError::Http { .. } => "http",
+            MiioError::NotLoggedIn => "not_logged_in",
+            MiioError::AuthExpired => "auth_expired",
+            MiioError::UnsupportedRegion(_) => "unsupported_region",
+            MiioError::Cloud { .. } => "cloud",
+            MiioError::Parse(_) => "parse",
+            MiioError::Login(_) => "login",
+            MiioError::Invalid(_) => "invalid",
+            MiioError::Io(_) => "io",
+        }
+    }
+
+    /// Numeric error code reported by Mi Cloud, or the HTTP status.
+    pub fn code(&self) -> Option<i64> {
+        match self {
+            MiioError::Cloud { code, .. } => Some(*code),
+            MiioError::Http { status } => Some(*status as i64),
+            _ => None,
+        }
+    }
+
+    pub fn is_auth_expired(&self) -> bool {
+        matches!(self, MiioError::AuthExpired)
+    }
+
+    /// Wraps socket errors, which are transport failures rather than file I/O.
+    pub(crate) fn transport(e: impl Display) -> Self {
+        MiioError::Transport(e.to_string())
+    }
+
+    /// Prefixes the message with `context`. Variants that carry structured data
+    /// (status, code) are returned unchanged.
+    pub fn context(self, context: impl Display) -> Self {
+        let prefix = |message: String| format!("{}: {}", context, message);
+        match self {
+            MiioError::Transport(m) => MiioError::Transport(prefix(m)),
+            MiioError::Parse(m) => MiioError::Parse(prefix(m)),
+            MiioError::Login(m) => MiioError::Login(prefix(m)),
+            MiioError::Invalid(m) => MiioError::Invalid(prefix(m)),
+            MiioError::Io(m) => MiioError::Io(prefix(m)),
+            other => other,
+        }
+    }
+}
+
+impl From<reqwest::Error> for MiioError {
+    fn from(e: reqwest::Error) -> Self {
+        if let Some(status) = e.status() {
+            MiioError::Http {
+                status: status.as_u16(),
+            }
+        } else if e.is_decode() {
+            MiioError::Parse(e.to_string())
+        } else {
+            MiioError::Transport(e.to_string())
+        }
+    }
+}
+
+impl From<serde_json::Error> for MiioError {
+    fn from(e: serde_json::Error) -> Self {
+        MiioError::Parse(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for MiioError {
+    fn from(e: std::io::Error) -> Self {
+        MiioError::Io(e.to_string())
+    }
+}
+
+/// Serialized as `{ kind, code, message }` so the frontend can react to the kind.
+impl Serialize for MiioError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("MiioError", 3)?;
+        state.serialize_field("kind", self.kind())?;
+        state.serialize_field("code", &self.code())?;
+        state.serialize_field("message", &self.to_string())?;
+        state.end()
+    }
+}
+
+/// `anyhow`-style `with_context` for results whose error converts into `MiioError`.
+pub(crate) trait Context<T> {
+    fn with_context<C: Display, F: FnOnce() -> C>(self, f: F) -> Result<T>;
+}
+
+impl<T, E: Into<MiioError>> Context<T> for std::result::Result<T, E> {
+    fn with_context<C: Display, F: FnOnce() -> C>(self, f: F) -> Result<T> {
+        self.map_err(|e| e.into().context(f()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn context_keeps_structured_variants() {
+        let err = MiioError::Transport("connection refused".to_string()).context("Login failed");
+        assert_eq!(err.to_string(), "Login failed: connection refused");
+
+        let err = MiioError::Http { status: 500 }.context("Login failed");
+        assert_eq!(err, MiioError::Http { status: 500 });
+    }
+
+    #[test]
+    fn serialize() {
+        let err = MiioError::Cloud {
+            code: -2,
+            message: "bad request".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_value(&err).unwrap(),
+            json!({ "kind": "cloud", "code": -2, "message": "bad request" })
+        );
+    }
+}
@@ -0,0 +1,343 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fmt::Write;
+
+use crate::{error::Context, Device, MiCloudErrorResponse, MiCloudProtocol, Result};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    Json,
+    Csv,
+    /// Device list with the fields python-miio needs to connect
+    PythonMiioYaml,
+    /// `xiaomi_miio` platform entries for Home Assistant's `configuration.yaml`
+    HomeAssistantYaml,
+}
+
+/// What is needed to control a device locally.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeviceCredentials {
+    pub name: String,
+    pub model: String,
+    pub did: String,
+    pub ip: String,
+    pub mac: String,
+    pub token: String,
+    /// Beacon key of Bluetooth devices, used to decrypt their advertisements
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ble_key: Option<String>,
+    /// Why the beacon key of a Bluetooth device couldn't be fetched
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ble_key_error: Option<String>,
+}
+
+impl DeviceCredentials {
+    /// `ble_key` is the outcome of `get_beacon_key`, `None` for devices that
+    /// aren't Bluetooth devices.
+    pub fn new(device: &Device, ble_key: Option<Result<String>>) -> Self {
+        let (ble_key, ble_key_error) = match ble_key {
+            Some(Ok(key)) => (Some(key), None),
+            Some(Err(e)) => (None, Some(e.to_string())),
+            None => (None, None),
+        };
+        DeviceCredentials {
+            name: device.name.clone(),
+            model: device.model.clone(),
+            did: device.did.clone(),
+            ip: device.localip.clone(),
+            mac: device.mac.clone(),
+            token: device.token.clone(),
+            ble_key,
+            ble_key_error,
+        }
+    }
+}
+
+impl Device {
+    /// Bluetooth devices have ids like `blt.3.abc`; they have a beacon key instead of a token.
+    pub fn is_bluetooth(&self) -> bool {
+        self.did.starts_with("blt.")
+    }
+}
+
+impl MiCloudProtocol {
+    /// Fetches the beacon key of a Bluetooth device.
+    pub async fn get_beacon_key(&self, device_id: &str, country: Option<&str>) -> Result<String> {
+        let req = json!({ "did": device_id, "pdid": 1 });
+        let country = self.device_country(device_id, country);
+        let fallback_msg = format!("Get beacon key for device {} failed", device_id);
+        let res = self
+            .request_encrypted("/v2/device/blt_get_beaconkey", req, &country)
+            .await
+            .with_context(|| fallback_msg.clone())?;
+
+        match res["result"]["beaconkey"].as_str() {
+            Some(key) => Ok(key.to_string()),
+            None => {
+                let parsed_err: MiCloudErrorResponse = serde_json::from_value(res.clone())?;
+                Err(parsed_err.into_error(&fallback_msg))
+            }
+        }
+    }
+}
+
+/// Renders the credentials of `devices` in `format`.
+pub fn export_credentials(devices: &[DeviceCredentials], format: ExportFormat) -> Result<String> {
+    match format {
+        ExportFormat::Json => Ok(serde_json::to_string_pretty(devices)?),
+        ExportFormat::Csv => Ok(to_csv(devices)),
+        ExportFormat::PythonMiioYaml => Ok(to_python_miio_yaml(devices)),
+        ExportFormat::HomeAssistantYaml => Ok(to_home_assistant_yaml(devices)),
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn to_csv(devices: &[DeviceCredentials]) -> String {
+    let mut csv = String::from("name,model,did,ip,mac,token,ble_key,ble_key_error\n");
+    for device in devices {
+        let fields = [
+            &device.name,
+            &device.model,
+            &device.did,
+            &device.ip,
+            &device.mac,
+            &device.token,
+            device.ble_key.as_deref().unwrap_or(""),
+            device.ble_key_error.as_deref().unwrap_or(""),
+        ];
+        let row: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+/// Double quoted YAML scalar, so names like `yes` or `#1` stay strings.
+fn yaml_str(value: &str) -> String {
+    serde_json::to_string(value).unwrap_or_default()
+}
+
+fn to_python_miio_yaml(devices: &[DeviceCredentials]) -> String {
+    let mut yaml = String::from("devices:\n");
+    for device in devices {
+        let _ = writeln!(yaml, "  - name: {}", yaml_str(&device.name));
+        let _ = writeln!(yaml, "    model: {}", yaml_str(&device.model));
+        let _ = writeln!(yaml, "    did: {}", yaml_str(&device.did));
+        let _ = writeln!(yaml, "    ip: {}", yaml_str(&device.ip));
+        let _ = writeln!(yaml, "    mac: {}", yaml_str(&device.mac));
+        let _ = writeln!(yaml, "    token: {}", yaml_str(&device.token));
+        if let Some(ble_key) = &device.ble_key {
+            let _ = writeln!(yaml, "    ble_key: {}", yaml_str(ble_key));
+        }
+        if let Some(error) = &device.ble_key_error {
+            let _ = writeln!(yaml, "    # ble_key unavailable: {}", yaml_str(error));
+        }
+    }
+    yaml
+}
+
+/// Home Assistant domain of the `xiaomi_miio` YAML platform for a model, from
+/// its kind, e.g. `light` for `yeelink.light.color1`.
+fn home_assistant_domain(model: &str) -> Option<&'static str> {
+    let kind = model.split('.').nth(1)?;
+    match kind {
+        "light" | "bulb" | "ceiling" | "lamp" => Some("light"),
+        "plug" | "switch" | "powerstrip" | "ctrl_neutral1" | "ctrl_neutral2" => Some("switch"),
+        "fan" | "airpurifier" | "airfresh" | "humidifier" => Some("fan"),
+        "vacuum" => Some("vacuum"),
+        "ir" | "remote" => Some("remote"),
+        "airmonitor" | "airquality" => Some("sensor"),
+        _ => None,
+    }
+}
+
+fn to_home_assistant_yaml(devices: &[DeviceCredentials]) -> String {
+    // The platform talks to devices over the LAN, so it needs both
+    let (configurable, skipped): (Vec<&DeviceCredentials>, Vec<&DeviceCredentials>) =
+        devices.iter().partition(|d| {
+            home_assistant_domain(&d.model).is_some() && !d.ip.is_empty() && !d.token.is_empty()
+        });
+
+    let mut yaml = String::new();
+    for domain in ["light", "switch", "fan", "vacuum", "remote", "sensor"] {
+        let entries: Vec<&&DeviceCredentials> = configurable
+            .iter()
+            .filter(|d| home_assistant_domain(&d.model) == Some(domain))
+            .collect();
+        if entries.is_empty() {
+            continue;
+        }
+        let _ = writeln!(yaml, "{}:", domain);
+        for device in entries {
+            let _ = writeln!(yaml, "  - platform: xiaomi_miio");
+            let _ = writeln!(yaml, "    name: {}", yaml_str(&device.name));
+            let _ = writeln!(yaml, "    host: {}", yaml_str(&device.ip));
+            let _ = writeln!(yaml, "    token: {}", yaml_str(&device.token));
+            let _ = writeln!(yaml, "    model: {}", yaml_str(&device.model));
+        }
+    }
+
+    // Keep the file valid YAML while still listing what couldn't be configured
+    if !skipped.is_empty() {
+        yaml.push_str("# Not configurable with the xiaomi_miio YAML platform:\n");
+        for device in skipped {
+            // Quoted, as a line break in a name would end the comment
+            let _ = writeln!(
+                yaml,
+                "#   {} ({}, did {})",
+                yaml_str(&device.name),
+                device.model,
+                device.did
+            );
+        }
+    }
+    yaml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fixtures::device_with,
+        test_server::{serve, StubRequest, StubResponse},
+        MiioError, RegionRegistry, SecureSession,
+    };
+
+    fn credentials() -> Vec<DeviceCredentials> {
+        let lamp = device_with(
+            "1",
+            json!({ "name": "Desk, \"big\" lamp", "localip": "192.168.1.2", "mac": "AA:BB" }),
+        );
+        let sensor = device_with(
+            "blt.3.abc",
+            json!({ "name": "Thermometer", "model": "miaomiaoce.sensor_ht.t2", "token": "" }),
+        );
+        let offline = device_with(
+            "blt.3.def",
+            json!({ "name": "Door\nsensor", "model": "lumi.sensor_magnet.v2", "token": "" }),
+        );
+        vec![
+            DeviceCredentials::new(&lamp, None),
+            DeviceCredentials::new(&sensor, Some(Ok("00ff".to_string()))),
+            DeviceCredentials::new(&offline, Some(Err(MiioError::Http { status: 503 }))),
+        ]
+    }
+
+    #[test]
+    fn bluetooth_devices() {
+        assert!(device_with("blt.3.abc", json!({})).is_bluetooth());
+        assert!(!device_with("123", json!({})).is_bluetooth());
+    }
+
+    #[test]
+    fn export_json_and_csv() {
+        let json = export_credentials(&credentials(), ExportFormat::Json).unwrap();
+        let parsed: Vec<DeviceCredentials> = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, credentials());
+        assert!(!json.contains("\"ble_key\": null"));
+        assert!(!json.contains("\"ble_key_error\": null"));
+        assert!(parsed[2].ble_key_error.is_some());
+
+        let csv = export_credentials(&credentials(), ExportFormat::Csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            lines[0],
+            "name,model,did,ip,mac,token,ble_key,ble_key_error"
+        );
+        assert_eq!(
+            lines[1],
+            "\"Desk, \"\"big\"\" lamp\",yeelink.light.color1,1,192.168.1.2,AA:BB,00112233445566778899aabbccddeeff,,"
+        );
+        assert_eq!(
+            lines[2],
+            "Thermometer,miaomiaoce.sensor_ht.t2,blt.3.abc,,,,00ff,"
+        );
+        assert!(csv.ends_with(&format!(",,{}\n", MiioError::Http { status: 503 })));
+    }
+
+    #[test]
+    fn export_python_miio_yaml() {
+        let yaml = export_credentials(&credentials(), ExportFormat::PythonMiioYaml).unwrap();
+        assert!(yaml.starts_with("devices:\n  - name: \"Desk, \\\"big\\\" lamp\"\n"));
+        assert!(yaml.contains("    ip: \"192.168.1.2\"\n"));
+        assert!(yaml.contains("    ble_key: \"00ff\"\n"));
+        assert!(yaml.contains("  - name: \"Door\\nsensor\"\n"));
+        assert!(yaml.ends_with(&format!(
+            "    # ble_key unavailable: \"{}\"\n",
+            MiioError::Http { status: 503 }
+        )));
+        assert_eq!(yaml.matches("ble_key: ").count(), 1);
+    }
+
+    #[test]
+    fn export_home_assistant_yaml() {
+        let yaml = export_credentials(&credentials(), ExportFormat::HomeAssistantYaml).unwrap();
+        assert_eq!(
+            yaml,
+            "light:\n  \
+             - platform: xiaomi_miio\n    \
+             name: \"Desk, \\\"big\\\" lamp\"\n    \
+             host: \"192.168.1.2\"\n    \
+             token: \"00112233445566778899aabbccddeeff\"\n    \
+             model: \"yeelink.light.color1\"\n\
+             # Not configurable with the xiaomi_miio YAML platform:\n\
+             #   \"Thermometer\" (miaomiaoce.sensor_ht.t2, did blt.3.abc)\n\
+             #   \"Door\\nsensor\" (lumi.sensor_magnet.v2, did blt.3.def)\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn beacon_key_lookup() {
+        const SSECURITY: &str = "/pM9bFOMTk4/6sSeqnpchA==";
+        let base = serve(|req: StubRequest| {
+            let field = |name: &str| {
+                req.body
+                    .split('&')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(k, _)| *k == name)
+                    .map(|(_, v)| urlencoding::decode(v).unwrap().into_owned())
+                    .unwrap()
+            };
+            // Answer encrypted with the signed nonce, like the real endpoint
+            let mi = MiCloudProtocol::new();
+            let signed_nonce = mi.signed_nonce(SSECURITY, &field("_nonce"));
+            let data = mi.decrypt_rc4(&signed_nonce, &field("data")).unwrap();
+            let data: serde_json::Value = serde_json::from_slice(&data).unwrap();
+            let res = match data["did"].as_str() {
+                Some("blt.3.abc") if req.path == "/v2/device/blt_get_beaconkey" => {
+                    json!({ "code": 0, "result": { "beaconkey": "00ff" } })
+                }
+                _ => json!({ "code": -6, "message": "device not found" }),
+            };
+            StubResponse::ok(mi.encrypt_rc4(&signed_nonce, &res.to_string()))
+        })
+        .await;
+
+        let mut mi = MiCloudProtocol::builder()
+            .regions(RegionRegistry::with_base_url(&base))
+            .build()
+            .unwrap();
+        mi.import_secure_session(SecureSession {
+            username: "username".to_string(),
+            ssecurity: SSECURITY.to_string(),
+            user_id: "42".to_string(),
+            country: "de".to_string(),
+            service_token: "token".to_string(),
+            client_id: "ABCDEF".to_string(),
+            pass_token: None,
+            device_regions: Default::default(),
+        });
+
+        assert_eq!(mi.get_beacon_key("blt.3.abc", None).await.unwrap(), "00ff");
+        let err = mi.get_beacon_key("blt.3.def", None).await.unwrap_err();
+        assert_eq!(err.code(), Some(-6));
+    }
+}
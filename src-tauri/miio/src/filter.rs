@@ -0,0 +1,172 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{Device, Home};
+
+/// Selects devices by model and location, e.g. every `yeelink.light.*` in the
+/// room "Kitchen". Unset fields match every device.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct DeviceFilter {
+    /// Model glob; `*` matches any run of characters, `?` a single one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// Home id or name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub home: Option<String>,
+    /// Room id or name, in any home unless `home` is set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+}
+
+impl DeviceFilter {
+    /// Whether the filter needs the home list to be evaluated.
+    pub fn needs_homes(&self) -> bool {
+        self.home.is_some() || self.room.is_some()
+    }
+
+    /// The devices matching the filter, in their original order. `homes` is only
+    /// consulted when filtering by home or room.
+    pub fn select(&self, homes: &[Home], devices: Vec<Device>) -> Vec<Device> {
+        let located: Option<Vec<&String>> = if self.needs_homes() {
+            Some(self.located_dids(homes))
+        } else {
+            None
+        };
+
+        devices
+            .into_iter()
+            .filter(|device| match &self.model {
+                Some(pattern) => glob_match(pattern, &device.model),
+                None => true,
+            })
+            .filter(|device| match &located {
+                Some(dids) => dids.contains(&&device.did),
+                None => true,
+            })
+            .collect()
+    }
+
+    fn located_dids<'a>(&self, homes: &'a [Home]) -> Vec<&'a String> {
+        homes
+            .iter()
+            .filter(|home| match &self.home {
+                Some(home_ref) => name_or_id(home_ref, &home.id, &home.name),
+                None => true,
+            })
+            .flat_map(|home| match &self.room {
+                Some(room_ref) => home
+                    .rooms
+                    .iter()
+                    .filter(|room| name_or_id(room_ref, &room.id, &room.name))
+                    .flat_map(|room| room.dids.iter())
+                    .collect::<Vec<_>>(),
+                None => home.all_dids().collect(),
+            })
+            .collect()
+    }
+}
+
+fn name_or_id(reference: &str, id: &str, name: &str) -> bool {
+    reference == id || reference.eq_ignore_ascii_case(name)
+}
+
+/// Matches `text` against a glob where `*` stands for any run of characters
+/// and `?` for exactly one.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and the text position it was tried at
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                // Let the last `*` swallow one more character
+                Some((star, star_t)) => {
+                    backtrack = Some((star, star_t + 1));
+                    p = star + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::device_with;
+    use serde_json::json;
+
+    #[test]
+    fn glob() {
+        assert!(glob_match("yeelink.light.*", "yeelink.light.color1"));
+        assert!(glob_match("*.light.*", "philips.light.bulb"));
+        assert!(glob_match("chuangmi.plug.?1", "chuangmi.plug.m1"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("zhimi.airpurifier.ma4", "zhimi.airpurifier.ma4"));
+        assert!(!glob_match("yeelink.light.*", "yeelink.bhf_light.v1"));
+        assert!(!glob_match("chuangmi.plug.?1", "chuangmi.plug.m11"));
+        assert!(!glob_match("*.plug", "chuangmi.plug.m1"));
+    }
+
+    #[test]
+    fn select_by_model_and_room() {
+        let homes: Vec<Home> = serde_json::from_value(json!([
+            {
+                "id": "100", "name": "Flat", "dids": ["4"],
+                "roomlist": [
+                    { "id": "101", "name": "Kitchen", "dids": ["1", "2"] },
+                    { "id": "102", "name": "Bedroom", "dids": ["3"] }
+                ]
+            }
+        ]))
+        .unwrap();
+        let devices = || {
+            [
+                ("1", "yeelink.light.color1"),
+                ("2", "chuangmi.plug.m1"),
+                ("3", "yeelink.light.ceiling1"),
+                ("4", "yeelink.light.lamp1"),
+            ]
+            .iter()
+            .map(|(did, model)| device_with(did, json!({ "model": model })))
+            .collect::<Vec<_>>()
+        };
+        let dids = |devices: Vec<Device>| devices.into_iter().map(|d| d.did).collect::<Vec<_>>();
+
+        let lights = DeviceFilter {
+            model: Some("yeelink.light.*".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(dids(lights.select(&[], devices())), vec!["1", "3", "4"]);
+
+        let kitchen_lights = DeviceFilter {
+            room: Some("kitchen".to_string()),
+            ..lights.clone()
+        };
+        assert_eq!(dids(kitchen_lights.select(&homes, devices())), vec!["1"]);
+
+        let flat = DeviceFilter {
+            home: Some("100".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(dids(flat.select(&homes, devices())).len(), 4);
+
+        let elsewhere = DeviceFilter {
+            home: Some("Cottage".to_string()),
+            ..Default::default()
+        };
+        assert!(elsewhere.select(&homes, devices()).is_empty());
+    }
+}
@@ -0,0 +1,29 @@
+//! Shared fixtures for unit tests.
+
+use serde_json::{json, Value};
+
+use crate::Device;
+
+/// A cloud `Device` with plausible defaults for every field.
+pub fn device(did: &str, localip: &str) -> Device {
+    device_with(did, json!({ "localip": localip }))
+}
+
+/// Like `device`, with the fields in `overrides` replaced.
+pub fn device_with(did: &str, overrides: Value) -> Device {
+    let mut value = json!({
+        "adminFlag": 1, "bssid": "", "desc": "", "did": did, "extra": {},
+        "family_id": 0, "isOnline": true, "latitude": "0", "localip": "",
+        "longitude": "0", "mac": "", "model": "yeelink.light.color1",
+        "name": format!("Device {}", did), "p2p_id": "", "parent_id": "",
+        "parent_model": "", "password": "", "pd_id": 0, "permitLevel": 16,
+        "pid": "0", "reset_flag": 0, "rssi": -50, "shareFlag": 0, "show_mode": 1,
+        "ssid": "", "token": "00112233445566778899aabbccddeeff", "uid": 1
+    });
+    if let (Some(value), Some(overrides)) = (value.as_object_mut(), overrides.as_object()) {
+        for (k, v) in overrides {
+            value.insert(k.clone(), v.clone());
+        }
+    }
+    serde_json::from_value(value).unwrap()
+}
@@ -0,0 +1,199 @@
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+use crate::{error::Context, Device, MiCloudErrorResponse, MiCloudProtocol, Result};
+
+/// A Mi Home "home" with its rooms.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Home {
+    #[serde(deserialize_with = "id_string")]
+    pub id: String,
+    pub name: String,
+    /// Owner of the home; differs from the logged in user for shared homes
+    #[serde(default)]
+    pub uid: Option<i64>,
+    /// Devices of the home that are not assigned to a room
+    #[serde(default)]
+    pub dids: Vec<String>,
+    #[serde(default, rename(deserialize = "roomlist"))]
+    pub rooms: Vec<Room>,
+}
+
+impl Home {
+    /// Every device id of the home, room by room, then the unassigned ones.
+    pub fn all_dids(&self) -> impl Iterator<Item = &String> {
+        self.rooms
+            .iter()
+            .flat_map(|room| room.dids.iter())
+            .chain(self.dids.iter())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Room {
+    #[serde(deserialize_with = "id_string")]
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub dids: Vec<String>,
+}
+
+/// Devices of one home, grouped by room.
+#[derive(Serialize, Debug)]
+pub struct HomeDevices {
+    pub id: String,
+    pub name: String,
+    pub rooms: Vec<RoomDevices>,
+    /// Devices of the home without a room
+    pub devices: Vec<Device>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RoomDevices {
+    pub id: String,
+    pub name: String,
+    pub devices: Vec<Device>,
+}
+
+/// Result of `group_by_home`.
+#[derive(Serialize, Debug)]
+pub struct DevicesByHome {
+    pub homes: Vec<HomeDevices>,
+    /// Devices not listed in any home (e.g. shared devices)
+    pub other: Vec<Device>,
+}
+
+// Home and room ids come back as numbers or strings depending on the endpoint
+fn id_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    match Value::deserialize(deserializer)? {
+        Value::String(s) => Ok(s),
+        Value::Number(n) => Ok(n.to_string()),
+        other => Err(serde::de::Error::custom(format!("Invalid id {}", other))),
+    }
+}
+
+#[derive(Deserialize)]
+struct HomeListResponse {
+    #[serde(default)]
+    homelist: Vec<Home>,
+}
+
+/// Distributes `devices` over the homes and rooms they are assigned to, keeping
+/// the order of the home and room lists.
+pub fn group_by_home(homes: &[Home], devices: Vec<Device>) -> DevicesByHome {
+    let mut by_did: HashMap<String, Device> = HashMap::new();
+    let mut order: Vec<String> = vec![];
+    for device in devices {
+        order.push(device.did.clone());
+        by_did.insert(device.did.clone(), device);
+    }
+    let mut take = |dids: &[String]| -> Vec<Device> {
+        dids.iter().filter_map(|did| by_did.remove(did)).collect()
+    };
+
+    let homes = homes
+        .iter()
+        .map(|home| HomeDevices {
+            id: home.id.clone(),
+            name: home.name.clone(),
+            rooms: home
+                .rooms
+                .iter()
+                .map(|room| RoomDevices {
+                    id: room.id.clone(),
+                    name: room.name.clone(),
+                    devices: take(&room.dids),
+                })
+                .collect(),
+            devices: take(&home.dids),
+        })
+        .collect();
+    let other = take(&order);
+
+    DevicesByHome { homes, other }
+}
+
+impl MiCloudProtocol {
+    /// Lists the homes of the user, including homes shared with them, with
+    /// their rooms and the device ids assigned to each room.
+    pub async fn get_homes(&self, country: Option<&str>) -> Result<Vec<Home>> {
+        let req = json!({
+            "fg": true,
+            "fetch_share": true,
+            "fetch_share_dev": true,
+            "limit": 300,
+            "app_ver": 7
+        });
+        let country = country.unwrap_or(self.country.as_str());
+        let res = self
+            .request_encrypted("/v2/homeroom/gethome", req, country)
+            .await
+            .with_context(|| "Get homes failed")?;
+
+        if !res["result"].is_null() {
+            let parsed_res: HomeListResponse = serde_json::from_value(res["result"].clone())?;
+            Ok(parsed_res.homelist)
+        } else {
+            let parsed_err: MiCloudErrorResponse = serde_json::from_value(res.clone())?;
+            Err(parsed_err.into_error("Get homes failed"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::device;
+
+    fn homes() -> Vec<Home> {
+        let res = json!({
+            "homelist": [
+                {
+                    "id": "100", "name": "Flat", "uid": 42, "dids": ["3"],
+                    "roomlist": [
+                        { "id": "101", "name": "Kitchen", "dids": ["1"] },
+                        { "id": 102, "name": "Bedroom", "dids": ["2", "9"] }
+                    ]
+                },
+                { "id": 200, "name": "Cottage" }
+            ],
+            "has_more": false
+        });
+        serde_json::from_value::<HomeListResponse>(res)
+            .unwrap()
+            .homelist
+    }
+
+    #[test]
+    fn parse_homes() {
+        let homes = homes();
+        assert_eq!(homes.len(), 2);
+        assert_eq!(homes[0].rooms[1].id, "102");
+        assert_eq!(homes[0].uid, Some(42));
+        assert_eq!(
+            homes[0].all_dids().collect::<Vec<_>>(),
+            vec!["1", "2", "9", "3"]
+        );
+        assert_eq!(homes[1].id, "200");
+        assert!(homes[1].rooms.is_empty());
+    }
+
+    #[test]
+    fn group_devices_by_home() {
+        let devices = ["1", "2", "3", "4"]
+            .iter()
+            .map(|did| device(did, ""))
+            .collect();
+        let grouped = group_by_home(&homes(), devices);
+
+        let flat = &grouped.homes[0];
+        assert_eq!(flat.rooms[0].name, "Kitchen");
+        assert_eq!(flat.rooms[0].devices[0].did, "1");
+        assert_eq!(flat.rooms[1].devices.len(), 1);
+        assert_eq!(flat.devices[0].did, "3");
+        assert!(grouped.homes[1].rooms.is_empty());
+        assert_eq!(grouped.other.len(), 1);
+        assert_eq!(grouped.other[0].did, "4");
+    }
+}
@@ -0,0 +1,425 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::{spec::PropertySpec, Device, DeviceSpec};
+
+/// Topic prefix Home Assistant listens on for discovery configs.
+pub const DISCOVERY_PREFIX: &str = "homeassistant";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Component {
+    Switch,
+    Light,
+    Sensor,
+}
+
+impl Component {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Component::Switch => "switch",
+            Component::Light => "light",
+            Component::Sensor => "sensor",
+        }
+    }
+}
+
+/// One Home Assistant entity of a device, with the discovery config to publish
+/// (retained) on `topic`.
+///
+/// State is read from `<state_prefix>/<did>/<key>`, where the key is a legacy
+/// property name or `<siid>.<piid>`, the same keys the bridge publishes polled
+/// values under, and written to `<state_prefix>/<did>/set/<key>`. `props` and
+/// `miot_props` list what has to be polled for it.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DiscoveryEntity {
+    pub component: Component,
+    pub topic: String,
+    pub config: Value,
+    pub props: Vec<String>,
+    pub miot_props: Vec<(u32, u32)>,
+}
+
+// (MIoT property name, legacy property name, device class, unit, state class)
+const SENSORS: &[(&str, &str, &str, &str, &str)] = &[
+    (
+        "temperature",
+        "temperature",
+        "temperature",
+        "°C",
+        "measurement",
+    ),
+    (
+        "relative-humidity",
+        "humidity",
+        "humidity",
+        "%",
+        "measurement",
+    ),
+    ("pm2.5-density", "aqi", "pm25", "µg/m³", "measurement"),
+    ("co2-density", "co2", "carbon_dioxide", "ppm", "measurement"),
+    (
+        "illumination",
+        "illumination",
+        "illuminance",
+        "lx",
+        "measurement",
+    ),
+    ("battery-level", "battery", "battery", "%", "measurement"),
+    ("electric-power", "load_power", "power", "W", "measurement"),
+    (
+        "power-consumption",
+        "power_consume_rate",
+        "energy",
+        "kWh",
+        "total_increasing",
+    ),
+];
+
+/// Builds the discovery configs of a device from its MIoT spec, or from the
+/// properties common to legacy models of its kind when there is no spec:
+/// plugs become switches, lights become lights, and every known sensor
+/// property becomes a sensor.
+///
+/// The switch or light of a device is announced on
+/// `homeassistant/<component>/<did>/config`; as a device can have several
+/// sensors, those go to `homeassistant/sensor/<did>/<property>/config`.
+pub fn discovery_entities(
+    device: &Device,
+    spec: Option<&DeviceSpec>,
+    state_prefix: &str,
+) -> Vec<DiscoveryEntity> {
+    let topics = Topics {
+        device,
+        prefix: state_prefix,
+    };
+    match spec {
+        Some(spec) => miot_entities(&topics, spec),
+        None => legacy_entities(&topics),
+    }
+}
+
+struct Topics<'a> {
+    device: &'a Device,
+    prefix: &'a str,
+}
+
+impl Topics<'_> {
+    fn state(&self, key: &str) -> String {
+        format!("{}/{}/{}", self.prefix, self.device.did, key)
+    }
+
+    fn set(&self, key: &str) -> String {
+        format!("{}/{}/set/{}", self.prefix, self.device.did, key)
+    }
+
+    /// Config shared by every entity of the device: identity and availability.
+    fn base(&self, unique_id: &str, name: Option<&str>) -> Map<String, Value> {
+        let device = self.device;
+        let config = json!({
+            "unique_id": format!("mihome_{}_{}", device.did, unique_id),
+            "object_id": format!("mihome_{}_{}", device.did, unique_id),
+            "name": name,
+            "device": {
+                "identifiers": [format!("mihome_{}", device.did)],
+                "name": device.name,
+                "model": device.model,
+                "manufacturer": "Xiaomi",
+            },
+            "availability": [
+                { "topic": format!("{}/bridge/status", self.prefix) },
+                {
+                    "topic": self.state("online"),
+                    "payload_available": "true",
+                    "payload_not_available": "false",
+                },
+            ],
+            "availability_mode": "all",
+        });
+        match config {
+            Value::Object(map) => map,
+            _ => unreachable!(),
+        }
+    }
+
+    fn entity(
+        &self,
+        component: Component,
+        object_id: Option<&str>,
+        config: Map<String, Value>,
+    ) -> DiscoveryEntity {
+        let topic = match object_id {
+            Some(object_id) => format!(
+                "{}/{}/{}/{}/config",
+                DISCOVERY_PREFIX,
+                component.as_str(),
+                self.device.did,
+                object_id
+            ),
+            None => format!(
+                "{}/{}/{}/config",
+                DISCOVERY_PREFIX,
+                component.as_str(),
+                self.device.did
+            ),
+        };
+        DiscoveryEntity {
+            component,
+            topic,
+            config: Value::Object(config),
+            props: vec![],
+            miot_props: vec![],
+        }
+    }
+}
+
+fn miot_key(siid: u32, piid: u32) -> String {
+    format!("{}.{}", siid, piid)
+}
+
+fn miot_entities(topics: &Topics, spec: &DeviceSpec) -> Vec<DiscoveryEntity> {
+    let mut entities = vec![];
+
+    match spec.urn.name.as_str() {
+        "outlet" | "plug" | "switch" => {
+            if let Some((siid, on)) = spec.find_property("switch", "on") {
+                let key = miot_key(siid, on.iid);
+                let mut config = topics.base("switch", None);
+                config.insert("state_topic".into(), json!(topics.state(&key)));
+                config.insert("command_topic".into(), json!(topics.set(&key)));
+                config.insert("payload_on".into(), json!("true"));
+                config.insert("payload_off".into(), json!("false"));
+                config.insert("state_on".into(), json!("true"));
+                config.insert("state_off".into(), json!("false"));
+                let mut entity = topics.entity(Component::Switch, None, config);
+                entity.miot_props.push((siid, on.iid));
+                entities.push(entity);
+            }
+        }
+        "light" => {
+            if let Some((siid, on)) = spec.find_property("light", "on") {
+                let key = miot_key(siid, on.iid);
+                let mut config = topics.base("light", None);
+                config.insert("state_topic".into(), json!(topics.state(&key)));
+                config.insert("command_topic".into(), json!(topics.set(&key)));
+                config.insert("payload_on".into(), json!("true"));
+                config.insert("payload_off".into(), json!("false"));
+                let mut entity = topics.entity(Component::Light, None, config);
+                entity.miot_props.push((siid, on.iid));
+
+                let service = spec.service(siid);
+                if let Some(brightness) = service.and_then(|s| s.property_by_name("brightness")) {
+                    add_light_brightness(topics, &mut entity, siid, brightness);
+                }
+                if let Some(color_temperature) =
+                    service.and_then(|s| s.property_by_name("color-temperature"))
+                {
+                    add_light_color_temperature(topics, &mut entity, siid, color_temperature);
+                }
+                entities.push(entity);
+            }
+        }
+        _ => {}
+    }
+
+    for service in spec
+        .services
+        .iter()
+        .filter(|s| s.urn.name != "device-information")
+    {
+        for property in service.properties.iter().filter(|p| p.is_readable()) {
+            let sensor = SENSORS.iter().find(|(name, ..)| *name == property.urn.name);
+            if let Some(&(name, _, device_class, unit, state_class)) = sensor {
+                let key = miot_key(service.iid, property.iid);
+                let object_id = name.replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+                let mut config = topics.base(&object_id, Some(&property.description));
+                config.insert("state_topic".into(), json!(topics.state(&key)));
+                config.insert("device_class".into(), json!(device_class));
+                config.insert("unit_of_measurement".into(), json!(unit));
+                config.insert("state_class".into(), json!(state_class));
+                let mut entity = topics.entity(Component::Sensor, Some(&object_id), config);
+                entity.miot_props.push((service.iid, property.iid));
+                entities.push(entity);
+            }
+        }
+    }
+    entities
+}
+
+fn add_light_brightness(
+    topics: &Topics,
+    entity: &mut DiscoveryEntity,
+    siid: u32,
+    brightness: &PropertySpec,
+) {
+    let config = entity.config.as_object_mut().unwrap();
+    let key = miot_key(siid, brightness.iid);
+    let max = brightness.value_range.map_or(100.0, |r| r.max);
+    config.insert("brightness_state_topic".into(), json!(topics.state(&key)));
+    config.insert("brightness_command_topic".into(), json!(topics.set(&key)));
+    config.insert("brightness_scale".into(), json!(max as u64));
+    entity.miot_props.push((siid, brightness.iid));
+}
+
+fn add_light_color_temperature(
+    topics: &Topics,
+    entity: &mut DiscoveryEntity,
+    siid: u32,
+    color_temperature: &PropertySpec,
+) {
+    let config = entity.config.as_object_mut().unwrap();
+    let key = miot_key(siid, color_temperature.iid);
+    config.insert("color_temp_state_topic".into(), json!(topics.state(&key)));
+    config.insert("color_temp_command_topic".into(), json!(topics.set(&key)));
+    config.insert("color_temp_kelvin".into(), json!(true));
+    if let Some(range) = color_temperature.value_range {
+        config.insert("min_kelvin".into(), json!(range.min as u64));
+        config.insert("max_kelvin".into(), json!(range.max as u64));
+    }
+    entity.miot_props.push((siid, color_temperature.iid));
+}
+
+/// The kind of a legacy model from its name, e.g. `plug` for `chuangmi.plug.v1`.
+fn model_kind(model: &str) -> &str {
+    model.split('.').nth(1).unwrap_or("")
+}
+
+// Legacy values are strings like "on", published JSON encoded
+fn legacy_entities(topics: &Topics) -> Vec<DiscoveryEntity> {
+    let kind = model_kind(&topics.device.model);
+    let mut entities = vec![];
+
+    if kind == "plug" || kind.starts_with("switch") || kind == "light" {
+        let component = if kind == "light" {
+            Component::Light
+        } else {
+            Component::Switch
+        };
+        let mut config = topics.base(component.as_str(), None);
+        config.insert("state_topic".into(), json!(topics.state("power")));
+        config.insert("command_topic".into(), json!(topics.set("power")));
+        config.insert("payload_on".into(), json!("on"));
+        config.insert("payload_off".into(), json!("off"));
+        let mut props = vec!["power".to_string()];
+        if component == Component::Light {
+            config.insert("state_value_template".into(), json!("{{ value_json }}"));
+            config.insert(
+                "brightness_state_topic".into(),
+                json!(topics.state("bright")),
+            );
+            config.insert(
+                "brightness_command_topic".into(),
+                json!(topics.set("bright")),
+            );
+            config.insert("brightness_scale".into(), json!(100));
+            props.push("bright".to_string());
+        } else {
+            config.insert("value_template".into(), json!("{{ value_json }}"));
+            config.insert("state_on".into(), json!("on"));
+            config.insert("state_off".into(), json!("off"));
+        }
+        let mut entity = topics.entity(component, None, config);
+        entity.props = props;
+        entities.push(entity);
+    }
+
+    if kind.contains("sensor") || kind == "weather" || kind == "airmonitor" || kind == "plug" {
+        for &(_, legacy, device_class, unit, state_class) in SENSORS {
+            let known = match kind {
+                "plug" => legacy == "temperature" || legacy == "load_power",
+                "airmonitor" => legacy == "aqi" || legacy == "battery",
+                _ => legacy == "temperature" || legacy == "humidity",
+            };
+            if !known {
+                continue;
+            }
+            let mut config = topics.base(legacy, None);
+            config.insert("name".into(), json!(device_class.replace('_', " ")));
+            config.insert("state_topic".into(), json!(topics.state(legacy)));
+            config.insert("device_class".into(), json!(device_class));
+            config.insert("unit_of_measurement".into(), json!(unit));
+            config.insert("state_class".into(), json!(state_class));
+            let mut entity = topics.entity(Component::Sensor, Some(legacy), config);
+            entity.props.push(legacy.to_string());
+            entities.push(entity);
+        }
+    }
+    entities
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fixtures::device_with, SpecCache};
+
+    fn spec(model: &str) -> DeviceSpec {
+        SpecCache::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/specs"))
+            .load(model)
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn miot_plug_is_a_switch_with_a_temperature_sensor() {
+        let device = device_with("42", json!({ "model": "chuangmi.plug.m3" }));
+        let entities = discovery_entities(&device, Some(&spec("chuangmi.plug.m3")), "mihome");
+        assert_eq!(entities.len(), 2);
+
+        let switch = &entities[0];
+        assert_eq!(switch.component, Component::Switch);
+        assert_eq!(switch.topic, "homeassistant/switch/42/config");
+        assert_eq!(switch.config["state_topic"], "mihome/42/2.1");
+        assert_eq!(switch.config["command_topic"], "mihome/42/set/2.1");
+        assert_eq!(switch.config["device"]["identifiers"][0], "mihome_42");
+        assert_eq!(
+            switch.config["availability"][1]["topic"],
+            "mihome/42/online"
+        );
+        assert_eq!(switch.miot_props, vec![(2, 1)]);
+
+        let sensor = &entities[1];
+        assert_eq!(sensor.component, Component::Sensor);
+        assert_eq!(sensor.topic, "homeassistant/sensor/42/temperature/config");
+        assert_eq!(sensor.config["state_topic"], "mihome/42/2.2");
+        assert_eq!(sensor.config["device_class"], "temperature");
+        assert_ne!(sensor.config["unique_id"], switch.config["unique_id"]);
+    }
+
+    #[test]
+    fn miot_light_has_brightness_and_color_temperature() {
+        let device = device_with("7", json!({}));
+        let entities = discovery_entities(&device, Some(&spec("yeelink.light.color1")), "mihome");
+        assert_eq!(entities.len(), 1);
+
+        let light = &entities[0];
+        assert_eq!(light.component, Component::Light);
+        assert_eq!(light.topic, "homeassistant/light/7/config");
+        assert_eq!(light.config["brightness_command_topic"], "mihome/7/set/2.3");
+        assert_eq!(light.config["brightness_scale"], 100);
+        assert_eq!(light.config["color_temp_state_topic"], "mihome/7/2.4");
+        assert_eq!(light.miot_props, vec![(2, 1), (2, 3), (2, 4)]);
+    }
+
+    #[test]
+    fn legacy_models_use_known_properties() {
+        let plug = device_with("1", json!({ "model": "chuangmi.plug.v1" }));
+        let entities = discovery_entities(&plug, None, "mihome");
+        assert_eq!(entities[0].component, Component::Switch);
+        assert_eq!(entities[0].config["command_topic"], "mihome/1/set/power");
+        assert_eq!(entities[0].props, vec!["power"]);
+
+        let sensor = device_with("2", json!({ "model": "lumi.sensor_ht.v1" }));
+        let entities = discovery_entities(&sensor, None, "mihome");
+        let topics: Vec<&str> = entities.iter().map(|e| e.topic.as_str()).collect();
+        assert_eq!(
+            topics,
+            vec![
+                "homeassistant/sensor/2/temperature/config",
+                "homeassistant/sensor/2/humidity/config",
+            ]
+        );
+
+        let unknown = device_with("3", json!({ "model": "zhimi.fan.v3" }));
+        assert!(discovery_entities(&unknown, None, "mihome").is_empty());
+    }
+}
@@ -1,5 +1,6 @@
-extern crate anyhow;
+extern crate aes;
 extern crate base64;
+extern crate cbc;
 extern crate crypto_hash;
 extern crate hex;
 extern crate hmac;
@@ -8,19 +9,59 @@ extern crate reqwest;
 extern crate serde;
 extern crate serde_json;
 extern crate sha2;
+extern crate thiserror;
 extern crate urlencoding;
 
+pub mod builder;
+pub mod discovery;
+pub mod error;
+pub mod export;
+pub mod filter;
+#[cfg(test)]
+mod fixtures;
+pub mod home;
+pub mod home_assistant;
+pub mod local;
+pub mod miot;
+pub mod region;
+pub mod retry;
+pub mod scene;
+pub mod spec;
+#[cfg(test)]
+mod test_server;
+
+pub use builder::MiCloudProtocolBuilder;
+pub use discovery::{
+    discover, match_discovered, DeviceReachability, DiscoveredDevice, LocalStatus,
+};
+pub use error::{MiioError, Result};
+pub use export::{export_credentials, DeviceCredentials, ExportFormat};
+pub use filter::{glob_match, DeviceFilter};
+pub use home::{group_by_home, DevicesByHome, Home, HomeDevices, Room, RoomDevices};
+pub use home_assistant::{discovery_entities, Component, DiscoveryEntity};
+pub use local::LocalMiioClient;
+pub use miot::{ActionRequest, ActionResult, PropertyRequest, PropertyResult, SetPropertyRequest};
+pub use region::{Region, RegionRegistry};
+pub use retry::RetryPolicy;
+pub use scene::{Scene, SceneKind};
+pub use spec::{DeviceSpec, SpecCache};
+
 use ::hmac::{Hmac, Mac};
-use anyhow::{anyhow, Context, Result};
-use crypto_hash::{hex_digest, Algorithm};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use crypto_hash::{digest, hex_digest, Algorithm};
+use error::Context;
+use futures::future::join_all;
 use hmac::NewMac;
 use rand::{thread_rng, Rng};
-use reqwest::{header, Client};
+use reqwest::{header, Client, StatusCode};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Number, Value};
 use sha2::{Digest, Sha256};
 use std::{
+    collections::HashMap,
+    future::Future,
     iter,
+    sync::RwLock,
     time::{SystemTime, UNIX_EPOCH},
     vec,
 };
@@ -35,6 +76,8 @@ pub struct Credentials {
     pub country: String,
     pub service_token: String,
     pub client_id: String,
+    #[serde(default)]
+    pub pass_token: Option<String>,
 }
 
 // Secure session struct for persistence (excludes password data)
@@ -46,6 +89,12 @@ pub struct SecureSession {
     pub country: String,
     pub service_token: String,
     pub client_id: String,
+    // Long-lived token used to get a new service token without the password
+    #[serde(default)]
+    pub pass_token: Option<String>,
+    // Region of each device found by `get_devices_all_regions`, by did
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub device_regions: HashMap<String, String>,
 }
 
 fn parse_response_json(str: &str) -> serde_json::Result<Value> {
@@ -58,6 +107,57 @@ fn parse_response_json(str: &str) -> serde_json::Result<Value> {
     serde_json::from_str(str)
 }
 
+// Extracts `ssecurity`, `userId` and `location` from an authenticated login response
+fn parse_login_auth(data: &Value, step: &str) -> Result<(String, i64, String)> {
+    let ssecurity = match data["ssecurity"].as_str() {
+        Some(s) => s.to_string(),
+        None => {
+            return Err(MiioError::Login(format!(
+                "{} failed: No 'ssecurity' in response",
+                step
+            )));
+        }
+    };
+    let user_id = match data["userId"].as_i64() {
+        Some(i) => i,
+        None => {
+            return Err(MiioError::Login(format!(
+                "{} failed: No 'userId' in response",
+                step
+            )));
+        }
+    };
+    let location = match data["location"].as_str() {
+        Some(s) => s.to_string(),
+        None => {
+            return Err(MiioError::Login(format!(
+                "{} failed: No 'location' in response",
+                step
+            )));
+        }
+    };
+
+    Ok((ssecurity, user_id, location))
+}
+
+fn collect_cookies(headers: &header::HeaderMap) -> Vec<(String, String)> {
+    headers
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .filter_map(|value| value.split(';').next())
+        .filter_map(|pair| pair.trim().split_once('='))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect()
+}
+
+fn find_cookie(headers: &header::HeaderMap, name: &str) -> Option<String> {
+    collect_cookies(headers)
+        .into_iter()
+        .find(|(k, v)| k == name && !v.is_empty())
+        .map(|(_, v)| v)
+}
+
 fn serde_value_to_string(value: &Value) -> String {
     match value {
         serde_json::Value::String(s) => s.clone(),
@@ -81,35 +181,130 @@ fn object_to_query_string(obj: &Value) -> String {
     obj_as_query_string.replacen('&', "", 1)
 }
 
-#[derive(serde::Serialize)]
-pub struct UrlsConfig {
-    cn: String,
-    de: String,
-    ru: String,
-    sg: String,
-    tw: String,
-    us: String,
-    login_step1: String,
-    login_step2: String,
-}
-
-impl UrlsConfig {
-    fn to_json(&self) -> Value {
-        json!(self)
+/// RC4 as used by the encrypted Mi Cloud endpoints: the first 1024 bytes of
+/// the keystream are discarded. Encryption and decryption are the same operation.
+fn rc4_apply(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut s: Vec<u8> = (0..=255).collect();
+    let mut j: u8 = 0;
+    for i in 0..256 {
+        j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+        s.swap(i, j as usize);
     }
+
+    let (mut i, mut j) = (0u8, 0u8);
+    let mut keystream = iter::repeat_with(move || {
+        i = i.wrapping_add(1);
+        j = j.wrapping_add(s[i as usize]);
+        s.swap(i as usize, j as usize);
+        s[s[i as usize].wrapping_add(s[j as usize]) as usize]
+    });
+    keystream.by_ref().take(1024).for_each(drop);
+    data.iter().zip(keystream).map(|(b, k)| b ^ k).collect()
 }
 
 pub struct MiCloudProtocol {
-    urls: UrlsConfig,
+    regions: RegionRegistry,
+    // Shared by all requests so connections are pooled; see `MiCloudProtocolBuilder`
+    client: Client,
+    no_redirect_client: Client,
+    retry_policy: RetryPolicy,
     username: Option<String>,
     password_md5: Option<String>,
-    ssecurity: Option<String>,
     user_id: Option<String>,
     country: String,
-    service_token: Option<String>,
+    // `ssecurity` and `serviceToken`, replaced in place when the session is refreshed
+    tokens: RwLock<Option<SessionTokens>>,
+    // Region each device was found in by `get_devices_all_regions`, by did
+    device_regions: RwLock<HashMap<String, String>>,
+    // Replaced when Xiaomi hands out a new one while refreshing the session
+    pass_token: RwLock<Option<String>>,
+    refresh_lock: tokio::sync::Mutex<()>,
+    session_listener: Option<Box<dyn Fn(SecureSession) + Send + Sync>>,
     user_agent: String,
     client_id: String,
-    locale: &'static str,
+    locale: String,
+    pending_login: Option<PendingLogin>,
+}
+
+#[derive(Clone)]
+struct SessionTokens {
+    ssecurity: String,
+    service_token: String,
+}
+
+/// Result of a login attempt. Xiaomi may interrupt the password step with a
+/// captcha or a two-factor verification; both are resumed with the matching
+/// continuation (`submit_captcha`, `verify_two_factor`).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LoginOutcome {
+    LoggedIn,
+    CaptchaRequired {
+        captcha_url: String,
+        /// Captcha image, base64 encoded
+        image: String,
+    },
+    TwoFactorRequired {
+        notification_url: String,
+    },
+}
+
+/// Channel the two-factor verification code was sent through.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TwoFactorMethod {
+    Phone,
+    Email,
+}
+
+impl TwoFactorMethod {
+    fn from_flag(flag: i64) -> Option<Self> {
+        match flag {
+            4 => Some(TwoFactorMethod::Phone),
+            8 => Some(TwoFactorMethod::Email),
+            _ => None,
+        }
+    }
+
+    fn flag(&self) -> i64 {
+        match self {
+            TwoFactorMethod::Phone => 4,
+            TwoFactorMethod::Email => 8,
+        }
+    }
+
+    fn endpoint_name(&self) -> &'static str {
+        match self {
+            TwoFactorMethod::Phone => "Phone",
+            TwoFactorMethod::Email => "Email",
+        }
+    }
+}
+
+// Login state kept between a challenge and its continuation
+struct PendingLogin {
+    username: String,
+    password_md5: String,
+    sign: String,
+    captcha_ick: Option<String>,
+    notification_url: Option<String>,
+    identity_session: Option<String>,
+    two_factor_method: Option<TwoFactorMethod>,
+}
+
+enum LoginStep2Result {
+    Authenticated {
+        ssecurity: String,
+        user_id: i64,
+        location: String,
+        pass_token: Option<String>,
+    },
+    CaptchaRequired {
+        captcha_url: String,
+    },
+    TwoFactorRequired {
+        notification_url: String,
+    },
 }
 
 #[derive(Serialize, Deserialize)]
@@ -119,12 +314,36 @@ struct MiCloudOkResponse<T = Value> {
 
 #[derive(Serialize, Deserialize)]
 struct MiCloudErrorMessageResponse {
+    code: Option<i64>,
     message: Option<String>,
 }
 
+// Errors come either nested in `error` or as top-level `code`/`message`
 #[derive(Serialize, Deserialize)]
 struct MiCloudErrorResponse {
-    error: MiCloudErrorMessageResponse,
+    code: Option<i64>,
+    message: Option<String>,
+    error: Option<MiCloudErrorMessageResponse>,
+}
+
+impl MiCloudErrorResponse {
+    fn into_error(self, fallback_msg: &str) -> MiioError {
+        let (code, message) = match self.error {
+            Some(error) => (error.code.or(self.code), error.message.or(self.message)),
+            None => (self.code, self.message),
+        };
+        let message = message
+            .filter(|m| !m.is_empty())
+            .unwrap_or(fallback_msg.to_string());
+
+        if message == "auth err" {
+            return MiioError::AuthExpired;
+        }
+        MiioError::Cloud {
+            code: code.unwrap_or(-1),
+            message,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -161,59 +380,31 @@ pub struct Device {
     pub ssid: String,
     pub token: String,
     pub uid: Number,
+    /// Server region the device was listed by; only set by `get_devices_all_regions`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
 }
 
 impl MiCloudProtocol {
+    /// Protocol with the default HTTP client settings; use `MiCloudProtocol::builder()`
+    /// to change timeouts, proxy, user agent or locale.
     pub fn new() -> Self {
-        let mut rng = rand::thread_rng();
-        let agent_id: String =
-            iter::repeat_with(|| b"ABCDEF"[rng.gen_range(0..b"ABCDEF".len())] as char)
-                .take(13)
-                .collect();
-        let client_id: String = iter::repeat_with(|| {
-            b"ABCDEFGHIJKLMNOPQRSTUVWXYZ"[rng.gen_range(0..b"ABCDEFGHIJKLMNOPQRSTUVWXYZ".len())]
-                as char
-        })
-        .take(6)
-        .collect();
-
-        let xiaomi_base_url = "api.io.mi.com/app";
-        MiCloudProtocol {
-            urls: UrlsConfig {
-                cn: format!("https://{}", xiaomi_base_url),
-                de: format!("https://de.{}", xiaomi_base_url),
-                ru: format!("https://ru.{}", xiaomi_base_url),
-                sg: format!("https://sg.{}", xiaomi_base_url),
-                tw: format!("https://tw.{}", xiaomi_base_url),
-                us: format!("https://us.{}", xiaomi_base_url),
-                login_step1: "https://account.xiaomi.com/pass/serviceLogin".to_string(),
-                login_step2: "https://account.xiaomi.com/pass/serviceLoginAuth2".to_string(),
-            },
-            username: None,
-            password_md5: None,
-            ssecurity: None,
-            country: "cn".to_string(),
-            user_id: None,
-            service_token: None,
-            user_agent: format!(
-                "Android-7.1.1-1.0.0-ONEPLUS A3010-136-{} APP/xiaomi.smarthome APPV/62830",
-                agent_id.clone()
-            ),
-            client_id,
-            locale: "en",
-        }
+        Self::builder()
+            .build()
+            .expect("Failed to create the HTTP client")
     }
 
-    pub fn get_available_countries(&self) -> Vec<Vec<&'static str>> {
-        vec![
-            vec!["cn", "China"],
-            vec!["ru", "Russia"],
-            vec!["us", "USA"],
-            vec!["i2", "India"],
-            vec!["tw", "Taiwan"],
-            vec!["sg", "Singapore"],
-            vec!["de", "Germany"],
-        ] // https://www.openhab.org/addons/bindings/miio/#country-servers
+    /// `[code, name]` pairs of every region in the registry.
+    pub fn get_available_countries(&self) -> Vec<Vec<String>> {
+        self.regions
+            .regions()
+            .iter()
+            .map(|region| vec![region.code.clone(), region.name.clone()])
+            .collect()
+    }
+
+    pub fn regions(&self) -> &RegionRegistry {
+        &self.regions
     }
 
     /// Authenticates a user with Mi Cloud.
@@ -235,38 +426,321 @@ impl MiCloudProtocol {
     /// }
     /// ```
     ///
+    /// Returns `LoginOutcome::LoggedIn` on success. When Xiaomi asks for a captcha
+    /// or a two-factor verification the matching outcome is returned instead and
+    /// the login is resumed with `submit_captcha` or `verify_two_factor`.
+    ///
     /// # Errors
     ///
     /// Returns `Err` if authentication fails.
-    pub async fn login(&mut self, username: &str, password: &str) -> Result<()> {
-        let client = Client::new();
+    pub async fn login(&mut self, username: &str, password: &str) -> Result<LoginOutcome> {
+        let client = self.client.clone();
         let password_md5 = hex_digest(Algorithm::MD5, password.as_bytes()).to_uppercase();
-        let (sign, _) = self.login_step1(&client).await?;
-        let (ssecurity, user_id, location) = self
-            .login_step2(&client, username, password_md5.as_str(), &sign)
+        let data = self.login_step1(&client, None).await?;
+        let sign = match data["_sign"].as_str() {
+            Some(s) => s.to_string(),
+            None => {
+                return Err(MiioError::Login(
+                    "Login step 1 failed: No '_sign' in response".to_string(),
+                ));
+            }
+        };
+
+        self.pending_login = Some(PendingLogin {
+            username: username.to_string(),
+            password_md5,
+            sign,
+            captcha_ick: None,
+            notification_url: None,
+            identity_session: None,
+            two_factor_method: None,
+        });
+        self.continue_login(&client, None).await
+    }
+
+    /// Resumes a login interrupted by `LoginOutcome::CaptchaRequired` with the
+    /// code shown on the captcha image.
+    pub async fn submit_captcha(&mut self, code: &str) -> Result<LoginOutcome> {
+        let client = self.client.clone();
+        match &self.pending_login {
+            Some(pending) if pending.captcha_ick.is_some() => {}
+            _ => {
+                return Err(MiioError::Login(
+                    "Login failed: No captcha challenge pending".to_string(),
+                ))
+            }
+        }
+        self.continue_login(&client, Some(code)).await
+    }
+
+    /// Asks Xiaomi to send the verification code for a pending two-factor
+    /// challenge and returns the channel it was sent through.
+    pub async fn send_two_factor_code(&mut self) -> Result<TwoFactorMethod> {
+        let client = self.client.clone();
+        let notification_url = match &self.pending_login {
+            Some(PendingLogin {
+                notification_url: Some(url),
+                ..
+            }) => url.clone(),
+            _ => {
+                return Err(MiioError::Login(
+                    "Login failed: No two-factor challenge pending".to_string(),
+                ))
+            }
+        };
+
+        let res = client
+            .get(notification_url.replace("authStart", "list"))
+            .header(header::USER_AGENT, &self.user_agent)
+            .send()
+            .await
+            .with_context(|| "Two-factor verification failed")?;
+        let identity_session = find_cookie(res.headers(), "identity_session");
+        let content = res.text().await.unwrap_or_default();
+        let data = parse_response_json(&content).unwrap_or(Value::Null);
+        let method = data["flag"]
+            .as_i64()
+            .and_then(TwoFactorMethod::from_flag)
+            .or_else(|| {
+                data["options"].as_array().and_then(|o| {
+                    o.iter()
+                        .filter_map(Value::as_i64)
+                        .find_map(TwoFactorMethod::from_flag)
+                })
+            })
+            .unwrap_or(TwoFactorMethod::Email);
+        let identity_session = identity_session.ok_or_else(|| {
+            MiioError::Login(
+                "Two-factor verification failed: No 'identity_session' in response".to_string(),
+            )
+        })?;
+
+        let url = format!(
+            "{}/identity/auth/send{}Ticket",
+            self.login_host(),
+            method.endpoint_name()
+        );
+        let res = client
+            .post(url)
+            .form(&[("_json", "true")])
+            .header(header::USER_AGENT, &self.user_agent)
+            .header(
+                header::COOKIE,
+                format!("identity_session={}", identity_session),
+            )
+            .send()
+            .await
+            .with_context(|| "Two-factor verification failed: Cannot send code")?;
+        let content = res.text().await.unwrap_or_default();
+        let data = parse_response_json(&content)?;
+        if data["code"].as_i64() != Some(0) {
+            return Err(MiioError::Login(format!(
+                "Two-factor verification failed: {}",
+                data["tips"].as_str().unwrap_or("Cannot send code")
+            )));
+        }
+
+        if let Some(pending) = self.pending_login.as_mut() {
+            pending.identity_session = Some(identity_session);
+            pending.two_factor_method = Some(method);
+        }
+        Ok(method)
+    }
+
+    /// Finishes a login interrupted by `LoginOutcome::TwoFactorRequired` with the
+    /// code sent by `send_two_factor_code`, then resumes at `login_step3`.
+    pub async fn verify_two_factor(&mut self, code: &str) -> Result<LoginOutcome> {
+        let client = self.no_redirect_client.clone();
+        let (identity_session, method) = match &self.pending_login {
+            Some(PendingLogin {
+                identity_session: Some(session),
+                two_factor_method: Some(method),
+                ..
+            }) => (session.clone(), *method),
+            _ => {
+                return Err(MiioError::Login(
+                    "Two-factor verification failed: Verification code was not requested"
+                        .to_string(),
+                ))
+            }
+        };
+
+        let url = format!(
+            "{}/identity/auth/verify{}",
+            self.login_host(),
+            method.endpoint_name()
+        );
+        let res = client
+            .post(url)
+            .query(&[
+                ("_flag", method.flag().to_string()),
+                ("_json", "true".to_string()),
+            ])
+            .form(&[("ticket", code), ("trust", "true"), ("_json", "true")])
+            .header(header::USER_AGENT, &self.user_agent)
+            .header(
+                header::COOKIE,
+                format!("identity_session={}", identity_session),
+            )
+            .send()
+            .await
+            .with_context(|| "Two-factor verification failed")?;
+        let content = res.text().await.unwrap_or_default();
+        let data = parse_response_json(&content)?;
+        let mut location = match (data["code"].as_i64(), data["location"].as_str()) {
+            (Some(0), Some(location)) => location.to_string(),
+            _ => {
+                return Err(MiioError::Login(format!(
+                    "Two-factor verification failed: {}",
+                    data["tips"].as_str().unwrap_or("Wrong code")
+                )))
+            }
+        };
+
+        // Follow the redirect chain by hand to pick up the passToken cookies
+        let mut cookies: Vec<(String, String)> = vec![];
+        for _ in 0..10 {
+            let res = client
+                .get(&location)
+                .header(header::USER_AGENT, &self.user_agent)
+                .send()
+                .await
+                .with_context(|| "Two-factor verification failed")?;
+            cookies.extend(collect_cookies(res.headers()));
+            match res
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|l| l.to_str().ok())
+            {
+                Some(next) => location = next.to_string(),
+                None => break,
+            }
+        }
+        let cookie = |name: &str| {
+            cookies
+                .iter()
+                .rev()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.clone())
+        };
+        let (pass_token, user_id) = match (cookie("passToken"), cookie("userId")) {
+            (Some(pass_token), Some(user_id)) => (pass_token, user_id),
+            _ => {
+                return Err(MiioError::Login(
+                    "Two-factor verification failed: No 'passToken' in response".to_string(),
+                ))
+            }
+        };
+
+        // With a passToken step 1 authenticates directly
+        let client = self.client.clone();
+        let data = self
+            .login_step1(
+                &client,
+                Some(format!("passToken={}; userId={}", pass_token, user_id)),
+            )
+            .await?;
+        let (ssecurity, user_id, location) = parse_login_auth(&data, "Login step 1")?;
+        let pass_token = data["passToken"]
+            .as_str()
+            .filter(|t| !t.is_empty())
+            .map(|t| t.to_string())
+            .or(Some(pass_token));
+        self.finish_login(&client, ssecurity, user_id, location, pass_token)
+            .await
+    }
+
+    async fn continue_login(
+        &mut self,
+        client: &Client,
+        captcha_code: Option<&str>,
+    ) -> Result<LoginOutcome> {
+        let pending = self
+            .pending_login
+            .as_ref()
+            .ok_or_else(|| MiioError::Login("Login failed: No login in progress".to_string()))?;
+        let captcha = match (captcha_code, &pending.captcha_ick) {
+            (Some(code), Some(ick)) => Some((code, ick.as_str())),
+            _ => None,
+        };
+
+        let result = self
+            .login_step2(
+                client,
+                &pending.username,
+                &pending.password_md5,
+                &pending.sign,
+                captcha,
+            )
             .await?;
-        let service_token = self.login_step3(&client, location).await?;        
-        self.username = Some(username.to_string());
-        self.password_md5 = Some(password_md5);
-        self.ssecurity = Some(ssecurity);
+
+        match result {
+            LoginStep2Result::Authenticated {
+                ssecurity,
+                user_id,
+                location,
+                pass_token,
+            } => {
+                self.finish_login(client, ssecurity, user_id, location, pass_token)
+                    .await
+            }
+            LoginStep2Result::CaptchaRequired { captcha_url } => {
+                let (image, ick) = self.fetch_captcha(client, &captcha_url).await?;
+                if let Some(pending) = self.pending_login.as_mut() {
+                    pending.captcha_ick = Some(ick);
+                }
+                Ok(LoginOutcome::CaptchaRequired {
+                    captcha_url,
+                    image: BASE64.encode(image),
+                })
+            }
+            LoginStep2Result::TwoFactorRequired { notification_url } => {
+                if let Some(pending) = self.pending_login.as_mut() {
+                    pending.notification_url = Some(notification_url.clone());
+                }
+                Ok(LoginOutcome::TwoFactorRequired { notification_url })
+            }
+        }
+    }
+
+    async fn finish_login(
+        &mut self,
+        client: &Client,
+        ssecurity: String,
+        user_id: i64,
+        location: String,
+        pass_token: Option<String>,
+    ) -> Result<LoginOutcome> {
+        let service_token = self.login_step3(client, location).await?;
+        let pending = self
+            .pending_login
+            .take()
+            .ok_or_else(|| MiioError::Login("Login failed: No login in progress".to_string()))?;
+        self.username = Some(pending.username);
+        self.password_md5 = Some(pending.password_md5);
         self.user_id = Some(user_id.to_string());
-        self.service_token = Some(service_token);
+        *self.pass_token.write().unwrap() = pass_token;
+        self.set_tokens(Some(SessionTokens {
+            ssecurity,
+            service_token,
+        }));
 
-        Ok(())
+        Ok(LoginOutcome::LoggedIn)
     }
 
     /// Exports current credentials for saving
     pub fn export_credentials(&self) -> Option<Credentials> {
-        match (&self.username, &self.password_md5, &self.ssecurity, &self.user_id, &self.service_token) {
-            (Some(username), Some(password_md5), Some(ssecurity), Some(user_id), Some(service_token)) => {
+        match (&self.username, &self.password_md5, self.tokens(), &self.user_id) {
+            (Some(username), Some(password_md5), Some(tokens), Some(user_id)) => {
                 Some(Credentials {
                     username: username.clone(),
                     password_md5: password_md5.clone(),
-                    ssecurity: ssecurity.clone(),
+                    ssecurity: tokens.ssecurity,
                     user_id: user_id.clone(),
                     country: self.country.clone(),
-                    service_token: service_token.clone(),
+                    service_token: tokens.service_token,
                     client_id: self.client_id.clone(),
+                    pass_token: self.pass_token.read().unwrap().clone(),
                 })
             }
             _ => None,
@@ -276,66 +750,138 @@ impl MiCloudProtocol {
     pub fn import_credentials(&mut self, credentials: Credentials) {
         self.username = Some(credentials.username);
         self.password_md5 = Some(credentials.password_md5);
-        self.ssecurity = Some(credentials.ssecurity);
         self.user_id = Some(credentials.user_id);
         self.country = credentials.country;
-        self.service_token = Some(credentials.service_token);
+        self.set_tokens(Some(SessionTokens {
+            ssecurity: credentials.ssecurity,
+            service_token: credentials.service_token,
+        }));
+        *self.pass_token.write().unwrap() = credentials.pass_token;
         self.client_id = credentials.client_id;
     }
 
     /// Exports secure session data (excludes password)
     pub fn export_secure_session(&self) -> Option<SecureSession> {
-        match (&self.username, &self.ssecurity, &self.user_id, &self.service_token) {
-            (Some(username), Some(ssecurity), Some(user_id), Some(service_token)) => {
-                Some(SecureSession {
-                    username: username.clone(),
-                    ssecurity: ssecurity.clone(),
-                    user_id: user_id.clone(),
-                    country: self.country.clone(),
-                    service_token: service_token.clone(),
-                    client_id: self.client_id.clone(),
-                })
-            }
+        match (&self.username, self.tokens(), &self.user_id) {
+            (Some(username), Some(tokens), Some(user_id)) => Some(SecureSession {
+                username: username.clone(),
+                ssecurity: tokens.ssecurity,
+                user_id: user_id.clone(),
+                country: self.country.clone(),
+                service_token: tokens.service_token,
+                client_id: self.client_id.clone(),
+                pass_token: self.pass_token.read().unwrap().clone(),
+                device_regions: self.device_regions.read().unwrap().clone(),
+            }),
             _ => None,
         }
     }
 
     pub fn import_secure_session(&mut self, session: SecureSession) {
         self.username = Some(session.username);
-        self.ssecurity = Some(session.ssecurity);
         self.user_id = Some(session.user_id);
         self.country = session.country;
-        self.service_token = Some(session.service_token);
+        self.set_tokens(Some(SessionTokens {
+            ssecurity: session.ssecurity,
+            service_token: session.service_token,
+        }));
+        *self.pass_token.write().unwrap() = session.pass_token;
         self.client_id = session.client_id;
+        *self.device_regions.write().unwrap() = session.device_regions;
+    }
+
+    /// Registers a callback receiving the new session after `refresh_session`,
+    /// so it can be persisted in place of the expired one.
+    pub fn on_session_refreshed(
+        &mut self,
+        listener: impl Fn(SecureSession) + Send + Sync + 'static,
+    ) {
+        self.session_listener = Some(Box::new(listener));
+    }
+
+    /// Moves a login waiting for a captcha or two-factor code to `other`, e.g. a
+    /// client rebuilt with other network options, which can then resume it.
+    pub fn move_pending_login_to(&mut self, other: &mut MiCloudProtocol) {
+        other.pending_login = self.pending_login.take();
+    }
+
+    /// Whether an expired service token can be renewed without the password.
+    pub fn can_refresh_session(&self) -> bool {
+        self.pass_token.read().unwrap().is_some() && self.user_id.is_some()
+    }
+
+    /// Gets a new `ssecurity` and service token by replaying login step 1 with
+    /// the stored `passToken`. Returns `MiioError::AuthExpired` when there is no
+    /// pass token or it was revoked, in which case the user has to log in again.
+    pub async fn refresh_session(&self) -> Result<()> {
+        let pass_token = self.pass_token.read().unwrap().clone();
+        let (pass_token, user_id) = match (pass_token, &self.user_id) {
+            (Some(pass_token), Some(user_id)) => (pass_token, user_id),
+            _ => return Err(MiioError::AuthExpired),
+        };
+
+        let client = self.client.clone();
+        let data = self
+            .login_step1(
+                &client,
+                Some(format!("passToken={}; userId={}", pass_token, user_id)),
+            )
+            .await
+            .with_context(|| "Session refresh failed")?;
+        if data["ssecurity"].is_null() {
+            // Step 1 asks for the password again: the pass token is no longer valid
+            return Err(MiioError::AuthExpired);
+        }
+        let (ssecurity, _, location) = parse_login_auth(&data, "Session refresh")?;
+        let service_token = self
+            .login_step3(&client, location)
+            .await
+            .with_context(|| "Session refresh failed")?;
+        self.set_tokens(Some(SessionTokens {
+            ssecurity,
+            service_token,
+        }));
+        // Keep a rotated pass token, the old one may stop working
+        if let Some(new_pass_token) = data["passToken"].as_str().filter(|t| !t.is_empty()) {
+            *self.pass_token.write().unwrap() = Some(new_pass_token.to_string());
+        }
+
+        if let (Some(listener), Some(session)) =
+            (&self.session_listener, self.export_secure_session())
+        {
+            listener(session);
+        }
+        Ok(())
     }
 
     /// Check if session is valid for API calls
     pub fn is_session_valid(&self) -> bool {
         self.username.is_some() && 
-        self.ssecurity.is_some() && 
         self.user_id.is_some() && 
-        self.service_token.is_some()
+        self.tokens().is_some()
     }
 
     /// Check if the user is logged in
     pub fn is_logged_in(&self) -> bool {
         self.username.is_some() && 
         self.password_md5.is_some() && 
-        self.ssecurity.is_some() && 
         self.user_id.is_some() && 
-        self.service_token.is_some()
+        self.tokens().is_some()
     }
 
     pub fn is_country_supported(&self, country: &str) -> bool {
-        self.get_available_countries()
-            .iter()
-            .any(|x| x[0] == country)
+        self.regions.contains(country)
     }
 
-    pub fn set_country(&mut self, country: &str) {
-        if self.is_country_supported(country) {
-            self.country = country.to_string();
-        }
+    /// Selects the default region for requests.
+    ///
+    /// # Errors
+    ///
+    /// Returns `MiioError::UnsupportedRegion` if `country` is not in the registry.
+    pub fn set_country(&mut self, country: &str) -> Result<()> {
+        self.regions.require(country)?;
+        self.country = country.to_string();
+        Ok(())
     }
 
     pub async fn get_devices<'a>(
@@ -350,10 +896,7 @@ impl MiCloudProtocol {
         };
 
         let country = country.unwrap_or(self.country.as_str());
-        let res = self
-            .request("/home/device_list", req, country)
-            .await
-            .map_err(|e| anyhow!(e))?;
+        let res = self.request("/home/device_list", req, country).await?;
 
         if !res["result"].is_null() {
             let parsed_res: MiCloudOkResponse<DeviceListResponse> =
@@ -362,12 +905,75 @@ impl MiCloudProtocol {
             Ok(devices)
         } else {
             let parsed_err: MiCloudErrorResponse = serde_json::from_value(res.clone())?;
-            let message = parsed_err
-                .error
-                .message
-                .unwrap_or("Get devices failed".to_string());
-            Err(anyhow!(message))
+            Err(parsed_err.into_error("Get devices failed"))
+        }
+    }
+
+    /// Lists the devices of every region at once, for users who don't know
+    /// which server their devices are registered on. Each device is tagged
+    /// with its region, which `call_device` and the MIoT calls then use for it.
+    ///
+    /// A device listed by several regions is kept once, preferring the
+    /// selected country. Regions that fail are skipped; if all of them fail,
+    /// the error of the selected country is returned.
+    pub async fn get_devices_all_regions(&self) -> Result<Vec<Device>> {
+        // Selected country first, so it wins when deduplicating
+        let mut codes: Vec<&str> = vec![self.country.as_str()];
+        codes.extend(
+            self.regions
+                .regions()
+                .iter()
+                .map(|region| region.code.as_str())
+                .filter(|code| *code != self.country),
+        );
+
+        let results = join_all(codes.iter().map(|code| self.get_devices(None, Some(code)))).await;
+
+        let mut devices: Vec<Device> = vec![];
+        let mut listed = vec![];
+        let mut errors = vec![];
+        for (code, result) in codes.iter().zip(results) {
+            match result {
+                Ok(list) => {
+                    listed.push(code.to_string());
+                    for mut device in list {
+                        if !devices.iter().any(|d| d.did == device.did) {
+                            device.region = Some(code.to_string());
+                            devices.push(device);
+                        }
+                    }
+                }
+                Err(e) => errors.push(e),
+            }
+        }
+        if errors.len() == codes.len() {
+            return Err(errors.remove(0));
+        }
+
+        // Devices of regions that failed this time keep their last known region
+        let mut device_regions = self.device_regions.write().unwrap();
+        device_regions.retain(|_, region| !listed.contains(region));
+        for device in &devices {
+            if let Some(region) = &device.region {
+                device_regions.insert(device.did.clone(), region.clone());
+            }
         }
+        Ok(devices)
+    }
+
+    /// Whether `get_devices_all_regions` was used, so device lists should come
+    /// from all regions too. The regions are kept in the exported session.
+    pub fn uses_all_regions(&self) -> bool {
+        !self.device_regions.read().unwrap().is_empty()
+    }
+
+    /// Region to send requests about a device to: `country` if given, else the
+    /// region `get_devices_all_regions` found it in, else the selected country.
+    fn device_country(&self, device_id: &str, country: Option<&str>) -> String {
+        country
+            .map(|c| c.to_string())
+            .or_else(|| self.device_regions.read().unwrap().get(device_id).cloned())
+            .unwrap_or_else(|| self.country.clone())
     }
 
     pub async fn get_device<'a>(
@@ -376,7 +982,9 @@ impl MiCloudProtocol {
         country: Option<&str>,
     ) -> Result<Vec<Device>> {
         let device_ids = Some(vec![device_id]);
-        self.get_devices(device_ids.as_deref(), country).await
+        let country = self.device_country(device_id, country);
+        self.get_devices(device_ids.as_deref(), Some(&country))
+            .await
     }
 
     pub async fn call_device<'a>(
@@ -388,10 +996,16 @@ impl MiCloudProtocol {
     ) -> Result<Value> {
         let req = json!({ "method": method, "params": params });
 
-        let country = country.unwrap_or(self.country.as_str());
+        let country = self.device_country(device_id, country);
         let fallback_msg = format!("Miio call for device {} failed", device_id);
+        let idempotent = self.retry_policy.is_idempotent(method);
         let res = self
-            .request(&format!(r"/home/rpc/{}", device_id), req, country)
+            .request_with_retry(
+                &format!(r"/home/rpc/{}", device_id),
+                req,
+                &country,
+                idempotent,
+            )
             .await
             .with_context(|| fallback_msg.to_string())?;
 
@@ -399,39 +1013,48 @@ impl MiCloudProtocol {
             Ok(res["result"].clone())
         } else {
             let parsed_err: MiCloudErrorResponse = serde_json::from_value(res.clone())?;
-            let message = parsed_err.error.message.unwrap_or(fallback_msg.to_string());
-            Err(anyhow!(message))
+            Err(parsed_err.into_error(&fallback_msg))
         }
     }
 
-    pub fn _override_urls(&mut self, urls: UrlsConfig) {
-        self.urls = urls;
+    pub fn _override_regions(&mut self, regions: RegionRegistry) {
+        self.regions = regions;
     }
 
-    async fn login_step1(&self, client: &Client) -> Result<(String, String)> {
-        let url = self.urls.login_step1.clone();
+    async fn login_step1(&self, client: &Client, cookie: Option<String>) -> Result<Value> {
+        let url = format!("{}/pass/serviceLogin", self.login_host());
         let query = (("sid", "xiaomiio"), ("_json", "true"));
-        let res = client.get(url).query(&query).send().await?;
+        let mut req = client.get(url).query(&query);
+        if let Some(cookie) = cookie {
+            req = req.header(
+                header::COOKIE,
+                format!(
+                    "sdkVersion=accountsdk-18.8.15; deviceId={}; {}",
+                    self.client_id, cookie
+                ),
+            );
+        }
+        let res = req.send().await?;
 
         let status = res.status();
+        let pass_token_cookie = find_cookie(res.headers(), "passToken");
         let content = res.text().await?;
 
         if !status.is_success() {
-            return Err(anyhow!(format!(
+            return Err(MiioError::Login(format!(
                 "Login step 1 failed: Response status {}",
                 status
-            ),));
+            )));
         }
 
-        let data = parse_response_json(&content)?;
-        let sign = match data["_sign"].as_str() {
-            Some(s) => s.to_string(),
-            None => {
-                return Err(anyhow!("Login step 1 failed: No '_sign' in response"));
+        let mut data = parse_response_json(&content)?;
+        // A new passToken may only come as a cookie; report it like the JSON field
+        if data["passToken"].is_null() {
+            if let Some(pass_token) = pass_token_cookie {
+                data["passToken"] = Value::String(pass_token);
             }
-        };
-
-        Ok((sign, content))
+        }
+        Ok(data)
     }
 
     async fn login_step2(
@@ -440,8 +1063,9 @@ impl MiCloudProtocol {
         username: &str,
         password_md5: &str,
         sign: &str,
-    ) -> Result<(String, i64, String)> {
-        let form_data = [
+        captcha: Option<(&str, &str)>,
+    ) -> Result<LoginStep2Result> {
+        let mut form_data = vec![
             ("hash", password_md5.to_string().clone()),
             ("_json", "true".to_string()),
             ("sid", "xiaomiio".to_string()),
@@ -450,50 +1074,83 @@ impl MiCloudProtocol {
             ("_sign", sign.to_string()),
             ("user", username.to_string()),
         ];
+        let mut cookie = format!("sdkVersion=accountsdk-18.8.15; deviceId={}", self.client_id);
+        if let Some((code, ick)) = captcha {
+            form_data.push(("captCode", code.to_string()));
+            cookie.push_str(&format!("; ick={}", ick));
+        }
 
-        let url = self.urls.login_step2.clone();
+        let url = format!("{}/pass/serviceLoginAuth2", self.login_host());
         let res = client
             .post(url)
             .form(&form_data)
             .header(header::USER_AGENT, &self.user_agent)
-            .header(
-                header::COOKIE,
-                format!("sdkVersion=accountsdk-18.8.15; deviceId={}", self.client_id),
-            )
+            .header(header::COOKIE, cookie)
             .send()
             .await?;
 
         let status = res.status();
+        let pass_token_cookie = find_cookie(res.headers(), "passToken");
         let content = res.text().await.unwrap_or("".to_string());
 
         if !status.is_success() {
-            return Err(anyhow!(format!(
+            return Err(MiioError::Login(format!(
                 "Login step 2 failed: Response status {}",
                 status
-            ),));
+            )));
         }
 
         let data = parse_response_json(&content)?;
-        let ssecurity = match data["ssecurity"].as_str() {
-            Some(s) => s.to_string(),
-            None => {
-                return Err(anyhow!("Login step 2 failed: No 'ssecurity' in response"));
-            }
-        };
-        let user_id = match data["userId"].as_i64() {
-            Some(i) => i,
-            None => {
-                return Err(anyhow!("Login step 2 failed: No 'userId' in response"));
-            }
-        };
-        let location = match data["location"].as_str() {
-            Some(s) => s.to_string(),
-            None => {
-                return Err(anyhow!("Login step 2 failed: No 'location' in response"));
+        if let Some(captcha_url) = data["captchaUrl"].as_str().filter(|u| !u.is_empty()) {
+            return Ok(LoginStep2Result::CaptchaRequired {
+                captcha_url: captcha_url.to_string(),
+            });
+        }
+        if data["ssecurity"].is_null() {
+            if let Some(notification_url) =
+                data["notificationUrl"].as_str().filter(|u| !u.is_empty())
+            {
+                return Ok(LoginStep2Result::TwoFactorRequired {
+                    notification_url: notification_url.to_string(),
+                });
             }
+        }
+
+        let (ssecurity, user_id, location) = parse_login_auth(&data, "Login step 2")?;
+        let pass_token = data["passToken"]
+            .as_str()
+            .filter(|t| !t.is_empty())
+            .map(|t| t.to_string())
+            .or(pass_token_cookie);
+        Ok(LoginStep2Result::Authenticated {
+            ssecurity,
+            user_id,
+            location,
+            pass_token,
+        })
+    }
+
+    async fn fetch_captcha(&self, client: &Client, captcha_url: &str) -> Result<(Vec<u8>, String)> {
+        let url = if captcha_url.starts_with("http") {
+            captcha_url.to_string()
+        } else {
+            format!("{}{}", self.login_host(), captcha_url)
         };
+        let res = client
+            .get(url)
+            .header(header::USER_AGENT, &self.user_agent)
+            .send()
+            .await
+            .with_context(|| "Failed to fetch captcha")?;
+        let ick = find_cookie(res.headers(), "ick").ok_or_else(|| {
+            MiioError::Login("Failed to fetch captcha: No 'ick' in response".to_string())
+        })?;
+        let image = res
+            .bytes()
+            .await
+            .with_context(|| "Failed to fetch captcha")?;
 
-        Ok((ssecurity, user_id, location))
+        Ok((image.to_vec(), ick))
     }
 
     async fn login_step3(&self, client: &Client, url: String) -> Result<String> {
@@ -509,11 +1166,11 @@ impl MiCloudProtocol {
                         }
                     }
                 }
-                Err(anyhow!(
-                    "Login step 3 failed: No 'serviceToken' in response"
+                Err(MiioError::Login(
+                    "Login step 3 failed: No 'serviceToken' in response".to_string(),
                 ))
             }
-            Err(e) => Err(anyhow!(e).context("Login step 3 failed")),
+            Err(e) => Err(MiioError::from(e).context("Login step 3 failed")),
         }
     }
 
@@ -523,23 +1180,61 @@ impl MiCloudProtocol {
         data: serde_json::Value,
         country: &str,
     ) -> Result<serde_json::Value> {
-        let client = Client::new();
+        self.request_with_retry(path, data, country, true).await
+    }
 
-        if self.service_token.is_none() {
-            return Err(anyhow!("Request error: Not logged in"));
-        }
+    /// Like `request`; requests that aren't `idempotent` are never retried.
+    async fn request_with_retry(
+        &self,
+        path: &str,
+        data: serde_json::Value,
+        country: &str,
+        idempotent: bool,
+    ) -> Result<serde_json::Value> {
+        // Every attempt is signed again with a fresh nonce, Mi Cloud rejects reused ones
+        self.retry_policy
+            .run(idempotent, || {
+                self.with_session_refresh(|| self.send_request(path, data.clone(), country))
+            })
+            .await
+    }
 
-        if !self.is_country_supported(country) {
-            return Err(anyhow!(
-                "Request error: Server Location {} is not supported",
-                country
-            ));
+    /// Runs `send`, and when the service token turns out to be expired refreshes
+    /// the session and runs it once more.
+    async fn with_session_refresh<F, Fut>(&self, send: F) -> Result<Value>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<Value>>,
+    {
+        let used_token = self.tokens().map(|t| t.service_token);
+        match send().await {
+            Err(MiioError::AuthExpired) if self.can_refresh_session() => {
+                let guard = self.refresh_lock.lock().await;
+                // Concurrent requests may have refreshed the session while we waited
+                if self.tokens().map(|t| t.service_token) == used_token {
+                    self.refresh_session().await?;
+                }
+                drop(guard);
+                send().await
+            }
+            res => res,
         }
+    }
+
+    async fn send_request(
+        &self,
+        path: &str,
+        data: serde_json::Value,
+        country: &str,
+    ) -> Result<serde_json::Value> {
+        let client = self.client.clone();
+
+        let tokens = self.tokens().ok_or(MiioError::NotLoggedIn)?;
 
         let params = json!({"data": data});
-        let url = format!("{}{}", self.get_api_url(country), path);
+        let url = format!("{}{}", self.regions.require(country)?.api_url, path);
         let nonce = self.generate_nonce();
-        let signed_nonce = self.signed_nonce(self.ssecurity.as_ref().unwrap(), &nonce);
+        let signed_nonce = self.signed_nonce(&tokens.ssecurity, &nonce);
         let signature = self.generate_signature(path, &signed_nonce, &nonce, &params);
         let body = json!({
             "_nonce": nonce,
@@ -561,17 +1256,112 @@ impl MiCloudProtocol {
             .await
             .with_context(|| "Failed to send request")?;
 
+        if res.status() == StatusCode::UNAUTHORIZED {
+            return Err(MiioError::AuthExpired);
+        }
         if !res.status().is_success() {
-            return Err(anyhow!(
-                "Request error: Status {}, {:#?}",
-                res.status(),
-                res
-            ));
+            return Err(MiioError::Http {
+                status: res.status().as_u16(),
+            });
         }
 
         res.json().await.with_context(|| "Failed to parse response")
     }
 
+    /// Sends a request to one of the encrypted ("v2") endpoints such as
+    /// `/v2/homeroom/gethome` or `/appgateway/...`.
+    ///
+    /// The parameters are signed with `rc4_hash__` and RC4-encrypted with the
+    /// signed nonce; the response body comes back RC4-encrypted as well.
+    pub async fn request_encrypted(
+        &self,
+        path: &str,
+        data: serde_json::Value,
+        country: &str,
+    ) -> Result<serde_json::Value> {
+        self.request_encrypted_with_retry(path, data, country, true)
+            .await
+    }
+
+    async fn request_encrypted_with_retry(
+        &self,
+        path: &str,
+        data: serde_json::Value,
+        country: &str,
+        idempotent: bool,
+    ) -> Result<serde_json::Value> {
+        self.retry_policy
+            .run(idempotent, || {
+                self.with_session_refresh(|| {
+                    self.send_request_encrypted(path, data.clone(), country)
+                })
+            })
+            .await
+    }
+
+    async fn send_request_encrypted(
+        &self,
+        path: &str,
+        data: serde_json::Value,
+        country: &str,
+    ) -> Result<serde_json::Value> {
+        let client = self.client.clone();
+
+        let tokens = self.tokens().ok_or(MiioError::NotLoggedIn)?;
+
+        let url = format!("{}{}", self.regions.require(country)?.api_url, path);
+        let nonce = self.generate_nonce();
+        let signed_nonce = self.signed_nonce(&tokens.ssecurity, &nonce);
+        let params = self.generate_enc_params(
+            path,
+            "POST",
+            &signed_nonce,
+            &nonce,
+            vec![("data".to_string(), data.to_string())],
+        );
+
+        let body_as_query_string = params
+            .iter()
+            .map(|(k, v)| format!(r"{}={}", k, urlencoding::encode(v)))
+            .collect::<Vec<String>>()
+            .join("&");
+
+        let res = client
+            .post(&url)
+            .header(header::USER_AGENT, self.user_agent.to_string())
+            .header("x-xiaomi-protocal-flag-cli", "PROTOCAL-HTTP2")
+            .header("MIOT-ENCRYPT-ALGORITHM", "ENCRYPT-RC4")
+            .header(header::ACCEPT_ENCODING, "identity")
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(header::COOKIE, self.get_cookie())
+            .body(body_as_query_string)
+            .send()
+            .await
+            .with_context(|| "Failed to send request")?;
+
+        if res.status() == StatusCode::UNAUTHORIZED {
+            return Err(MiioError::AuthExpired);
+        }
+        if !res.status().is_success() {
+            return Err(MiioError::Http {
+                status: res.status().as_u16(),
+            });
+        }
+
+        let content = res
+            .text()
+            .await
+            .with_context(|| "Failed to read response")?;
+        let content = content.trim();
+        // Errors raised before decryption (e.g. bad signature) come back as plain JSON
+        if content.starts_with('{') || content.starts_with("&&&START&&&") {
+            return parse_response_json(content).with_context(|| "Failed to parse response");
+        }
+
+        let decrypted = self.decrypt_rc4(&signed_nonce, content)?;
+        serde_json::from_slice(&decrypted).with_context(|| "Failed to parse response")
+    }
+
     fn generate_nonce(&self) -> String {
         let mut buf = [0u8; 12];
         let random_bytes: Vec<u8> = thread_rng().gen::<[u8; 8]>().to_vec();
@@ -631,11 +1421,80 @@ impl MiCloudProtocol {
         base64::encode(result)
     }
 
-    fn get_api_url(&self, country: &str) -> String {
-        match self.urls.to_json().get(country) {
-            Some(url) => url.as_str().unwrap().to_string(),
-            None => self.urls.cn.clone(),
+    fn generate_enc_signature(
+        &self,
+        path: &str,
+        method: &str,
+        signed_nonce: &str,
+        params: &[(String, String)],
+    ) -> String {
+        let mut exps = vec![method.to_uppercase(), path.to_string()];
+        for (key, value) in params {
+            exps.push(format!(r"{}={}", key, value));
         }
+        exps.push(signed_nonce.to_string());
+        let exps_str = exps.join("&");
+
+        BASE64.encode(digest(Algorithm::SHA1, exps_str.as_bytes()))
+    }
+
+    fn generate_enc_params(
+        &self,
+        path: &str,
+        method: &str,
+        signed_nonce: &str,
+        nonce: &str,
+        mut params: Vec<(String, String)>,
+    ) -> Vec<(String, String)> {
+        let rc4_hash = self.generate_enc_signature(path, method, signed_nonce, &params);
+        params.push(("rc4_hash__".to_string(), rc4_hash));
+        for (_, value) in params.iter_mut() {
+            *value = self.encrypt_rc4(signed_nonce, value);
+        }
+
+        let signature = self.generate_enc_signature(path, method, signed_nonce, &params);
+        params.push(("signature".to_string(), signature));
+        params.push((
+            "ssecurity".to_string(),
+            self.tokens().map(|t| t.ssecurity).unwrap_or_default(),
+        ));
+        params.push(("_nonce".to_string(), nonce.to_string()));
+        params
+    }
+
+    fn encrypt_rc4(&self, signed_nonce: &str, payload: &str) -> String {
+        let key = BASE64
+            .decode(signed_nonce)
+            .expect("Failed to decode signed nonce from base64");
+        BASE64.encode(rc4_apply(&key, payload.as_bytes()))
+    }
+
+    fn decrypt_rc4(&self, signed_nonce: &str, payload: &str) -> Result<Vec<u8>> {
+        let key = BASE64
+            .decode(signed_nonce)
+            .expect("Failed to decode signed nonce from base64");
+        let payload = BASE64
+            .decode(payload)
+            .map_err(|e| MiioError::Parse(format!("Failed to decode encrypted response: {}", e)))?;
+        Ok(rc4_apply(&key, &payload))
+    }
+
+    // Account server of the selected region, or of the first region if the
+    // selected country is unknown
+    fn login_host(&self) -> &str {
+        self.regions
+            .get(&self.country)
+            .or_else(|| self.regions.regions().first())
+            .map(|region| region.login_host.as_str())
+            .unwrap_or("https://account.xiaomi.com")
+    }
+
+    fn tokens(&self) -> Option<SessionTokens> {
+        self.tokens.read().unwrap().clone()
+    }
+
+    fn set_tokens(&self, tokens: Option<SessionTokens>) {
+        *self.tokens.write().unwrap() = tokens;
     }
 
     fn get_cookie(&self) -> String {
@@ -646,9 +1505,9 @@ impl MiCloudProtocol {
         if let Some(user_id) = &self.user_id.as_ref() {
             cookies.push(format!("userId={}", user_id));
         }
-        if let Some(token) = &self.service_token.as_ref() {
-            cookies.push(format!("serviceToken={}", token));
-            cookies.push(format!("yetAnotherServiceToken={}", token));
+        if let Some(tokens) = self.tokens() {
+            cookies.push(format!("serviceToken={}", tokens.service_token));
+            cookies.push(format!("yetAnotherServiceToken={}", tokens.service_token));
         }
         cookies.push(format!("locale={}", self.locale));
         cookies.push("channel=MI_APP_STORE".to_string());
@@ -672,6 +1531,7 @@ mod tests {
 
     extern crate tokio;
     use super::*;
+    use crate::test_server::{serve, StubRequest, StubResponse};
 
     #[test]
     fn signed_nonce() {
@@ -695,6 +1555,63 @@ mod tests {
         assert_eq!(result, expect);
     }
 
+    #[test]
+    fn encrypt_rc4() {
+        let mi: MiCloudProtocol = MiCloudProtocol::new();
+        let result = mi.encrypt_rc4(
+            "zq3TaSr/VwnmvvWwMTAEMAuzxs2gLgP6uFJS7bBtWKo=",
+            "{\"fg\":true}",
+        );
+        let expect = "9UFu+35iZ8NZ3NQ=";
+        assert_eq!(result, expect);
+
+        let decrypted = mi
+            .decrypt_rc4("zq3TaSr/VwnmvvWwMTAEMAuzxs2gLgP6uFJS7bBtWKo=", expect)
+            .unwrap();
+        assert_eq!(decrypted, b"{\"fg\":true}");
+    }
+
+    #[test]
+    fn generate_enc_signature() {
+        let mi: MiCloudProtocol = MiCloudProtocol::new();
+        let result = mi.generate_enc_signature(
+            "/v2/homeroom/gethome",
+            "POST",
+            "zq3TaSr/VwnmvvWwMTAEMAuzxs2gLgP6uFJS7bBtWKo=",
+            &[("data".to_string(), "{\"fg\":true}".to_string())],
+        );
+        let expect = "7qpYaipWwu0jqNkxX0CIgJeQQ5c=";
+        assert_eq!(result, expect);
+    }
+
+    #[test]
+    fn generate_enc_params() {
+        let mi: MiCloudProtocol = MiCloudProtocol::new();
+        mi.set_tokens(Some(SessionTokens {
+            ssecurity: "9wR21gAtfAyn+KDX1ok/Iw==".to_string(),
+            service_token: "token".to_string(),
+        }));
+        let result = mi.generate_enc_params(
+            "/v2/homeroom/gethome",
+            "POST",
+            "zq3TaSr/VwnmvvWwMTAEMAuzxs2gLgP6uFJS7bBtWKo=",
+            "BejIOTLgvecBs9sT",
+            vec![("data".to_string(), "{\"fg\":true}".to_string())],
+        );
+        let expect = vec![
+            ("data", "9UFu+35iZ8NZ3NQ="),
+            ("rc4_hash__", "uRJ4xT0xY+ZbzJnHRHlAgGUPLcbAoGHtkVKDqw=="),
+            ("signature", "OzHKB9RDT90fY25rFbL+jFrkmUg="),
+            ("ssecurity", "9wR21gAtfAyn+KDX1ok/Iw=="),
+            ("_nonce", "BejIOTLgvecBs9sT"),
+        ];
+        let result: Vec<(&str, &str)> = result
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        assert_eq!(result, expect);
+    }
+
     #[test]
     fn parse_response_json() {
         let res = super::parse_response_json("&&&START&&&{\"_nonce\":\"BejIOTLgvecBs9sT\",\"data\":{\"getVirtualModel\":false,\"getHuamiDevices\":0}}").unwrap();
@@ -727,19 +1644,462 @@ mod tests {
         assert_eq!(result, expect);
     }
 
+    // Stub account server: step 1, step 2 (challenged unless `captCode` is sent),
+    // captcha image, the two-factor endpoints and the STS redirect handing out
+    // the service token. `{base}` in the challenge is replaced by the server url.
+    async fn login_stub(step2_challenge: &'static str) -> String {
+        use std::sync::{Arc, Mutex};
+        let base = Arc::new(Mutex::new(String::new()));
+        let base_clone = base.clone();
+        let url = serve(move |req: StubRequest| {
+            let base = base_clone.lock().unwrap().clone();
+            if req.path.starts_with("/pass/serviceLoginAuth2") {
+                assert_eq!(req.method, "POST");
+                if req.body.contains("captCode=1234") {
+                    assert!(req.header("cookie").unwrap().contains("ick=captcha-ick"));
+                    StubResponse::ok(format!(
+                        r#"&&&START&&&{{"ssecurity":"9wR21gAtfAyn+KDX1ok/Iw==","userId":42,"location":"{}/sts"}}"#,
+                        base
+                    ))
+                } else {
+                    StubResponse::ok(format!(
+                        "&&&START&&&{}",
+                        step2_challenge.replace("{base}", &base)
+                    ))
+                }
+            } else if req.path.starts_with("/pass/serviceLogin") {
+                let cookie = req.header("cookie").unwrap_or("");
+                if cookie.contains("passToken=pass-token; userId=42") {
+                    StubResponse::ok(format!(
+                        r#"&&&START&&&{{"ssecurity":"9wR21gAtfAyn+KDX1ok/Iw==","userId":42,"location":"{}/sts"}}"#,
+                        base
+                    ))
+                } else {
+                    StubResponse::ok(r#"&&&START&&&{"_sign":"sign"}"#)
+                }
+            } else if req.path.starts_with("/identity/list") {
+                StubResponse::ok(r#"&&&START&&&{"code":2,"flag":4}"#)
+                    .with_header("Set-Cookie", "identity_session=session; Path=/")
+            } else if req.path.starts_with("/identity/auth/sendPhoneTicket") {
+                assert!(req.header("cookie").unwrap().contains("identity_session=session"));
+                StubResponse::ok(r#"&&&START&&&{"code":0}"#)
+            } else if req.path.starts_with("/identity/auth/verifyPhone") {
+                if req.body.contains("ticket=123456") {
+                    StubResponse::ok(format!(
+                        r#"&&&START&&&{{"code":0,"location":"{}/identity/result/check"}}"#,
+                        base
+                    ))
+                } else {
+                    StubResponse::ok(r#"&&&START&&&{"code":70014,"tips":"Wrong code"}"#)
+                }
+            } else if req.path.starts_with("/identity/result/check") {
+                StubResponse::new(302, "")
+                    .with_header("Location", &format!("{}/identity/finish", base))
+                    .with_header("Set-Cookie", "passToken=pass-token; Path=/")
+            } else if req.path.starts_with("/identity/finish") {
+                StubResponse::ok("ok").with_header("Set-Cookie", "userId=42; Path=/")
+            } else if req.path.starts_with("/pass/getCode") {
+                StubResponse::ok(vec![0x89, b'P', b'N', b'G'])
+                    .with_header("Set-Cookie", "ick=captcha-ick; Path=/")
+            } else if req.path.starts_with("/sts") {
+                StubResponse::ok("ok").with_header("Set-Cookie", "serviceToken=token; Path=/")
+            } else {
+                StubResponse::new(404, "")
+            }
+        })
+        .await;
+        *base.lock().unwrap() = url.clone();
+        url
+    }
+
+    #[tokio::test]
+    async fn login_captcha_challenge() {
+        let base =
+            login_stub(r#"{"code":87001,"captchaUrl":"/pass/getCode?icodeType=login"}"#).await;
+        let mut mi: MiCloudProtocol = MiCloudProtocol::new();
+        mi._override_regions(RegionRegistry::with_base_url(&base));
+
+        let outcome = mi.login("username", "password").await.unwrap();
+        assert_eq!(
+            outcome,
+            LoginOutcome::CaptchaRequired {
+                captcha_url: "/pass/getCode?icodeType=login".to_string(),
+                image: BASE64.encode([0x89, b'P', b'N', b'G']),
+            }
+        );
+        assert!(!mi.is_session_valid());
+
+        // A client rebuilt in the meantime takes the challenge over
+        let mut rebuilt = MiCloudProtocol::builder()
+            .regions(RegionRegistry::with_base_url(&base))
+            .build()
+            .unwrap();
+        mi.move_pending_login_to(&mut rebuilt);
+        assert!(mi.submit_captcha("1234").await.is_err());
+        let mut mi = rebuilt;
+
+        let outcome = mi.submit_captcha("1234").await.unwrap();
+        assert_eq!(outcome, LoginOutcome::LoggedIn);
+        assert!(mi.is_logged_in());
+        let session = mi.export_secure_session().unwrap();
+        assert_eq!(session.username, "username");
+        assert_eq!(session.user_id, "42");
+        assert_eq!(session.ssecurity, "9wR21gAtfAyn+KDX1ok/Iw==");
+        assert_eq!(session.service_token, "token");
+        // The challenge is consumed with the login
+        assert!(mi.submit_captcha("1234").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn login_two_factor_challenge() {
+        let base =
+            login_stub(r#"{"code":0,"notificationUrl":"{base}/identity/authStart?sid=xiaomiio"}"#)
+                .await;
+        let mut mi: MiCloudProtocol = MiCloudProtocol::new();
+        mi._override_regions(RegionRegistry::with_base_url(&base));
+
+        let outcome = mi.login("username", "password").await.unwrap();
+        assert_eq!(
+            outcome,
+            LoginOutcome::TwoFactorRequired {
+                notification_url: format!("{}/identity/authStart?sid=xiaomiio", base),
+            }
+        );
+        assert!(!mi.is_session_valid());
+        assert!(mi.submit_captcha("1234").await.is_err());
+        // The code has to be requested first
+        assert!(mi.verify_two_factor("123456").await.is_err());
+
+        assert_eq!(
+            mi.send_two_factor_code().await.unwrap(),
+            TwoFactorMethod::Phone
+        );
+        let err = mi.verify_two_factor("000000").await.unwrap_err();
+        assert_eq!(
+            err,
+            MiioError::Login("Two-factor verification failed: Wrong code".to_string())
+        );
+
+        // Follows the redirect to collect passToken and userId, then logs in with them
+        let outcome = mi.verify_two_factor("123456").await.unwrap();
+        assert_eq!(outcome, LoginOutcome::LoggedIn);
+        assert!(mi.is_logged_in());
+        assert!(mi.can_refresh_session());
+        let session = mi.export_secure_session().unwrap();
+        assert_eq!(session.username, "username");
+        assert_eq!(session.user_id, "42");
+        assert_eq!(session.ssecurity, "9wR21gAtfAyn+KDX1ok/Iw==");
+        assert_eq!(session.service_token, "token");
+        assert_eq!(session.pass_token.as_deref(), Some("pass-token"));
+    }
+
+    #[test]
+    fn set_country() {
+        let mut mi: MiCloudProtocol = MiCloudProtocol::new();
+        assert!(mi.set_country("i2").is_ok());
+        assert_eq!(
+            mi.set_country("xx"),
+            Err(MiioError::UnsupportedRegion("xx".to_string()))
+        );
+        assert_eq!(mi.country, "i2");
+        assert!(mi
+            .get_available_countries()
+            .contains(&vec!["i2".to_string(), "India".to_string()]));
+    }
+
+    #[tokio::test]
+    async fn request_errors() {
+        let base = serve(|req: StubRequest| match req.path.as_str() {
+            "/home/device_list" => StubResponse::new(401, "unauthorized"),
+            "/home/rpc/1" => StubResponse::ok(
+                r#"{"code":-2,"message":"","error":{"code":-704042011,"message":"device offline"}}"#,
+            ),
+            "/home/rpc/2" => StubResponse::ok(r#"{"code":-8,"message":"","result":null}"#),
+            _ => StubResponse::new(500, ""),
+        })
+        .await;
+        let mut mi: MiCloudProtocol = MiCloudProtocol::new();
+        mi._override_regions(RegionRegistry::with_base_url(&base));
+
+        let err = mi.get_devices(None, None).await.unwrap_err();
+        assert_eq!(err, MiioError::NotLoggedIn);
+
+        mi.import_secure_session(SecureSession {
+            username: "username".to_string(),
+            ssecurity: "/pM9bFOMTk4/6sSeqnpchA==".to_string(),
+            user_id: "42".to_string(),
+            country: "cn".to_string(),
+            service_token: "token".to_string(),
+            client_id: "ABCDEF".to_string(),
+            pass_token: None,
+            device_regions: Default::default(),
+        });
+        let err = mi.get_devices(None, None).await.unwrap_err();
+        assert!(err.is_auth_expired());
+
+        let err = mi
+            .call_device("1", "get_prop", None, None)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err,
+            MiioError::Cloud {
+                code: -704042011,
+                message: "device offline".to_string()
+            }
+        );
+
+        let err = mi
+            .call_device("2", "get_prop", None, None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Some(-8));
+        assert_eq!(err.to_string(), "Miio call for device 2 failed");
+
+        let err = mi
+            .call_device("3", "get_prop", None, Some("xx"))
+            .await
+            .unwrap_err();
+        assert_eq!(err, MiioError::UnsupportedRegion("xx".to_string()));
+
+        let err = mi
+            .call_device("3", "get_prop", None, None)
+            .await
+            .unwrap_err();
+        assert_eq!(err, MiioError::Http { status: 500 });
+    }
+
+    #[tokio::test]
+    async fn devices_of_all_regions() {
+        use crate::fixtures::device;
+        use std::sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        };
+        let list = |dids: &[&str]| {
+            let devices: Vec<Device> = dids.iter().map(|did| device(did, "")).collect();
+            json!({ "code": 0, "message": "ok", "result": { "list": devices } }).to_string()
+        };
+        let (cn, de) = (list(&["1", "2"]), list(&["2", "3"]));
+        let cn_down = Arc::new(AtomicBool::new(false));
+        let cn_down_clone = cn_down.clone();
+        let base = serve(move |req: StubRequest| {
+            let (region, path) = req.path[1..].split_once('/').unwrap();
+            match (region, path) {
+                ("cn", "home/device_list") if !cn_down_clone.load(Ordering::SeqCst) => {
+                    StubResponse::ok(cn.clone())
+                }
+                ("de", "home/device_list") => StubResponse::ok(de.clone()),
+                (_, "home/device_list") => StubResponse::new(500, ""),
+                ("cn", "miotspec/prop/get") => StubResponse::new(500, ""),
+                (region, "miotspec/prop/get") => {
+                    // Answers in reverse order, tagging each property with the region
+                    let data = req
+                        .body
+                        .split('&')
+                        .find_map(|pair| pair.strip_prefix("data="))
+                        .unwrap();
+                    let body: Value =
+                        serde_json::from_str(&urlencoding::decode(data).unwrap()).unwrap();
+                    let mut result = body["params"].as_array().unwrap().clone();
+                    result.reverse();
+                    for prop in result.iter_mut() {
+                        prop["code"] = json!(0);
+                        prop["value"] = json!(region);
+                    }
+                    StubResponse::ok(json!({ "code": 0, "result": result }).to_string())
+                }
+                (region, _) => StubResponse::ok(json!({ "code": 0, "result": region }).to_string()),
+            }
+        })
+        .await;
+        let regions = || {
+            let regions = ["cn", "de", "sg"]
+                .iter()
+                .map(|code| Region::new(code, code, &format!("{}/{}", base, code), &base))
+                .collect();
+            RegionRegistry::new(regions)
+        };
+        let mut mi: MiCloudProtocol = MiCloudProtocol::new();
+        mi._override_regions(regions());
+        mi.import_secure_session(SecureSession {
+            username: "username".to_string(),
+            ssecurity: "/pM9bFOMTk4/6sSeqnpchA==".to_string(),
+            user_id: "42".to_string(),
+            country: "de".to_string(),
+            service_token: "token".to_string(),
+            client_id: "ABCDEF".to_string(),
+            pass_token: None,
+            device_regions: Default::default(),
+        });
+
+        // The selected country wins for devices listed twice; failing regions are skipped
+        let devices = mi.get_devices_all_regions().await.unwrap();
+        let found: Vec<(&str, &str)> = devices
+            .iter()
+            .map(|d| (d.did.as_str(), d.region.as_deref().unwrap()))
+            .collect();
+        assert_eq!(found, vec![("2", "de"), ("3", "de"), ("1", "cn")]);
+
+        let routed = mi.call_device("1", "get_prop", None, None).await.unwrap();
+        assert_eq!(routed, "cn");
+        let routed = mi.call_device("3", "get_prop", None, None).await.unwrap();
+        assert_eq!(routed, "de");
+        let routed = mi
+            .call_device("1", "get_prop", None, Some("sg"))
+            .await
+            .unwrap();
+        assert_eq!(routed, "sg");
+        let unknown = mi.call_device("9", "get_prop", None, None).await.unwrap();
+        assert_eq!(unknown, "de");
+        assert_eq!(mi.get_device("1", None).await.unwrap()[0].did, "1");
+
+        // A batch spanning regions is sent to each of them and keeps its order;
+        // properties of a failed region carry the error
+        let props: Vec<PropertyRequest> = [("1", 1), ("3", 1), ("9", 1), ("3", 2)]
+            .iter()
+            .map(|(did, piid)| PropertyRequest {
+                did: did.to_string(),
+                siid: 2,
+                piid: *piid,
+            })
+            .collect();
+        let results = mi.get_properties(&props, None).await.unwrap();
+        let routed: Vec<(&str, u32, i64, Option<Value>)> = results
+            .iter()
+            .map(|r| (r.did.as_str(), r.piid, r.code, r.value.clone()))
+            .collect();
+        assert_eq!(
+            routed,
+            vec![
+                ("1", 1, 500, None),
+                ("3", 1, 0, Some(json!("de"))),
+                ("9", 1, 0, Some(json!("de"))),
+                ("3", 2, 0, Some(json!("de"))),
+            ]
+        );
+        assert!(results[0].message.is_some());
+        let err = mi.get_properties(&props, Some("cn")).await.unwrap_err();
+        assert_eq!(err.code(), Some(500));
+
+        // The regions are kept with the saved session
+        let session = mi.export_secure_session().unwrap();
+        assert_eq!(session.device_regions.len(), 3);
+        let mut restored: MiCloudProtocol = MiCloudProtocol::new();
+        restored._override_regions(regions());
+        restored.import_secure_session(session);
+        assert!(restored.uses_all_regions());
+        let routed = restored
+            .call_device("1", "get_prop", None, None)
+            .await
+            .unwrap();
+        assert_eq!(routed, "cn");
+
+        // Devices of a region that fails to list keep their region
+        cn_down.store(true, Ordering::SeqCst);
+        let devices = mi.get_devices_all_regions().await.unwrap();
+        assert_eq!(devices.len(), 2);
+        let routed = mi.call_device("1", "get_prop", None, None).await.unwrap();
+        assert_eq!(routed, "cn");
+
+        // Only fails when every region does
+        mi._override_regions(RegionRegistry::new(vec![Region::new(
+            "sg",
+            "sg",
+            &format!("{}/sg", base),
+            &base,
+        )]));
+        mi.set_country("sg").unwrap();
+        let err = mi.get_devices_all_regions().await.unwrap_err();
+        assert_eq!(err, MiioError::Http { status: 500 });
+    }
+
+    #[tokio::test]
+    async fn session_refresh() {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        };
+        let base = Arc::new(Mutex::new(String::new()));
+        let base_clone = base.clone();
+        let device_list_calls = Arc::new(AtomicUsize::new(0));
+        let calls = device_list_calls.clone();
+        let url = serve(move |req: StubRequest| {
+            let base = base_clone.lock().unwrap().clone();
+            let cookie = req.header("cookie").unwrap_or("").to_string();
+            if req.path.starts_with("/pass/serviceLogin") {
+                if cookie.contains("passToken=pass-token; userId=42") {
+                    // Also rotates the pass token
+                    StubResponse::ok(format!(
+                        r#"&&&START&&&{{"ssecurity":"/pM9bFOMTk4/6sSeqnpchA==","userId":42,"location":"{}/sts"}}"#,
+                        base
+                    ))
+                    .with_header("Set-Cookie", "passToken=rotated; Path=/")
+                } else {
+                    StubResponse::ok(r#"&&&START&&&{"_sign":"sign"}"#)
+                }
+            } else if req.path.starts_with("/sts") {
+                StubResponse::ok("ok").with_header("Set-Cookie", "serviceToken=fresh; Path=/")
+            } else if req.path == "/home/device_list" {
+                calls.fetch_add(1, Ordering::SeqCst);
+                if cookie.contains("serviceToken=fresh") {
+                    StubResponse::ok(r#"{"code":0,"message":"ok","result":{"list":[]}}"#)
+                } else {
+                    StubResponse::new(401, "auth err")
+                }
+            } else {
+                StubResponse::new(404, "")
+            }
+        })
+        .await;
+        *base.lock().unwrap() = url.clone();
+
+        let session = SecureSession {
+            username: "username".to_string(),
+            ssecurity: "9wR21gAtfAyn+KDX1ok/Iw==".to_string(),
+            user_id: "42".to_string(),
+            country: "cn".to_string(),
+            service_token: "expired".to_string(),
+            client_id: "ABCDEF".to_string(),
+            pass_token: Some("pass-token".to_string()),
+            device_regions: Default::default(),
+        };
+        let mut mi: MiCloudProtocol = MiCloudProtocol::new();
+        mi._override_regions(RegionRegistry::with_base_url(&url));
+        mi.import_secure_session(session.clone());
+        let refreshed = Arc::new(Mutex::new(None));
+        let refreshed_clone = refreshed.clone();
+        mi.on_session_refreshed(move |session| {
+            *refreshed_clone.lock().unwrap() = Some(session);
+        });
+
+        let devices = mi.get_devices(None, None).await.unwrap();
+        assert!(devices.is_empty());
+        assert_eq!(device_list_calls.load(Ordering::SeqCst), 2);
+        let exported = mi.export_secure_session().unwrap();
+        assert_eq!(exported.service_token, "fresh");
+        assert_eq!(exported.ssecurity, "/pM9bFOMTk4/6sSeqnpchA==");
+        assert_eq!(exported.pass_token.as_deref(), Some("rotated"));
+        let refreshed = refreshed.lock().unwrap().clone().unwrap();
+        assert_eq!(refreshed.service_token, "fresh");
+        assert_eq!(refreshed.pass_token.as_deref(), Some("rotated"));
+
+        // A revoked pass token surfaces as an expired session
+        let mut mi: MiCloudProtocol = MiCloudProtocol::new();
+        mi._override_regions(RegionRegistry::with_base_url(&url));
+        mi.import_secure_session(SecureSession {
+            pass_token: Some("revoked".to_string()),
+            device_regions: Default::default(),
+            ..session
+        });
+        let err = mi.get_devices(None, None).await.unwrap_err();
+        assert_eq!(err, MiioError::AuthExpired);
+    }
+
     // #[tokio::test]
     async fn e2e() {
         let mut mi: MiCloudProtocol = MiCloudProtocol::new();
-        mi._override_urls(UrlsConfig {
-            cn: "http://localhost:3000".to_string(),
-            de: "http://localhost:3000".to_string(),
-            ru: "http://localhost:3000".to_string(),
-            sg: "http://localhost:3000".to_string(),
-            tw: "http://localhost:3000".to_string(),
-            us: "http://localhost:3000".to_string(),
-            login_step1: "http://localhost:3000/step1".to_string(),
-            login_step2: "http://localhost:3000/step2".to_string(),
-        });
+        mi._override_regions(RegionRegistry::with_base_url("http://localhost:3000"));
         mi.login("username", "password").await.unwrap();
         mi.get_devices(None, None).await.unwrap();
     }
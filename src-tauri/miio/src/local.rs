@@ -0,0 +1,414 @@
+use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use crypto_hash::{digest, Algorithm};
+use serde_json::{json, Value};
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+use tokio::{net::UdpSocket, time::timeout};
+
+use crate::{
+    error::{Context, MiioError, Result},
+    Device,
+};
+
+type Aes128CbcEnc = cbc::Encryptor<aes::Aes128>;
+type Aes128CbcDec = cbc::Decryptor<aes::Aes128>;
+
+/// UDP port every miIO device listens on.
+pub const MIIO_PORT: u16 = 54321;
+
+const MAGIC: u16 = 0x2131;
+const HEADER_LEN: usize = 32;
+
+/// The 32 byte "hello" packet used both for the handshake and for discovery.
+pub(crate) fn hello_packet() -> [u8; HEADER_LEN] {
+    let mut packet = [0xffu8; HEADER_LEN];
+    packet[0..2].copy_from_slice(&MAGIC.to_be_bytes());
+    packet[2..4].copy_from_slice(&(HEADER_LEN as u16).to_be_bytes());
+    packet
+}
+
+/// Header fields of a miIO packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PacketHeader {
+    pub unknown: u32,
+    pub device_id: u32,
+    pub stamp: u32,
+}
+
+/// Parses the plain header of a miIO packet without touching the payload.
+pub(crate) fn parse_header(data: &[u8]) -> Result<PacketHeader> {
+    if data.len() < HEADER_LEN {
+        return Err(MiioError::Parse(format!(
+            "Invalid miIO packet: {} bytes is shorter than the header",
+            data.len()
+        )));
+    }
+    let magic = u16::from_be_bytes([data[0], data[1]]);
+    if magic != MAGIC {
+        return Err(MiioError::Parse(format!(
+            "Invalid miIO packet: Bad magic {:#06x}",
+            magic
+        )));
+    }
+    let length = u16::from_be_bytes([data[2], data[3]]) as usize;
+    if length != data.len() {
+        return Err(MiioError::Parse(format!(
+            "Invalid miIO packet: Length field {} does not match {} received bytes",
+            length,
+            data.len()
+        )));
+    }
+
+    let read_u32 = |offset: usize| {
+        u32::from_be_bytes([
+            data[offset],
+            data[offset + 1],
+            data[offset + 2],
+            data[offset + 3],
+        ])
+    };
+    Ok(PacketHeader {
+        unknown: read_u32(4),
+        device_id: read_u32(8),
+        stamp: read_u32(12),
+    })
+}
+
+/// Decodes a 32 character hex device token into its raw bytes.
+pub(crate) fn parse_token(token: &str) -> Result<[u8; 16]> {
+    let mut buf = [0u8; 16];
+    hex::decode_to_slice(token.trim(), &mut buf)
+        .map_err(|e| MiioError::Invalid(format!("Invalid device token '{}': {}", token, e)))?;
+    Ok(buf)
+}
+
+/// Derives the AES-128-CBC key and IV from a device token.
+///
+/// `key = md5(token)` and `iv = md5(key + token)`.
+fn token_key_iv(token: &[u8; 16]) -> ([u8; 16], [u8; 16]) {
+    let mut key = [0u8; 16];
+    key.copy_from_slice(&digest(Algorithm::MD5, token));
+
+    let mut iv_input = key.to_vec();
+    iv_input.extend_from_slice(token);
+    let mut iv = [0u8; 16];
+    iv.copy_from_slice(&digest(Algorithm::MD5, &iv_input));
+
+    (key, iv)
+}
+
+pub(crate) fn encrypt(token: &[u8; 16], plaintext: &[u8]) -> Vec<u8> {
+    let (key, iv) = token_key_iv(token);
+    Aes128CbcEnc::new(&key.into(), &iv.into()).encrypt_padded_vec_mut::<Pkcs7>(plaintext)
+}
+
+pub(crate) fn decrypt(token: &[u8; 16], ciphertext: &[u8]) -> Result<Vec<u8>> {
+    let (key, iv) = token_key_iv(token);
+    Aes128CbcDec::new(&key.into(), &iv.into())
+        .decrypt_padded_vec_mut::<Pkcs7>(ciphertext)
+        .map_err(|_| MiioError::Parse("Failed to decrypt miIO payload: Wrong token?".to_string()))
+}
+
+/// Builds an encrypted miIO packet for `payload`.
+///
+/// The checksum is `md5(header with token in place of the checksum + encrypted payload)`.
+pub(crate) fn encode_packet(
+    token: &[u8; 16],
+    device_id: u32,
+    stamp: u32,
+    payload: &[u8],
+) -> Vec<u8> {
+    let encrypted = encrypt(token, payload);
+    let length = (HEADER_LEN + encrypted.len()) as u16;
+
+    let mut packet = Vec::with_capacity(length as usize);
+    packet.extend_from_slice(&MAGIC.to_be_bytes());
+    packet.extend_from_slice(&length.to_be_bytes());
+    packet.extend_from_slice(&0u32.to_be_bytes());
+    packet.extend_from_slice(&device_id.to_be_bytes());
+    packet.extend_from_slice(&stamp.to_be_bytes());
+    packet.extend_from_slice(token);
+    packet.extend_from_slice(&encrypted);
+
+    let checksum = digest(Algorithm::MD5, &packet);
+    packet[16..HEADER_LEN].copy_from_slice(&checksum);
+    packet
+}
+
+/// Verifies and decrypts a miIO packet, returning its header and plain payload.
+pub(crate) fn decode_packet(token: &[u8; 16], data: &[u8]) -> Result<(PacketHeader, Vec<u8>)> {
+    let header = parse_header(data)?;
+    if data.len() == HEADER_LEN {
+        return Ok((header, vec![]));
+    }
+
+    let mut checked = data.to_vec();
+    checked[16..HEADER_LEN].copy_from_slice(token);
+    if digest(Algorithm::MD5, &checked) != data[16..HEADER_LEN] {
+        return Err(MiioError::Parse(
+            "Invalid miIO packet: Checksum mismatch".to_string(),
+        ));
+    }
+
+    let mut payload = decrypt(token, &data[HEADER_LEN..])?;
+    // Some firmwares terminate the JSON with a NUL byte
+    while payload.last() == Some(&0) {
+        payload.pop();
+    }
+    Ok((header, payload))
+}
+
+/// Talks to a single device over the local miIO protocol (UDP, port 54321).
+///
+/// The client performs the hello handshake on first use, keeps track of the
+/// device stamp and encrypts every payload with the device token.
+pub struct LocalMiioClient {
+    addr: SocketAddr,
+    token: [u8; 16],
+    device_id: Option<u32>,
+    stamp: Option<(u32, Instant)>,
+    request_id: u64,
+    timeout: Duration,
+}
+
+impl LocalMiioClient {
+    pub fn new(ip: &str, token: &str) -> Result<Self> {
+        let ip: IpAddr = ip
+            .parse()
+            .map_err(|_| MiioError::Invalid(format!("Invalid device address '{}'", ip)))?;
+        Ok(LocalMiioClient {
+            addr: SocketAddr::new(ip, MIIO_PORT),
+            token: parse_token(token)?,
+            device_id: None,
+            stamp: None,
+            request_id: 0,
+            timeout: Duration::from_secs(5),
+        })
+    }
+
+    /// Creates a client from a cloud `Device` using its `localip` and `token`.
+    pub fn from_device(device: &Device) -> Result<Self> {
+        let mut client = Self::new(&device.localip, &device.token)?;
+        client.device_id = device.did.parse().ok();
+        Ok(client)
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Device id reported by the last handshake (or taken from the cloud `did`).
+    pub fn device_id(&self) -> Option<u32> {
+        self.device_id
+    }
+
+    pub fn _override_addr(&mut self, addr: SocketAddr) {
+        self.addr = addr;
+    }
+
+    /// Sends the hello packet and records the device id and stamp from the reply.
+    pub async fn handshake(&mut self) -> Result<()> {
+        let socket = self.connect().await?;
+        socket
+            .send(&hello_packet())
+            .await
+            .map_err(MiioError::transport)
+            .with_context(|| format!("Failed to send hello to {}", self.addr))?;
+
+        let mut buf = [0u8; 1024];
+        let len = timeout(self.timeout, socket.recv(&mut buf))
+            .await
+            .map_err(|_| {
+                MiioError::Transport(format!("Handshake failed: {} did not answer", self.addr))
+            })?
+            .map_err(MiioError::transport)
+            .with_context(|| "Handshake failed")?;
+
+        let header = parse_header(&buf[..len]).with_context(|| "Handshake failed")?;
+        self.device_id = Some(header.device_id);
+        self.stamp = Some((header.stamp, Instant::now()));
+        Ok(())
+    }
+
+    /// Calls a miIO method on the device and returns the `result` of the reply.
+    pub async fn call_device(&mut self, method: &str, params: Option<Value>) -> Result<Value> {
+        if self.stamp.is_none() {
+            self.handshake().await?;
+        }
+
+        self.request_id += 1;
+        let id = self.request_id;
+        let payload = json!({
+            "id": id,
+            "method": method,
+            "params": params.unwrap_or_else(|| json!([])),
+        });
+        let packet = encode_packet(
+            &self.token,
+            self.device_id.unwrap_or_default(),
+            self.next_stamp(),
+            payload.to_string().as_bytes(),
+        );
+
+        let fallback_msg = format!("Local miio call {} to {} failed", method, self.addr);
+        let socket = self.connect().await?;
+        socket
+            .send(&packet)
+            .await
+            .map_err(MiioError::transport)
+            .with_context(|| fallback_msg.to_string())?;
+
+        let deadline = Instant::now() + self.timeout;
+        let mut buf = [0u8; 4096];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let len = timeout(remaining, socket.recv(&mut buf))
+                .await
+                .map_err(|_| MiioError::Transport(format!("{}: Timed out", fallback_msg)))?
+                .map_err(MiioError::transport)
+                .with_context(|| fallback_msg.to_string())?;
+
+            let (header, payload) = decode_packet(&self.token, &buf[..len])
+                .with_context(|| fallback_msg.to_string())?;
+            self.stamp = Some((header.stamp, Instant::now()));
+            if payload.is_empty() {
+                continue;
+            }
+
+            let res: Value = serde_json::from_slice(&payload)
+                .with_context(|| format!("{}: Failed to parse response", fallback_msg))?;
+            if res["id"].as_u64() != Some(id) {
+                // Late reply to an earlier request
+                continue;
+            }
+
+            return if !res["error"].is_null() {
+                let message = res["error"]["message"]
+                    .as_str()
+                    .unwrap_or(fallback_msg.as_str());
+                Err(MiioError::Cloud {
+                    code: res["error"]["code"].as_i64().unwrap_or(-1),
+                    message: message.to_string(),
+                })
+            } else {
+                Ok(res["result"].clone())
+            };
+        }
+    }
+
+    async fn connect(&self) -> Result<UdpSocket> {
+        let bind_addr: SocketAddr = if self.addr.is_ipv4() {
+            SocketAddr::from(([0, 0, 0, 0], 0))
+        } else {
+            SocketAddr::from(([0u16; 8], 0))
+        };
+        let socket = UdpSocket::bind(bind_addr)
+            .await
+            .map_err(MiioError::transport)
+            .with_context(|| "Failed to bind UDP socket")?;
+        socket
+            .connect(self.addr)
+            .await
+            .map_err(MiioError::transport)
+            .with_context(|| format!("Failed to connect to {}", self.addr))?;
+        Ok(socket)
+    }
+
+    /// Device stamp advanced by the time elapsed since it was last seen.
+    fn next_stamp(&self) -> u32 {
+        match self.stamp {
+            Some((stamp, seen_at)) => stamp.wrapping_add(seen_at.elapsed().as_secs() as u32 + 1),
+            None => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "00112233445566778899aabbccddeeff";
+
+    #[test]
+    fn hello_packet() {
+        let packet = super::hello_packet();
+        assert_eq!(&packet[0..4], &[0x21, 0x31, 0x00, 0x20]);
+        assert!(packet[4..].iter().all(|b| *b == 0xff));
+    }
+
+    #[test]
+    fn encrypt() {
+        let token = parse_token(TOKEN).unwrap();
+        let result = super::encrypt(
+            &token,
+            br#"{"id":1,"method":"get_prop","params":["power"]}"#,
+        );
+        let expect = "a5516ec6151955dc2bb2d43e7c84c1833ad6abd2560c09de4318b095b7713e230bbed4ee40764c0304f323716693cc0a";
+        assert_eq!(hex::encode(result), expect);
+    }
+
+    #[test]
+    fn encode_decode_packet() {
+        let token = parse_token(TOKEN).unwrap();
+        let packet = encode_packet(&token, 0x0102_0304, 42, b"{\"id\":7}");
+        let (header, payload) = decode_packet(&token, &packet).unwrap();
+        assert_eq!(header.device_id, 0x0102_0304);
+        assert_eq!(header.stamp, 42);
+        assert_eq!(payload, b"{\"id\":7}");
+
+        let mut corrupted = packet.clone();
+        *corrupted.last_mut().unwrap() ^= 0xff;
+        assert!(decode_packet(&token, &corrupted).is_err());
+    }
+
+    #[tokio::test]
+    async fn call_device_against_udp_stand_in() {
+        let token = parse_token(TOKEN).unwrap();
+        let device = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let device_addr = device.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+
+            // Hello: answer with device id and stamp
+            let (_, peer) = device.recv_from(&mut buf).await.unwrap();
+            let mut reply = super::hello_packet();
+            reply[4..8].copy_from_slice(&0u32.to_be_bytes());
+            reply[8..12].copy_from_slice(&1234u32.to_be_bytes());
+            reply[12..16].copy_from_slice(&100u32.to_be_bytes());
+            device.send_to(&reply, peer).await.unwrap();
+
+            // Request: echo the method back as the result
+            let (len, peer) = device.recv_from(&mut buf).await.unwrap();
+            let (header, payload) = decode_packet(&token, &buf[..len]).unwrap();
+            assert_eq!(header.device_id, 1234);
+            assert!(header.stamp > 100);
+            let req: Value = serde_json::from_slice(&payload).unwrap();
+            let res = json!({ "id": req["id"], "result": [req["method"], req["params"]] });
+            let packet = encode_packet(&token, 1234, header.stamp, res.to_string().as_bytes());
+            device.send_to(&packet, peer).await.unwrap();
+        });
+
+        let mut client = LocalMiioClient::new("127.0.0.1", TOKEN).unwrap();
+        client._override_addr(device_addr);
+        let result = client
+            .call_device("get_prop", Some(json!(["power"])))
+            .await
+            .unwrap();
+
+        assert_eq!(client.device_id(), Some(1234));
+        assert_eq!(result, json!(["get_prop", ["power"]]));
+    }
+
+    #[tokio::test]
+    async fn call_device_times_out() {
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut client = LocalMiioClient::new("127.0.0.1", TOKEN).unwrap();
+        client._override_addr(silent.local_addr().unwrap());
+        client.set_timeout(Duration::from_millis(100));
+
+        assert!(client.call_device("get_prop", None).await.is_err());
+    }
+}
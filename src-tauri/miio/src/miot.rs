@@ -0,0 +1,295 @@
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{error::Context, MiCloudErrorResponse, MiCloudOkResponse, MiCloudProtocol, Result};
+
+/// Addresses a single MIoT property by service and property id.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PropertyRequest {
+    pub did: String,
+    pub siid: u32,
+    pub piid: u32,
+}
+
+/// A MIoT property together with the value it should be set to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SetPropertyRequest {
+    pub did: String,
+    pub siid: u32,
+    pub piid: u32,
+    pub value: Value,
+}
+
+/// Per-property outcome of `/miotspec/prop/get` and `/miotspec/prop/set`.
+///
+/// `code` is `0` on success; negative codes are device or gateway errors
+/// (e.g. `-704042011` when the device is offline). When the request to the
+/// device's region failed as a whole, `code` and `message` describe that error.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PropertyResult {
+    pub did: String,
+    pub siid: u32,
+    pub piid: u32,
+    pub code: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<Value>,
+    #[serde(
+        default,
+        rename = "updateTime",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub update_time: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl PropertyResult {
+    pub fn is_ok(&self) -> bool {
+        self.code == 0
+    }
+}
+
+/// Invokes a MIoT action by service and action id with its input arguments.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ActionRequest {
+    pub did: String,
+    pub siid: u32,
+    pub aiid: u32,
+    #[serde(default, rename = "in")]
+    pub input: Vec<Value>,
+}
+
+/// Outcome of `/miotspec/action`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ActionResult {
+    pub code: i64,
+    #[serde(default)]
+    pub out: Vec<Value>,
+}
+
+impl ActionResult {
+    pub fn is_ok(&self) -> bool {
+        self.code == 0
+    }
+}
+
+impl MiCloudProtocol {
+    /// Reads MIoT properties. Every entry of the result carries its own `code`,
+    /// so a partially failed read still returns `Ok`.
+    ///
+    /// Devices in different regions are read with one request per region. The
+    /// results keep the order of `props`; only when every region fails is the
+    /// error returned.
+    pub async fn get_properties(
+        &self,
+        props: &[PropertyRequest],
+        country: Option<&str>,
+    ) -> Result<Vec<PropertyResult>> {
+        self.property_request(
+            "/miotspec/prop/get",
+            props,
+            |p| (&p.did, p.siid, p.piid),
+            |batch| json!({ "params": batch, "datasource": 1 }),
+            country,
+            "Get properties failed",
+        )
+        .await
+    }
+
+    /// Writes MIoT properties, returning the per-property result codes.
+    /// Like `get_properties`, a batch spanning regions is split by region.
+    pub async fn set_properties(
+        &self,
+        props: &[SetPropertyRequest],
+        country: Option<&str>,
+    ) -> Result<Vec<PropertyResult>> {
+        self.property_request(
+            "/miotspec/prop/set",
+            props,
+            |p| (&p.did, p.siid, p.piid),
+            |batch| json!({ "params": batch }),
+            country,
+            "Set properties failed",
+        )
+        .await
+    }
+
+    /// Runs a MIoT action on a device.
+    pub async fn action(
+        &self,
+        action: &ActionRequest,
+        country: Option<&str>,
+    ) -> Result<ActionResult> {
+        let req = json!({ "params": action });
+        let fallback_msg = format!("Action {}.{} failed", action.siid, action.aiid);
+        let country = self.device_country(&action.did, country);
+        // Actions like toggling or brewing act again when repeated, so they are never retried
+        self.miot_request(
+            "/miotspec/action",
+            req,
+            Some(&country),
+            &fallback_msg,
+            false,
+        )
+        .await
+    }
+
+    /// Sends `props` to the region of their device, one request per region.
+    /// `key` gives the did, siid and piid of a property.
+    async fn property_request<P: Serialize>(
+        &self,
+        path: &str,
+        props: &[P],
+        key: fn(&P) -> (&str, u32, u32),
+        req: fn(&[&P]) -> Value,
+        country: Option<&str>,
+        fallback_msg: &str,
+    ) -> Result<Vec<PropertyResult>> {
+        // Indices into `props`, grouped by region
+        let mut batches: Vec<(String, Vec<usize>)> = vec![];
+        for (i, prop) in props.iter().enumerate() {
+            let region = self.device_country(key(prop).0, country);
+            match batches.iter_mut().find(|(r, _)| *r == region) {
+                Some((_, batch)) => batch.push(i),
+                None => batches.push((region, vec![i])),
+            }
+        }
+
+        let results = join_all(batches.iter().map(|(region, batch)| {
+            let batch: Vec<&P> = batch.iter().map(|&i| &props[i]).collect();
+            self.miot_request::<Vec<PropertyResult>>(
+                path,
+                req(&batch),
+                Some(region),
+                fallback_msg,
+                true,
+            )
+        }))
+        .await;
+        // Nothing to report per property when every region failed, e.g. the session expired
+        if results.iter().all(Result::is_err) {
+            return results.into_iter().next().unwrap_or(Ok(vec![]));
+        }
+
+        let mut ordered: Vec<Option<PropertyResult>> = vec![None; props.len()];
+        let mut unmatched = vec![];
+        for ((_, batch), result) in batches.iter().zip(results) {
+            match result {
+                Ok(list) => {
+                    for result in list {
+                        let slot = batch.iter().find(|&&i| {
+                            ordered[i].is_none()
+                                && key(&props[i]) == (result.did.as_str(), result.siid, result.piid)
+                        });
+                        match slot {
+                            Some(&i) => ordered[i] = Some(result),
+                            None => unmatched.push(result),
+                        }
+                    }
+                }
+                // The other regions' results are still returned
+                Err(e) => {
+                    for &i in batch {
+                        let (did, siid, piid) = key(&props[i]);
+                        ordered[i] = Some(PropertyResult {
+                            did: did.to_string(),
+                            siid,
+                            piid,
+                            code: e.code().unwrap_or(-1),
+                            value: None,
+                            update_time: None,
+                            message: Some(e.to_string()),
+                        });
+                    }
+                }
+            }
+        }
+        Ok(ordered.into_iter().flatten().chain(unmatched).collect())
+    }
+
+    async fn miot_request<T: for<'de> Deserialize<'de>>(
+        &self,
+        path: &str,
+        req: Value,
+        country: Option<&str>,
+        fallback_msg: &str,
+        idempotent: bool,
+    ) -> Result<T> {
+        let country = country.unwrap_or(self.country.as_str());
+        let res = self
+            .request_with_retry(path, req, country, idempotent)
+            .await
+            .with_context(|| fallback_msg.to_string())?;
+
+        if !res["result"].is_null() {
+            let parsed_res: MiCloudOkResponse<T> = serde_json::from_value(res.clone())
+                .with_context(|| format!("{}: Unexpected response", fallback_msg))?;
+            Ok(parsed_res.result)
+        } else {
+            let parsed_err: MiCloudErrorResponse = serde_json::from_value(res.clone())?;
+            Err(parsed_err.into_error(fallback_msg))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn property_request_shape() {
+        let req = json!({
+            "params": [PropertyRequest { did: "123".to_string(), siid: 2, piid: 1 }],
+            "datasource": 1
+        });
+        assert_eq!(
+            req.to_string(),
+            r#"{"datasource":1,"params":[{"did":"123","piid":1,"siid":2}]}"#
+        );
+    }
+
+    #[test]
+    fn action_request_shape() {
+        let action = ActionRequest {
+            did: "123".to_string(),
+            siid: 5,
+            aiid: 1,
+            input: vec![json!("hello")],
+        };
+        assert_eq!(
+            serde_json::to_value(&action).unwrap(),
+            json!({ "did": "123", "siid": 5, "aiid": 1, "in": ["hello"] })
+        );
+    }
+
+    #[test]
+    fn parse_property_results() {
+        let res = json!({
+            "code": 0,
+            "message": "ok",
+            "result": [
+                { "did": "123", "siid": 2, "piid": 1, "value": true, "code": 0, "updateTime": 1700000000, "exe_time": 0 },
+                { "did": "123", "siid": 2, "piid": 9, "code": -704040003 }
+            ]
+        });
+        let parsed: MiCloudOkResponse<Vec<PropertyResult>> = serde_json::from_value(res).unwrap();
+        let results = parsed.result;
+
+        assert!(results[0].is_ok());
+        assert_eq!(results[0].value, Some(json!(true)));
+        assert_eq!(results[0].update_time, Some(1700000000));
+        assert!(!results[1].is_ok());
+        assert_eq!(results[1].code, -704040003);
+        assert_eq!(results[1].value, None);
+    }
+
+    #[test]
+    fn parse_action_result() {
+        let res =
+            json!({ "result": { "did": "123", "siid": 5, "aiid": 1, "code": 0, "out": [1] } });
+        let parsed: MiCloudOkResponse<ActionResult> = serde_json::from_value(res).unwrap();
+        assert!(parsed.result.is_ok());
+        assert_eq!(parsed.result.out, vec![json!(1)]);
+    }
+}
@@ -0,0 +1,157 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{MiioError, Result};
+
+/// A Mi Cloud server region.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Region {
+    /// Country code used by Xiaomi, e.g. `de` or `i2`
+    pub code: String,
+    /// Display name, e.g. `Germany`
+    pub name: String,
+    /// Base url of the device API, e.g. `https://de.api.io.mi.com/app`
+    pub api_url: String,
+    /// Base url of the account server handling the login
+    pub login_host: String,
+}
+
+impl Region {
+    pub fn new(code: &str, name: &str, api_url: &str, login_host: &str) -> Self {
+        Region {
+            code: code.to_string(),
+            name: name.to_string(),
+            api_url: api_url.to_string(),
+            login_host: login_host.to_string(),
+        }
+    }
+}
+
+/// The regions a `MiCloudProtocol` can talk to. The first region is the
+/// fallback used for logging in when no valid country is selected.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RegionRegistry {
+    regions: Vec<Region>,
+}
+
+impl RegionRegistry {
+    pub fn new(regions: Vec<Region>) -> Self {
+        RegionRegistry { regions }
+    }
+
+    /// Same region codes and names as the default registry, with every API and
+    /// login url pointing at `base_url`. Meant for tests against a local server.
+    pub fn with_base_url(base_url: &str) -> Self {
+        let regions = Self::default()
+            .regions
+            .into_iter()
+            .map(|region| Region {
+                api_url: base_url.to_string(),
+                login_host: base_url.to_string(),
+                ..region
+            })
+            .collect();
+        RegionRegistry { regions }
+    }
+
+    pub fn get(&self, code: &str) -> Option<&Region> {
+        self.regions.iter().find(|region| region.code == code)
+    }
+
+    /// Like `get`, but fails with `MiioError::UnsupportedRegion` for unknown codes.
+    pub fn require(&self, code: &str) -> Result<&Region> {
+        self.get(code)
+            .ok_or_else(|| MiioError::UnsupportedRegion(code.to_string()))
+    }
+
+    pub fn contains(&self, code: &str) -> bool {
+        self.get(code).is_some()
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    /// Adds a region or replaces the one with the same code.
+    pub fn insert(&mut self, region: Region) {
+        match self.regions.iter_mut().find(|r| r.code == region.code) {
+            Some(existing) => *existing = region,
+            None => self.regions.push(region),
+        }
+    }
+}
+
+impl Default for RegionRegistry {
+    // https://www.openhab.org/addons/bindings/miio/#country-servers
+    fn default() -> Self {
+        let login_host = "https://account.xiaomi.com";
+        let api_url = |code: &str| match code {
+            "cn" => "https://api.io.mi.com/app".to_string(),
+            _ => format!("https://{}.api.io.mi.com/app", code),
+        };
+        let regions = [
+            ("cn", "China"),
+            ("ru", "Russia"),
+            ("us", "USA"),
+            ("i2", "India"),
+            ("tw", "Taiwan"),
+            ("sg", "Singapore"),
+            ("de", "Germany"),
+        ]
+        .iter()
+        .map(|(code, name)| Region::new(code, name, &api_url(code), login_host))
+        .collect();
+
+        RegionRegistry { regions }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_regions() {
+        let registry = RegionRegistry::default();
+        assert_eq!(
+            registry.require("cn").unwrap().api_url,
+            "https://api.io.mi.com/app"
+        );
+        assert_eq!(
+            registry.require("i2").unwrap().api_url,
+            "https://i2.api.io.mi.com/app"
+        );
+        assert_eq!(registry.require("i2").unwrap().name, "India");
+        assert!(registry
+            .regions()
+            .iter()
+            .all(|r| r.login_host == "https://account.xiaomi.com"));
+        assert_eq!(
+            registry.require("xx"),
+            Err(MiioError::UnsupportedRegion("xx".to_string()))
+        );
+    }
+
+    #[test]
+    fn insert_replaces_region() {
+        let mut registry = RegionRegistry::with_base_url("http://127.0.0.1:1234");
+        assert_eq!(
+            registry.require("de").unwrap().api_url,
+            "http://127.0.0.1:1234"
+        );
+
+        registry.insert(Region::new(
+            "de",
+            "Germany",
+            "http://de.local",
+            "http://login.local",
+        ));
+        registry.insert(Region::new(
+            "eu",
+            "Europe",
+            "http://eu.local",
+            "http://login.local",
+        ));
+        assert_eq!(registry.require("de").unwrap().api_url, "http://de.local");
+        assert_eq!(registry.regions().len(), 8);
+    }
+}
@@ -0,0 +1,322 @@
+use rand::Rng;
+use serde_json::Value;
+use std::{future::Future, time::Duration};
+
+use crate::{MiioError, Result};
+
+/// Miio RPC methods whose effect depends on the current state, so running
+/// them twice (e.g. after a reset that hid a successful first call) changes
+/// the outcome.
+pub const NON_IDEMPOTENT_METHODS: &[&str] = &[
+    "toggle",
+    "bg_toggle",
+    "dev_toggle",
+    "set_adjust",
+    "bg_set_adjust",
+    "adjust_bright",
+    "adjust_ct",
+    "adjust_color",
+    "bg_adjust_bright",
+    "bg_adjust_ct",
+    "bg_adjust_color",
+];
+
+/// When and how often a failed Mi Cloud request is sent again.
+///
+/// Connection errors, timeouts, `5xx` statuses and rate-limit responses are
+/// retried; everything else (bad parameters, expired sessions, device errors)
+/// fails right away. The wait before retry `n` is `initial_backoff * 2^(n - 1)`,
+/// capped at `max_backoff`, of which a random half is cut off so clients that
+/// failed together don't retry together.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts including the first one; `1` disables retries
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// RPC methods `call_device` sends only once, see `NON_IDEMPOTENT_METHODS`
+    pub non_idempotent_methods: Vec<String>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(250),
+            max_backoff: Duration::from_secs(5),
+            non_idempotent_methods: NON_IDEMPOTENT_METHODS
+                .iter()
+                .map(|m| m.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Sends every request exactly once.
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_idempotent(&self, method: &str) -> bool {
+        !self.non_idempotent_methods.iter().any(|m| m == method)
+    }
+
+    /// Whether a request that failed with `err` may succeed when sent again.
+    pub fn is_retryable(&self, err: &MiioError) -> bool {
+        match err {
+            MiioError::Transport(_) => true,
+            MiioError::Http { status } => *status == 429 || (500..600).contains(status),
+            _ => false,
+        }
+    }
+
+    /// Wait before retry number `retry`, starting at 1.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff);
+        let jitter = rand::thread_rng().gen_range(0.0..=0.5);
+        backoff.mul_f64(1.0 - jitter)
+    }
+
+    /// Runs `send` until it succeeds, fails with an error that isn't retryable or
+    /// runs out of attempts. Requests that aren't `idempotent` are sent once.
+    ///
+    /// A rate-limit answer is still returned as is after the last attempt, so
+    /// callers turn it into a `MiioError::Cloud` like any other error response.
+    pub(crate) async fn run<F, Fut>(&self, idempotent: bool, send: F) -> Result<Value>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<Value>>,
+    {
+        let max_attempts = if idempotent { self.max_attempts } else { 1 };
+        let mut attempt = 1;
+        loop {
+            let res = send().await;
+            let retryable = match &res {
+                Ok(value) => is_rate_limited(value),
+                Err(err) => self.is_retryable(err),
+            };
+            if !retryable || attempt >= max_attempts {
+                return res;
+            }
+            tokio::time::sleep(self.backoff(attempt)).await;
+            attempt += 1;
+        }
+    }
+}
+
+/// Mi Cloud answers throttled requests with HTTP 200 and an error message
+/// such as `request too frequently`.
+fn is_rate_limited(res: &Value) -> bool {
+    if !res["result"].is_null() {
+        return false;
+    }
+    let message = res["message"]
+        .as_str()
+        .or(res["error"]["message"].as_str())
+        .unwrap_or("")
+        .to_lowercase();
+    ["too frequent", "too many", "rate limit"]
+        .iter()
+        .any(|pattern| message.contains(pattern))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_server::{serve, StubRequest, StubResponse},
+        ActionRequest, MiCloudProtocol, RegionRegistry, SecureSession,
+    };
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 4,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(20),
+            ..Default::default()
+        }
+    }
+
+    /// Serves `responses` one after the other, then `{"result": "ok"}`, and
+    /// records the body of every request.
+    async fn flaky_cloud(
+        responses: Vec<StubResponse>,
+    ) -> (MiCloudProtocol, Arc<Mutex<Vec<String>>>) {
+        let bodies = Arc::new(Mutex::new(vec![]));
+        let responses = Mutex::new(responses.into_iter());
+        let recorded = bodies.clone();
+        let base = serve(move |req: StubRequest| {
+            recorded.lock().unwrap().push(req.body);
+            responses.lock().unwrap().next().unwrap_or_else(|| {
+                StubResponse::ok(json!({ "code": 0, "result": "ok" }).to_string())
+            })
+        })
+        .await;
+
+        let mut mi = MiCloudProtocol::builder()
+            .retry_policy(policy())
+            .regions(RegionRegistry::with_base_url(&base))
+            .build()
+            .unwrap();
+        mi.import_secure_session(SecureSession {
+            username: "username".to_string(),
+            ssecurity: "/pM9bFOMTk4/6sSeqnpchA==".to_string(),
+            user_id: "42".to_string(),
+            country: "de".to_string(),
+            service_token: "token".to_string(),
+            client_id: "ABCDEF".to_string(),
+            pass_token: None,
+            device_regions: Default::default(),
+        });
+        (mi, bodies)
+    }
+
+    fn form_field<'a>(body: &'a str, name: &str) -> Option<&'a str> {
+        body.split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(k, _)| *k == name)
+            .map(|(_, v)| v)
+    }
+
+    #[test]
+    fn backoff_grows_with_jitter() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(300),
+            ..Default::default()
+        };
+        for _ in 0..20 {
+            let first = policy.backoff(1);
+            assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+            let second = policy.backoff(2);
+            assert!(second >= Duration::from_millis(100) && second <= Duration::from_millis(200));
+            let capped = policy.backoff(10);
+            assert!(capped >= Duration::from_millis(150) && capped <= Duration::from_millis(300));
+        }
+    }
+
+    #[test]
+    fn retryable_errors() {
+        let policy = RetryPolicy::default();
+        assert!(policy.is_retryable(&MiioError::Transport("reset".to_string())));
+        assert!(policy.is_retryable(&MiioError::Http { status: 503 }));
+        assert!(policy.is_retryable(&MiioError::Http { status: 429 }));
+        assert!(!policy.is_retryable(&MiioError::Http { status: 400 }));
+        assert!(!policy.is_retryable(&MiioError::AuthExpired));
+        assert!(is_rate_limited(
+            &json!({ "code": -106, "message": "Request too frequently" })
+        ));
+        assert!(!is_rate_limited(
+            &json!({ "code": -2, "message": "bad request" })
+        ));
+    }
+
+    #[tokio::test]
+    async fn retries_with_a_new_signature() {
+        let (mi, bodies) = flaky_cloud(vec![
+            StubResponse::new(503, ""),
+            StubResponse::close(),
+            StubResponse::new(429, ""),
+        ])
+        .await;
+
+        let res = mi.call_device("1", "get_prop", None, None).await.unwrap();
+        assert_eq!(res, "ok");
+
+        let bodies = bodies.lock().unwrap();
+        assert_eq!(bodies.len(), 4);
+        let mut nonces: Vec<&str> = bodies
+            .iter()
+            .map(|b| form_field(b, "_nonce").unwrap())
+            .collect();
+        let mut signatures: Vec<&str> = bodies
+            .iter()
+            .map(|b| form_field(b, "signature").unwrap())
+            .collect();
+        nonces.sort();
+        nonces.dedup();
+        signatures.sort();
+        signatures.dedup();
+        assert_eq!(nonces.len(), 4);
+        assert_eq!(signatures.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn retries_rate_limit_responses() {
+        let throttled = json!({ "code": -106, "message": "request too frequently" }).to_string();
+        let (mi, bodies) = flaky_cloud(vec![StubResponse::ok(throttled.clone())]).await;
+        assert_eq!(
+            mi.call_device("1", "get_prop", None, None).await.unwrap(),
+            "ok"
+        );
+        assert_eq!(bodies.lock().unwrap().len(), 2);
+
+        // Still throttled after the last attempt: reported as a cloud error
+        let (mi, bodies) = flaky_cloud(
+            (0..4)
+                .map(|_| StubResponse::ok(throttled.clone()))
+                .collect(),
+        )
+        .await;
+        let err = mi
+            .call_device("1", "get_prop", None, None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Some(-106));
+        assert_eq!(bodies.lock().unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let failures = (0..10).map(|_| StubResponse::new(500, "")).collect();
+        let (mi, bodies) = flaky_cloud(failures).await;
+
+        let err = mi
+            .call_device("1", "get_prop", None, None)
+            .await
+            .unwrap_err();
+        assert_eq!(err, MiioError::Http { status: 500 });
+        assert_eq!(bodies.lock().unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_client_errors() {
+        let (mi, bodies) = flaky_cloud(vec![StubResponse::new(400, "")]).await;
+
+        let err = mi
+            .call_device("1", "get_prop", None, None)
+            .await
+            .unwrap_err();
+        assert_eq!(err, MiioError::Http { status: 400 });
+        assert_eq!(bodies.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn non_idempotent_calls_are_sent_once() {
+        let (mi, bodies) = flaky_cloud(vec![StubResponse::new(503, "")]).await;
+        let err = mi.call_device("1", "toggle", None, None).await.unwrap_err();
+        assert_eq!(err, MiioError::Http { status: 503 });
+        assert_eq!(bodies.lock().unwrap().len(), 1);
+
+        let (mi, bodies) = flaky_cloud(vec![StubResponse::close()]).await;
+        let action = ActionRequest {
+            did: "1".to_string(),
+            siid: 2,
+            aiid: 1,
+            input: vec![],
+        };
+        let err = mi.action(&action, None).await.unwrap_err();
+        assert_eq!(err.kind(), "transport");
+        assert_eq!(bodies.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,155 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{error::Context, Home, MiCloudErrorResponse, MiCloudProtocol, Result};
+
+const SCENE_SERVICE: &str = "/appgateway/miot/appsceneservice/AppSceneService";
+
+/// Trigger key of scenes started by hand from the app.
+const MANUAL_TRIGGER: &str = "user.click";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SceneKind {
+    /// Started by hand ("Tap to run" in Mi Home); can be run with `run_scene`
+    Manual,
+    /// Started by the cloud on device events or schedules
+    Automation,
+}
+
+/// A Mi Home scene or automation.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Scene {
+    pub id: String,
+    pub name: String,
+    pub home_id: String,
+    pub kind: SceneKind,
+    pub enabled: bool,
+}
+
+impl Scene {
+    fn from_value(home_id: &str, value: &Value) -> Option<Self> {
+        let id = match &value["scene_id"] {
+            Value::String(s) => s.clone(),
+            Value::Number(n) => n.to_string(),
+            _ => return None,
+        };
+        let name = value["scene_name"]
+            .as_str()
+            .or(value["name"].as_str())
+            .unwrap_or_default()
+            .to_string();
+        // Manual scenes have a single `user.click` condition (or none at all)
+        let conditions = value["trigger"]["conditions"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        let kind = if conditions
+            .iter()
+            .all(|c| c["key"].as_str() == Some(MANUAL_TRIGGER))
+        {
+            SceneKind::Manual
+        } else {
+            SceneKind::Automation
+        };
+        let enabled = value["enable"].as_bool().unwrap_or(true);
+
+        Some(Scene {
+            id,
+            name,
+            home_id: home_id.to_string(),
+            kind,
+            enabled,
+        })
+    }
+}
+
+impl MiCloudProtocol {
+    /// Lists the manual scenes and automations of a home.
+    pub async fn get_scenes(&self, home: &Home, country: Option<&str>) -> Result<Vec<Scene>> {
+        let mut req = json!({
+            "home_id": home.id,
+            "source": "zkp",
+            "get_type": 2
+        });
+        if let Some(uid) = home.uid {
+            req["owner_uid"] = json!(uid);
+        }
+        let result = self
+            .scene_request("GetSceneList", req, country, "Get scenes failed", true)
+            .await?;
+
+        let scenes = result["scene_info_list"]
+            .as_array()
+            .map(|list| {
+                list.iter()
+                    .filter_map(|scene| Scene::from_value(&home.id, scene))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(scenes)
+    }
+
+    /// Runs a manual scene.
+    pub async fn run_scene(&self, scene_id: &str, country: Option<&str>) -> Result<()> {
+        let req = json!({ "scene_id": scene_id, "trigger_key": MANUAL_TRIGGER });
+        let fallback_msg = format!("Run scene {} failed", scene_id);
+        // Scenes may toggle devices, so a run is never repeated
+        self.scene_request("RunScene", req, country, &fallback_msg, false)
+            .await?;
+        Ok(())
+    }
+
+    async fn scene_request(
+        &self,
+        method: &str,
+        req: Value,
+        country: Option<&str>,
+        fallback_msg: &str,
+        idempotent: bool,
+    ) -> Result<Value> {
+        let country = country.unwrap_or(self.country.as_str());
+        let path = format!("{}/{}", SCENE_SERVICE, method);
+        let res = self
+            .request_encrypted_with_retry(&path, req, country, idempotent)
+            .await
+            .with_context(|| fallback_msg.to_string())?;
+
+        if !res["result"].is_null() {
+            Ok(res["result"].clone())
+        } else {
+            let parsed_err: MiCloudErrorResponse = serde_json::from_value(res.clone())?;
+            Err(parsed_err.into_error(fallback_msg))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_scenes() {
+        let manual = json!({
+            "scene_id": "1001", "scene_name": "Good night", "enable": true,
+            "trigger": { "conditions": [{ "key": "user.click" }] }
+        });
+        let automation = json!({
+            "scene_id": 1002, "scene_name": "Door opened", "enable": false,
+            "trigger": { "conditions": [{ "key": "event.lumi.sensor_magnet.open", "did": "1" }] }
+        });
+
+        let scene = Scene::from_value("100", &manual).unwrap();
+        assert_eq!(scene.id, "1001");
+        assert_eq!(scene.kind, SceneKind::Manual);
+        assert!(scene.enabled);
+
+        let scene = Scene::from_value("100", &automation).unwrap();
+        assert_eq!(scene.id, "1002");
+        assert_eq!(scene.name, "Door opened");
+        assert_eq!(scene.kind, SceneKind::Automation);
+        assert!(!scene.enabled);
+
+        assert!(Scene::from_value("100", &json!({ "scene_name": "No id" })).is_none());
+    }
+}
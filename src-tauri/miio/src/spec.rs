@@ -0,0 +1,391 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::{
+    error::{Context, MiioError, Result},
+    Device,
+};
+
+/// A MIoT type URN such as
+/// `urn:miot-spec-v2:property:brightness:0000000D:yeelink-color1:1`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Urn {
+    pub namespace: String,
+    pub kind: String,
+    pub name: String,
+    pub value: String,
+    pub vendor_product: Option<String>,
+    pub version: Option<u32>,
+}
+
+impl FromStr for Urn {
+    type Err = MiioError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split(':').collect();
+        if parts.len() < 5 || parts[0] != "urn" {
+            return Err(MiioError::Parse(format!("Invalid MIoT urn '{}'", s)));
+        }
+        Ok(Urn {
+            namespace: parts[1].to_string(),
+            kind: parts[2].to_string(),
+            name: parts[3].to_string(),
+            value: parts[4].to_string(),
+            vendor_product: parts.get(5).map(|p| p.to_string()),
+            version: parts.get(6).and_then(|v| v.parse().ok()),
+        })
+    }
+}
+
+impl fmt::Display for Urn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "urn:{}:{}:{}:{}",
+            self.namespace, self.kind, self.name, self.value
+        )?;
+        if let Some(vendor_product) = &self.vendor_product {
+            write!(f, ":{}", vendor_product)?;
+        }
+        if let Some(version) = self.version {
+            write!(f, ":{}", version)?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for Urn {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Urn {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    Read,
+    Write,
+    Notify,
+}
+
+/// `value-range` of a numeric property, stored in the spec as `[min, max, step]`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ValueRange {
+    pub min: f64,
+    pub max: f64,
+    pub step: f64,
+}
+
+impl ValueRange {
+    pub fn contains(&self, value: f64) -> bool {
+        value >= self.min && value <= self.max
+    }
+}
+
+impl Serialize for ValueRange {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        [self.min, self.max, self.step].serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ValueRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let range = Vec::<f64>::deserialize(deserializer)?;
+        match range.as_slice() {
+            [min, max, step, ..] => Ok(ValueRange {
+                min: *min,
+                max: *max,
+                step: *step,
+            }),
+            [min, max] => Ok(ValueRange {
+                min: *min,
+                max: *max,
+                step: 1.0,
+            }),
+            _ => Err(serde::de::Error::custom(format!(
+                "Invalid value-range {:?}",
+                range
+            ))),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ValueListItem {
+    pub value: serde_json::Value,
+    pub description: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PropertySpec {
+    pub iid: u32,
+    #[serde(rename = "type")]
+    pub urn: Urn,
+    #[serde(default)]
+    pub description: String,
+    pub format: String,
+    #[serde(default)]
+    pub access: Vec<Access>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    #[serde(
+        default,
+        rename = "value-range",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub value_range: Option<ValueRange>,
+    #[serde(
+        default,
+        rename = "value-list",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub value_list: Option<Vec<ValueListItem>>,
+}
+
+impl PropertySpec {
+    pub fn is_readable(&self) -> bool {
+        self.access.contains(&Access::Read)
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.access.contains(&Access::Write)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ActionSpec {
+    pub iid: u32,
+    #[serde(rename = "type")]
+    pub urn: Urn,
+    #[serde(default)]
+    pub description: String,
+    /// Property iids (of the same service) the action takes as input
+    #[serde(default, rename = "in")]
+    pub input: Vec<u32>,
+    /// Property iids (of the same service) the action returns
+    #[serde(default)]
+    pub out: Vec<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServiceSpec {
+    pub iid: u32,
+    #[serde(rename = "type")]
+    pub urn: Urn,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub properties: Vec<PropertySpec>,
+    #[serde(default)]
+    pub actions: Vec<ActionSpec>,
+}
+
+impl ServiceSpec {
+    pub fn property(&self, piid: u32) -> Option<&PropertySpec> {
+        self.properties.iter().find(|p| p.iid == piid)
+    }
+
+    pub fn property_by_name(&self, name: &str) -> Option<&PropertySpec> {
+        self.properties.iter().find(|p| p.urn.name == name)
+    }
+
+    pub fn action(&self, aiid: u32) -> Option<&ActionSpec> {
+        self.actions.iter().find(|a| a.iid == aiid)
+    }
+}
+
+/// A parsed MIoT device spec (one `miot-spec-v2` instance document).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeviceSpec {
+    #[serde(rename = "type")]
+    pub urn: Urn,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub services: Vec<ServiceSpec>,
+}
+
+impl DeviceSpec {
+    pub fn from_json(json: &str) -> Result<Self> {
+        serde_json::from_str(json).with_context(|| "Failed to parse MIoT spec")
+    }
+
+    pub fn service(&self, siid: u32) -> Option<&ServiceSpec> {
+        self.services.iter().find(|s| s.iid == siid)
+    }
+
+    pub fn service_by_name(&self, name: &str) -> Option<&ServiceSpec> {
+        self.services.iter().find(|s| s.urn.name == name)
+    }
+
+    /// Looks up a property by service and property name, e.g. `("light", "brightness")`,
+    /// returning the `siid` alongside it.
+    pub fn find_property(&self, service: &str, property: &str) -> Option<(u32, &PropertySpec)> {
+        self.services
+            .iter()
+            .filter(|s| s.urn.name == service)
+            .find_map(|s| s.property_by_name(property).map(|p| (s.iid, p)))
+    }
+}
+
+/// On-disk cache of MIoT specs, one `<model>.json` file per device model.
+pub struct SpecCache {
+    dir: PathBuf,
+}
+
+impl SpecCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        SpecCache { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Loads the cached spec for `model`, or `None` if it was never cached.
+    pub fn load(&self, model: &str) -> Result<Option<DeviceSpec>> {
+        let path = self.path_for(model)?;
+        if !path.exists() {
+            return Ok(None);
+        }
+        let json = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read spec cache {}", path.display()))?;
+        DeviceSpec::from_json(&json)
+            .with_context(|| format!("Invalid spec cache for model {}", model))
+            .map(Some)
+    }
+
+    pub fn load_for(&self, device: &Device) -> Result<Option<DeviceSpec>> {
+        self.load(&device.model)
+    }
+
+    pub fn store(&self, model: &str, spec: &DeviceSpec) -> Result<()> {
+        let path = self.path_for(model)?;
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create {}", self.dir.display()))?;
+        let json = serde_json::to_string_pretty(spec)?;
+        fs::write(&path, json)
+            .with_context(|| format!("Failed to write spec cache {}", path.display()))
+    }
+
+    /// Models of every spec currently in the cache.
+    pub fn models(&self) -> Result<Vec<String>> {
+        if !self.dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut models: Vec<String> = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                entry
+                    .file_name()
+                    .to_str()
+                    .and_then(|name| name.strip_suffix(".json"))
+                    .map(|model| model.to_string())
+            })
+            .collect();
+        models.sort();
+        Ok(models)
+    }
+
+    fn path_for(&self, model: &str) -> Result<PathBuf> {
+        if model.is_empty()
+            || !model
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_')
+            || model.contains("..")
+        {
+            return Err(MiioError::Invalid(format!(
+                "Invalid device model '{}'",
+                model
+            )));
+        }
+        Ok(self.dir.join(format!("{}.json", model)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixtures() -> SpecCache {
+        SpecCache::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/specs"))
+    }
+
+    #[test]
+    fn parse_urn() {
+        let urn: Urn = "urn:miot-spec-v2:property:brightness:0000000D:yeelink-color1:1"
+            .parse()
+            .unwrap();
+        assert_eq!(urn.namespace, "miot-spec-v2");
+        assert_eq!(urn.kind, "property");
+        assert_eq!(urn.name, "brightness");
+        assert_eq!(urn.value, "0000000D");
+        assert_eq!(urn.vendor_product.as_deref(), Some("yeelink-color1"));
+        assert_eq!(urn.version, Some(1));
+        assert_eq!(
+            urn.to_string(),
+            "urn:miot-spec-v2:property:brightness:0000000D:yeelink-color1:1"
+        );
+
+        assert!("not-an-urn".parse::<Urn>().is_err());
+    }
+
+    #[test]
+    fn load_fixture_spec() {
+        let spec = fixtures().load("yeelink.light.color1").unwrap().unwrap();
+        assert_eq!(spec.urn.kind, "device");
+        assert_eq!(spec.urn.name, "light");
+
+        let (siid, on) = spec.find_property("light", "on").unwrap();
+        assert_eq!(siid, 2);
+        assert_eq!(on.iid, 1);
+        assert_eq!(on.format, "bool");
+        assert!(on.is_readable() && on.is_writable());
+
+        let light = spec.service(2).unwrap();
+        let brightness = light.property_by_name("brightness").unwrap();
+        let range = brightness.value_range.unwrap();
+        assert_eq!((range.min, range.max, range.step), (1.0, 100.0, 1.0));
+        assert!(range.contains(50.0) && !range.contains(0.0));
+        assert_eq!(brightness.unit.as_deref(), Some("percentage"));
+
+        let mode = light.property(2).unwrap();
+        let values = mode.value_list.as_ref().unwrap();
+        assert_eq!(values[1].description, "Day");
+
+        assert_eq!(light.action(1).unwrap().urn.name, "toggle");
+
+        let manufacturer = spec.service(1).unwrap().property(1).unwrap();
+        assert!(manufacturer.is_readable() && !manufacturer.is_writable());
+    }
+
+    #[test]
+    fn missing_model_is_none() {
+        assert!(fixtures().load("unknown.model.v1").unwrap().is_none());
+        assert!(fixtures().load("../escape").is_err());
+    }
+
+    #[test]
+    fn store_round_trip() {
+        let spec = fixtures().load("chuangmi.plug.m3").unwrap().unwrap();
+        let dir = std::env::temp_dir().join(format!("miio-spec-cache-{}", std::process::id()));
+        let cache = SpecCache::new(&dir);
+
+        cache.store("chuangmi.plug.m3", &spec).unwrap();
+        assert_eq!(cache.models().unwrap(), vec!["chuangmi.plug.m3"]);
+        assert_eq!(cache.load("chuangmi.plug.m3").unwrap(), Some(spec));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
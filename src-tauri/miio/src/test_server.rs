@@ -0,0 +1,147 @@
+//! Minimal HTTP/1.1 stub server for exercising the cloud client in tests.
+
+use std::sync::Arc;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+pub struct StubRequest {
+    pub method: String,
+    /// Path including the query string
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl StubRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+pub struct StubResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Close the connection without answering, like a reset by the peer
+    pub close: bool,
+}
+
+impl StubResponse {
+    pub fn new(status: u16, body: impl Into<Vec<u8>>) -> Self {
+        StubResponse {
+            status,
+            headers: vec![],
+            body: body.into(),
+            close: false,
+        }
+    }
+
+    pub fn ok(body: impl Into<Vec<u8>>) -> Self {
+        Self::new(200, body)
+    }
+
+    pub fn close() -> Self {
+        StubResponse {
+            close: true,
+            ..Self::new(0, "")
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+/// Starts a stub server on a random local port and returns its base url.
+pub async fn serve<F>(handler: F) -> String
+where
+    F: Fn(StubRequest) -> StubResponse + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handler = Arc::new(handler);
+
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let handler = handler.clone();
+            tokio::spawn(async move {
+                if let Some(req) = read_request(&mut stream).await {
+                    let res = handler(req);
+                    let _ = write_response(&mut stream, res).await;
+                }
+            });
+        }
+    });
+
+    format!("http://{}", addr)
+}
+
+async fn read_request(stream: &mut TcpStream) -> Option<StubRequest> {
+    let mut buf: Vec<u8> = vec![];
+    let mut chunk = [0u8; 4096];
+    let header_end = loop {
+        let n = stream.read(&mut chunk).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos;
+        }
+    };
+
+    let head = String::from_utf8_lossy(&buf[..header_end]).to_string();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?.to_string();
+    let path = request_line.next()?.to_string();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect();
+
+    let content_length = headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, v)| v.parse::<usize>().ok())
+        .unwrap_or(0);
+    let mut body = buf[header_end + 4..].to_vec();
+    while body.len() < content_length {
+        let n = stream.read(&mut chunk).await.ok()?;
+        if n == 0 {
+            break;
+        }
+        body.extend_from_slice(&chunk[..n]);
+    }
+
+    Some(StubRequest {
+        method,
+        path,
+        headers,
+        body: String::from_utf8_lossy(&body).to_string(),
+    })
+}
+
+async fn write_response(stream: &mut TcpStream, res: StubResponse) -> std::io::Result<()> {
+    if res.close {
+        return stream.shutdown().await;
+    }
+    let mut head = format!(
+        "HTTP/1.1 {} Stub\r\nContent-Length: {}\r\nConnection: close\r\n",
+        res.status,
+        res.body.len()
+    );
+    for (k, v) in &res.headers {
+        head.push_str(&format!("{}: {}\r\n", k, v));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&res.body).await?;
+    stream.shutdown().await
+}
@@ -0,0 +1,183 @@
+//! Timeouts, pooling and headers of the shared HTTP client, against a local
+//! server that can be told to answer slowly.
+
+use miio::{
+    MiCloudProtocol, MiCloudProtocolBuilder, MiioError, RegionRegistry, RetryPolicy, SecureSession,
+};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+const DEVICE_LIST: &str = r#"{"code":0,"message":"ok","result":{"list":[]}}"#;
+
+#[derive(Default)]
+struct Server {
+    connections: AtomicUsize,
+    /// Raw request heads, in the order they were received
+    requests: Mutex<Vec<String>>,
+}
+
+/// Serves `DEVICE_LIST` after waiting `delay`, keeping connections alive.
+async fn slow_server(delay: Duration) -> (String, Arc<Server>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Arc::new(Server::default());
+
+    let state = server.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            state.connections.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(handle_connection(stream, state.clone(), delay));
+        }
+    });
+
+    (format!("http://{}", addr), server)
+}
+
+async fn handle_connection(mut stream: TcpStream, server: Arc<Server>, delay: Duration) {
+    let mut buf: Vec<u8> = vec![];
+    let mut chunk = [0u8; 4096];
+    loop {
+        // Read one request: its head, then `Content-Length` bytes of body
+        let head_end = loop {
+            if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break pos + 4;
+            }
+            match stream.read(&mut chunk).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            }
+        };
+        let head = String::from_utf8_lossy(&buf[..head_end]).to_string();
+        let content_length: usize = head
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, value)| value.trim().parse().ok())
+            .unwrap_or(0);
+        while buf.len() < head_end + content_length {
+            match stream.read(&mut chunk).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            }
+        }
+        buf.drain(..head_end + content_length);
+        server.requests.lock().unwrap().push(head);
+
+        tokio::time::sleep(delay).await;
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            DEVICE_LIST.len(),
+            DEVICE_LIST
+        );
+        if stream.write_all(response.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+fn logged_in(builder: MiCloudProtocolBuilder, base: &str) -> MiCloudProtocol {
+    let mut mi = builder
+        .regions(RegionRegistry::with_base_url(base))
+        .build()
+        .unwrap();
+    mi.import_secure_session(SecureSession {
+        username: "username".to_string(),
+        ssecurity: "/pM9bFOMTk4/6sSeqnpchA==".to_string(),
+        user_id: "42".to_string(),
+        country: "de".to_string(),
+        service_token: "token".to_string(),
+        client_id: "ABCDEF".to_string(),
+        pass_token: None,
+        device_regions: Default::default(),
+    });
+    mi
+}
+
+#[tokio::test]
+async fn read_timeout_fails_a_hanging_request() {
+    let (base, _server) = slow_server(Duration::from_secs(30)).await;
+    let builder = MiCloudProtocol::builder()
+        .retry_policy(RetryPolicy::none())
+        .read_timeout(Duration::from_millis(200));
+    let mi = logged_in(builder, &base);
+
+    let started = Instant::now();
+    let err = mi.get_devices(None, None).await.unwrap_err();
+    assert!(matches!(err, MiioError::Transport(_)), "{:?}", err);
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[tokio::test]
+async fn timeout_bounds_the_whole_request() {
+    let (base, _server) = slow_server(Duration::from_secs(30)).await;
+    let builder = MiCloudProtocol::builder()
+        .retry_policy(RetryPolicy::none())
+        .timeout(Duration::from_millis(200));
+    let mi = logged_in(builder, &base);
+
+    let started = Instant::now();
+    let err = mi.get_devices(None, None).await.unwrap_err();
+    assert!(matches!(err, MiioError::Transport(_)), "{:?}", err);
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[tokio::test]
+async fn slow_responses_within_the_timeout_succeed() {
+    let (base, _server) = slow_server(Duration::from_millis(100)).await;
+    let builder = MiCloudProtocol::builder().read_timeout(Duration::from_secs(2));
+    let mi = logged_in(builder, &base);
+
+    assert!(mi.get_devices(None, None).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn connections_are_reused() {
+    let (base, server) = slow_server(Duration::ZERO).await;
+    let mi = logged_in(MiCloudProtocol::builder(), &base);
+
+    for _ in 0..3 {
+        mi.get_devices(None, None).await.unwrap();
+    }
+    assert_eq!(server.requests.lock().unwrap().len(), 3);
+    assert_eq!(server.connections.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn pooling_can_be_disabled() {
+    let (base, server) = slow_server(Duration::ZERO).await;
+    let builder = MiCloudProtocol::builder().pool_max_idle_per_host(0);
+    let mi = logged_in(builder, &base);
+
+    for _ in 0..2 {
+        mi.get_devices(None, None).await.unwrap();
+    }
+    assert_eq!(server.connections.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn custom_user_agent_and_locale_are_sent() {
+    let (base, server) = slow_server(Duration::ZERO).await;
+    let builder = MiCloudProtocol::builder()
+        .user_agent("toolkit-test/1.0")
+        .locale("de");
+    let mi = logged_in(builder, &base);
+
+    mi.get_devices(None, None).await.unwrap();
+    let requests = server.requests.lock().unwrap();
+    let head = requests[0].to_lowercase();
+    assert!(
+        head.contains("user-agent: toolkit-test/1.0\r\n"),
+        "{}",
+        head
+    );
+    assert!(head.contains("locale=de"), "{}", head);
+}
@@ -5,23 +5,39 @@ extern crate anyhow;
 extern crate miio;
 extern crate serde_json;
 
+mod mqtt;
+mod rest_api;
+mod rules;
+mod scheduler;
+mod sun;
 
-use miio::{Device, MiCloudProtocol, Credentials, SecureSession};
+use miio::{Device, MiCloudProtocol, Credentials, SecureSession, PropertyRequest, PropertyResult, SetPropertyRequest, ActionRequest, ActionResult, DeviceSpec, SpecCache, LoginOutcome, TwoFactorMethod, MiioError, Home, DevicesByHome, Scene, DeviceFilter, DeviceCredentials, ExportFormat};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::{fs, path::PathBuf};
+use std::{collections::{HashMap, HashSet}, fs, io::Write, path::PathBuf, time::{Duration, Instant}};
 
-use tauri::{Emitter, Manager, AppHandle, WindowEvent, tray::{TrayIconBuilder, MouseButton}, menu::{MenuBuilder, MenuItem}};
+use tauri::{Emitter, Manager, AppHandle, WindowEvent, tray::{TrayIconBuilder, MouseButton}, menu::{Menu, MenuBuilder}};
 use tauri_plugin_log::{Builder, Target, TargetKind};
-use tauri_plugin_global_shortcut::{GlobalShortcutExt, ShortcutWrapper};
+use tauri_plugin_global_shortcut::{GlobalShortcutExt, ShortcutState, ShortcutWrapper};
 use tauri_plugin_autostart::{MacosLauncher, ManagerExt};
+use tauri_plugin_fs::{FsExt, OpenOptions};
 use tokio::sync::Mutex;
+use futures::future::join_all;
 use lazy_static::lazy_static;
 
 lazy_static! {
     static ref MI_CLOUD_PROTOCOL: std::sync::Arc<Mutex<MiCloudProtocol>> = std::sync::Arc::new(Mutex::new(MiCloudProtocol::new()));
+    // Devices the poller reads, loaded from poll_targets.json on startup
+    static ref POLL_TARGETS: std::sync::Mutex<Vec<PollTarget>> = std::sync::Mutex::new(vec![]);
+    // Last polled property values per did
+    static ref DEVICE_STATE: std::sync::Mutex<HashMap<String, HashMap<String, Value>>> = std::sync::Mutex::new(HashMap::new());
+    // Dids whose poll is still running
+    static ref POLLS_IN_FLIGHT: std::sync::Mutex<HashSet<String>> = std::sync::Mutex::new(HashSet::new());
 }
 
+// Shortest poll interval, the cloud throttles accounts that poll too often
+const MIN_POLL_INTERVAL_SECS: u64 = 5;
+
 // Simple struct for backwards compatibility - still used for some operations
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct SavedCredentials {
@@ -36,6 +52,117 @@ struct SavedCommand {
     method: String,
     params: String,
     shortcut: Option<String>,
+    // Mi Home scene to run instead of calling `method` on a device
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scene_id: Option<String>,
+    // Target device
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    did: Option<String>,
+    // Model the target device must have, guards against a did reused by another device
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+    // Several devices to run the command on instead of `did`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<CommandTarget>,
+}
+
+impl SavedCommand {
+    // Commands saved before they carried a target
+    fn is_legacy(&self) -> bool {
+        self.did.is_none() && self.scene_id.is_none() && self.target.is_none()
+    }
+}
+
+// Multi-device target of a saved command
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum CommandTarget {
+    // A named device group
+    Group { name: String },
+    // Every device matching a model glob and/or home and room
+    Filter(DeviceFilter),
+}
+
+// Named set of devices
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct DeviceGroup {
+    name: String,
+    dids: Vec<String>,
+}
+
+// Struct for the device groups JSON file
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct DeviceGroups {
+    groups: Vec<DeviceGroup>,
+}
+
+// Outcome of a saved command on one device
+#[derive(Serialize, Debug, Clone)]
+struct DeviceResult {
+    did: String,
+    name: String,
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// Per-device report of a saved command run, also emitted as `command-executed`
+#[derive(Serialize, Debug, Clone)]
+struct CommandReport {
+    command: String,
+    results: Vec<DeviceResult>,
+}
+
+// One device call of a macro
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct MacroStep {
+    did: String,
+    method: String,
+    params: String,
+    // Milliseconds to wait before the step runs
+    #[serde(default)]
+    delay_ms: u64,
+    // Go on with the next step when this one fails instead of aborting the macro
+    #[serde(default)]
+    continue_on_error: bool,
+}
+
+// Ordered sequence of device calls run as a single command
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CommandMacro {
+    name: String,
+    steps: Vec<MacroStep>,
+    shortcut: Option<String>,
+}
+
+// Struct for the macros JSON file
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct SavedMacros {
+    macros: Vec<CommandMacro>,
+}
+
+// Outcome of one macro step
+#[derive(Serialize, Debug, Clone)]
+struct StepResult {
+    step: usize,
+    did: String,
+    method: String,
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// Report of a macro run, also emitted as `macro-executed`
+#[derive(Serialize, Debug, Clone)]
+struct MacroReport {
+    name: String,
+    results: Vec<StepResult>,
+    // Whether a failing step stopped the macro
+    aborted: bool,
 }
 
 // Struct for the commands JSON file
@@ -44,12 +171,81 @@ struct SavedCommands {
     commands: Vec<SavedCommand>,
 }
 
+// MIoT property read by the poller
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct PolledProperty {
+    siid: u32,
+    piid: u32,
+}
+
+// Properties the poller reads from one device and how often
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct PollTarget {
+    did: String,
+    interval_secs: u64,
+    // Legacy miio properties, read with `get_prop`
+    #[serde(default)]
+    props: Vec<String>,
+    // MIoT properties, read with `get_properties` and keyed as "<siid>.<piid>"
+    #[serde(default)]
+    miot_props: Vec<PolledProperty>,
+}
+
+// Struct for the poller JSON file
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct PollTargets {
+    devices: Vec<PollTarget>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct PropertyChange {
+    property: String,
+    // None on the first poll of a device
+    old: Option<Value>,
+    new: Value,
+}
+
+// Payload of the `device-state-changed` event
+#[derive(Serialize, Debug, Clone)]
+struct DeviceStateChange {
+    did: String,
+    changes: Vec<PropertyChange>,
+}
+
 // Struct for user settings
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct AppSettings {
     close_to_tray: Option<bool>,
     auto_start: Option<bool>,
     auto_hide_to_tray: Option<bool>,
+    // Used by sunrise/sunset schedules that don't follow a device
+    #[serde(default)]
+    location: Option<Location>,
+    #[serde(default)]
+    rest_api: Option<rest_api::RestApiSettings>,
+    #[serde(default)]
+    mqtt: Option<mqtt::MqttSettings>,
+    #[serde(default)]
+    network: Option<NetworkSettings>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+struct Location {
+    latitude: f64,
+    longitude: f64,
+}
+
+// Options of the Mi Cloud HTTP client, unset or empty ones keep the miio defaults
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct NetworkSettings {
+    // e.g. http://127.0.0.1:8080 or socks5://127.0.0.1:1080
+    #[serde(default)]
+    proxy: Option<String>,
+    #[serde(default)]
+    user_agent: Option<String>,
+    // Language of the texts returned by the cloud, e.g. "de"
+    #[serde(default)]
+    locale: Option<String>,
 }
 
 // Get the config file path for storing session credentials
@@ -88,6 +284,61 @@ fn get_commands_path(app_handle: &AppHandle) -> PathBuf {
     app_dir.join("saved_commands.json")
 }
 
+// Get the config file path for storing device groups
+fn get_groups_path(app_handle: &AppHandle) -> PathBuf {
+    let app_dir = app_handle.path().app_data_dir().expect("Failed to get app data dir");
+    
+    if !app_dir.exists() {
+        fs::create_dir_all(&app_dir).expect("Failed to create config directory");
+    }
+    
+    app_dir.join("device_groups.json")
+}
+
+// Get the config file path for storing macros
+fn get_macros_path(app_handle: &AppHandle) -> PathBuf {
+    let app_dir = app_handle.path().app_data_dir().expect("Failed to get app data dir");
+    
+    if !app_dir.exists() {
+        fs::create_dir_all(&app_dir).expect("Failed to create config directory");
+    }
+    
+    app_dir.join("macros.json")
+}
+
+// Get the config file path for storing schedules
+fn get_schedules_path(app_handle: &AppHandle) -> PathBuf {
+    let app_dir = app_handle.path().app_data_dir().expect("Failed to get app data dir");
+    
+    if !app_dir.exists() {
+        fs::create_dir_all(&app_dir).expect("Failed to create config directory");
+    }
+    
+    app_dir.join("schedules.json")
+}
+
+// Get the config file path for storing the polled devices
+fn get_poller_path(app_handle: &AppHandle) -> PathBuf {
+    let app_dir = app_handle.path().app_data_dir().expect("Failed to get app data dir");
+    
+    if !app_dir.exists() {
+        fs::create_dir_all(&app_dir).expect("Failed to create config directory");
+    }
+    
+    app_dir.join("poll_targets.json")
+}
+
+// Get the config file path for storing automation rules
+fn get_rules_path(app_handle: &AppHandle) -> PathBuf {
+    let app_dir = app_handle.path().app_data_dir().expect("Failed to get app data dir");
+    
+    if !app_dir.exists() {
+        fs::create_dir_all(&app_dir).expect("Failed to create config directory");
+    }
+    
+    app_dir.join("rules.json")
+}
+
 // Get the config file path for storing app settings
 fn get_settings_path(app_handle: &AppHandle) -> PathBuf {
     let app_dir = app_handle.path().app_data_dir().expect("Failed to get app data dir");
@@ -99,6 +350,12 @@ fn get_settings_path(app_handle: &AppHandle) -> PathBuf {
     app_dir.join("settings.json")
 }
 
+// Get the offline MIoT spec cache (one <model>.json file per device model)
+fn get_spec_cache(app_handle: &AppHandle) -> SpecCache {
+    let app_dir = app_handle.path().app_data_dir().expect("Failed to get app data dir");
+    SpecCache::new(app_dir.join("specs"))
+}
+
 // Save secure session (without password) to a file
 fn save_secure_session(app_handle: &AppHandle, session: &SecureSession) -> Result<(), String> {
     let path = get_session_path(app_handle);
@@ -107,6 +364,58 @@ fn save_secure_session(app_handle: &AppHandle, session: &SecureSession) -> Resul
     Ok(())
 }
 
+// Keep session.json up to date when miio refreshes an expired service token
+fn persist_refreshed_sessions(app_handle: &AppHandle, protocol: &mut MiCloudProtocol) {
+    let app_handle = app_handle.clone();
+    protocol.on_session_refreshed(move |session| {
+        if let Err(e) = save_secure_session(&app_handle, &session) {
+            eprintln!("Failed to save refreshed session: {}", e);
+        }
+    });
+}
+
+// Build a Mi Cloud client with the network settings
+fn build_protocol(network: &NetworkSettings) -> Result<MiCloudProtocol, MiioError> {
+    let set = |value: &Option<String>| value.clone().filter(|v| !v.trim().is_empty());
+    let mut builder = MiCloudProtocol::builder();
+    if let Some(proxy) = set(&network.proxy) {
+        builder = builder.proxy(proxy);
+    }
+    if let Some(user_agent) = set(&network.user_agent) {
+        builder = builder.user_agent(user_agent);
+    }
+    if let Some(locale) = set(&network.locale) {
+        builder = builder.locale(locale);
+    }
+    builder.build()
+}
+
+// Fresh Mi Cloud client with the saved network settings, or the defaults if they are invalid
+fn new_protocol(app_handle: &AppHandle) -> MiCloudProtocol {
+    let network = load_app_settings(app_handle).network.unwrap_or_default();
+    build_protocol(&network).unwrap_or_else(|e| {
+        eprintln!("Invalid network settings, using defaults: {}", e);
+        MiCloudProtocol::new()
+    })
+}
+
+// Replace the Mi Cloud client with one using the network settings, keeping the session
+async fn apply_network_settings(app_handle: &AppHandle, network: &NetworkSettings) -> Result<(), MiioError> {
+    let mut protocol = build_protocol(network)?;
+    let mut guard = MI_CLOUD_PROTOCOL.lock().await;
+    if let Some(session) = guard.export_secure_session() {
+        protocol.import_secure_session(session);
+        // Only sessions saved to disk are kept up to date there
+        if get_session_path(app_handle).exists() {
+            persist_refreshed_sessions(app_handle, &mut protocol);
+        }
+    }
+    // A login waiting for a captcha or 2FA code continues on the new client
+    guard.move_pending_login_to(&mut protocol);
+    *guard = protocol;
+    Ok(())
+}
+
 // Load secure session from a file
 fn load_secure_session(app_handle: &AppHandle) -> Option<SecureSession> {
     let path = get_session_path(app_handle);
@@ -223,6 +532,91 @@ fn delete_command_from_file(app_handle: &AppHandle, command_name: &str) -> Resul
     Ok(())
 }
 
+// Commands saved before `did` existed ran against the first device of the account;
+// pin them to that device so they keep hitting the same one
+fn migrate_saved_commands(app_handle: &AppHandle, devices: &[Device]) -> Result<(), String> {
+    let mut saved_commands = match load_all_commands(app_handle) {
+        Some(saved_commands) => saved_commands,
+        None => return Ok(()),
+    };
+    let first_device = match devices.first() {
+        Some(device) => device,
+        None => return Ok(()),
+    };
+    if !saved_commands.commands.iter().any(|c| c.is_legacy()) {
+        return Ok(());
+    }
+    
+    for command in saved_commands.commands.iter_mut().filter(|c| c.is_legacy()) {
+        command.did = Some(first_device.did.clone());
+        command.model = Some(first_device.model.clone());
+    }
+    let json = serde_json::to_string_pretty(&saved_commands).map_err(|e| e.to_string())?;
+    fs::write(get_commands_path(app_handle), &json).map_err(|e| e.to_string())?;
+    
+    // Shortcut handlers hold a copy of the command, register them again
+    let _ = app_handle.global_shortcut().unregister_all();
+    register_saved_shortcuts(app_handle);
+    Ok(())
+}
+
+// Load device groups, empty if none were saved yet
+fn load_device_groups(app_handle: &AppHandle) -> DeviceGroups {
+    let path = get_groups_path(app_handle);
+    if !path.exists() {
+        return DeviceGroups::default();
+    }
+    
+    match fs::read_to_string(path) {
+        Ok(json) => serde_json::from_str(&json).unwrap_or_default(),
+        Err(_) => DeviceGroups::default(),
+    }
+}
+
+fn save_device_groups(app_handle: &AppHandle, groups: &DeviceGroups) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(groups).map_err(|e| e.to_string())?;
+    fs::write(get_groups_path(app_handle), &json).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+// Load all macros, empty if none were saved yet
+fn load_all_macros(app_handle: &AppHandle) -> SavedMacros {
+    let path = get_macros_path(app_handle);
+    if !path.exists() {
+        return SavedMacros::default();
+    }
+    
+    match fs::read_to_string(path) {
+        Ok(json) => serde_json::from_str(&json).unwrap_or_default(),
+        Err(_) => SavedMacros::default(),
+    }
+}
+
+fn save_all_macros(app_handle: &AppHandle, macros: &SavedMacros) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(macros).map_err(|e| e.to_string())?;
+    fs::write(get_macros_path(app_handle), &json).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+// Load the polled devices, empty if none were configured yet
+fn load_poll_targets(app_handle: &AppHandle) -> PollTargets {
+    let path = get_poller_path(app_handle);
+    if !path.exists() {
+        return PollTargets::default();
+    }
+    
+    match fs::read_to_string(path) {
+        Ok(json) => serde_json::from_str(&json).unwrap_or_default(),
+        Err(_) => PollTargets::default(),
+    }
+}
+
+fn save_poll_targets(app_handle: &AppHandle, targets: &PollTargets) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(targets).map_err(|e| e.to_string())?;
+    fs::write(get_poller_path(app_handle), &json).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
 // Load all commands from the commands file
 fn load_all_commands(app_handle: &AppHandle) -> Option<SavedCommands> {
     let path = get_commands_path(app_handle);
@@ -247,6 +641,10 @@ fn load_app_settings(app_handle: &AppHandle) -> AppSettings {
             close_to_tray: None,
             auto_start: None,
             auto_hide_to_tray: None,
+            location: None,
+            rest_api: None,
+            mqtt: None,
+            network: None,
         };
     }
     
@@ -257,12 +655,20 @@ fn load_app_settings(app_handle: &AppHandle) -> AppSettings {
                 close_to_tray: None,
                 auto_start: None,
                 auto_hide_to_tray: None,
+                location: None,
+                rest_api: None,
+                mqtt: None,
+                network: None,
             },
         },
         Err(_) => AppSettings { 
             close_to_tray: None,
             auto_start: None,
             auto_hide_to_tray: None,
+            location: None,
+            rest_api: None,
+            mqtt: None,
+            network: None,
         },
     }
 }
@@ -283,7 +689,7 @@ async fn login(
     password: String, 
     country: Option<String>, 
     should_save_credentials: bool
-) -> Result<(), String> {    
+) -> Result<LoginOutcome, String> {    
     // Default country is "cn" if not specified
     let _current_country = country.clone().unwrap_or_else(|| "cn".to_string());
     
@@ -291,25 +697,71 @@ async fn login(
     
     // Set country if provided
     if let Some(c) = &country {
-        guard.set_country(c);
+        guard.set_country(c).map_err(|err| err.to_string())?;
     }
       // Perform login
-    guard
+    let outcome = guard
         .login(email.as_str(), password.as_str())
         .await
         .map_err(|err| err.to_string())?;
       // Save secure session (without password) if requested
-    if should_save_credentials {
+    if outcome == LoginOutcome::LoggedIn && should_save_credentials {
         if let Some(secure_session) = guard.export_secure_session() {
             save_secure_session(&app_handle, &secure_session)?;
         }
+        persist_refreshed_sessions(&app_handle, &mut guard);
     }
     
-    Ok(())
+    Ok(outcome)
+}
+
+#[tauri::command]
+async fn submit_login_captcha(app_handle: AppHandle, code: String, should_save_credentials: bool) -> Result<LoginOutcome, String> {
+    let mut guard = MI_CLOUD_PROTOCOL.lock().await;
+    let outcome = guard
+        .submit_captcha(&code)
+        .await
+        .map_err(|err| err.to_string())?;
+    
+    if outcome == LoginOutcome::LoggedIn && should_save_credentials {
+        if let Some(secure_session) = guard.export_secure_session() {
+            save_secure_session(&app_handle, &secure_session)?;
+        }
+        persist_refreshed_sessions(&app_handle, &mut guard);
+    }
+    
+    Ok(outcome)
+}
+
+#[tauri::command]
+async fn send_login_2fa_code() -> Result<TwoFactorMethod, String> {
+    let mut guard = MI_CLOUD_PROTOCOL.lock().await;
+    guard
+        .send_two_factor_code()
+        .await
+        .map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+async fn verify_login_2fa(app_handle: AppHandle, code: String, should_save_credentials: bool) -> Result<LoginOutcome, String> {
+    let mut guard = MI_CLOUD_PROTOCOL.lock().await;
+    let outcome = guard
+        .verify_two_factor(&code)
+        .await
+        .map_err(|err| err.to_string())?;
+    
+    if outcome == LoginOutcome::LoggedIn && should_save_credentials {
+        if let Some(secure_session) = guard.export_secure_session() {
+            save_secure_session(&app_handle, &secure_session)?;
+        }
+        persist_refreshed_sessions(&app_handle, &mut guard);
+    }
+    
+    Ok(outcome)
 }
 
 #[tauri::command]
-async fn get_countries() -> Vec<Vec<&'static str>> {
+async fn get_countries() -> Vec<Vec<String>> {
     let guard = MI_CLOUD_PROTOCOL.lock().await;
     guard.get_available_countries()
 }
@@ -317,7 +769,7 @@ async fn get_countries() -> Vec<Vec<&'static str>> {
 #[tauri::command]
 async fn set_country(app_handle: AppHandle, country: String) -> Result<(), String> {
     let mut guard = MI_CLOUD_PROTOCOL.lock().await;
-    guard.set_country(&country);
+    guard.set_country(&country).map_err(|err| err.to_string())?;
     
     // Save the updated session with new country
     if let Some(secure_session) = guard.export_secure_session() {
@@ -328,27 +780,166 @@ async fn set_country(app_handle: AppHandle, country: String) -> Result<(), Strin
 }
 
 #[tauri::command]
-async fn get_devices() -> Result<Vec<Device>, ()> {
+async fn get_devices(app_handle: AppHandle) -> Result<Vec<Device>, MiioError> {
+    let guard = MI_CLOUD_PROTOCOL.lock().await;
+    let devices = guard.get_devices(None, None).await?;
+    if let Err(e) = migrate_saved_commands(&app_handle, &devices) {
+        eprintln!("Failed to migrate saved commands: {}", e);
+    }
+    Ok(devices)
+}
+
+// Devices of every server region, tagged with their region; later calls to them go to that region
+#[tauri::command]
+async fn get_devices_all_regions(app_handle: AppHandle) -> Result<Vec<Device>, MiioError> {
+    let guard = MI_CLOUD_PROTOCOL.lock().await;
+    let devices = guard.get_devices_all_regions().await?;
+    if let Err(e) = migrate_saved_commands(&app_handle, &devices) {
+        eprintln!("Failed to migrate saved commands: {}", e);
+    }
+    
+    // The session carries the region of every device, so calls keep being routed after a restart
+    if let Some(secure_session) = guard.export_secure_session() {
+        if let Err(e) = save_secure_session(&app_handle, &secure_session) {
+            eprintln!("Failed to save session: {}", e);
+        }
+    }
+    Ok(devices)
+}
+
+// Devices of the account, or only `dids`. Once devices were listed from all regions,
+// lists are merged from all regions as well so devices outside the selected one aren't lost.
+async fn list_devices(guard: &MiCloudProtocol, dids: Option<&[String]>) -> Result<Vec<Device>, MiioError> {
+    if guard.uses_all_regions() {
+        let devices = guard.get_devices_all_regions().await?;
+        return Ok(match dids {
+            Some(dids) => devices.into_iter().filter(|d| dids.contains(&d.did)).collect(),
+            None => devices,
+        });
+    }
+    let dids: Option<Vec<&str>> = dids.map(|dids| dids.iter().map(String::as_str).collect());
+    guard.get_devices(dids.as_deref(), None).await
+}
+
+#[tauri::command]
+async fn get_device(did: String) -> Result<Vec<Device>, MiioError> {
+    let guard = MI_CLOUD_PROTOCOL.lock().await;
+    guard.get_device(&did, None).await
+}
+
+#[tauri::command]
+async fn get_homes() -> Result<Vec<Home>, MiioError> {
+    let guard = MI_CLOUD_PROTOCOL.lock().await;
+    guard.get_homes(None).await
+}
+
+// Devices grouped by home and room, for accounts with several homes
+#[tauri::command]
+async fn get_devices_by_home() -> Result<DevicesByHome, MiioError> {
     let guard = MI_CLOUD_PROTOCOL.lock().await;
-    guard.get_devices(None, None).await.map_err(|_| ())
+    let homes = guard.get_homes(None).await?;
+    let devices = list_devices(&guard, None).await?;
+    Ok(miio::group_by_home(&homes, devices))
+}
+
+// Export name, model, ip, token etc. of the devices in a home and/or region (default: the current one)
+#[tauri::command]
+async fn export_devices(app_handle: AppHandle, format: ExportFormat, path: String, home: Option<String>, region: Option<String>) -> Result<usize, MiioError> {
+    let guard = MI_CLOUD_PROTOCOL.lock().await;
+    let country = region.as_deref();
+    let devices = match country {
+        Some(country) => guard.get_devices(None, Some(country)).await?,
+        None => list_devices(&guard, None).await?,
+    };
+    let filter = DeviceFilter { home, ..Default::default() };
+    let homes = if filter.needs_homes() {
+        guard.get_homes(country).await?
+    } else {
+        vec![]
+    };
+    let devices = filter.select(&homes, devices);
+    
+    // Bluetooth devices have a beacon key instead of a token; a failed lookup is noted in the export
+    let protocol = &*guard;
+    let ble_keys = join_all(devices.iter().map(|device| async move {
+        if device.is_bluetooth() {
+            let ble_key = protocol.get_beacon_key(&device.did, country).await;
+            if let Err(e) = &ble_key {
+                eprintln!("{}", e);
+            }
+            Some(ble_key)
+        } else {
+            None
+        }
+    }))
+    .await;
+    let credentials: Vec<DeviceCredentials> = devices
+        .iter()
+        .zip(ble_keys)
+        .map(|(device, ble_key)| DeviceCredentials::new(device, ble_key))
+        .collect();
+    let content = miio::export_credentials(&credentials, format)?;
+    
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    app_handle
+        .fs()
+        .open(PathBuf::from(&path), options)
+        .and_then(|mut file| file.write_all(content.as_bytes()))
+        .map_err(|e| MiioError::Io(format!("Failed to write {}: {}", path, e)))?;
+    Ok(credentials.len())
 }
 
+// Scenes and automations of every home
 #[tauri::command]
-async fn get_device(did: String) -> Result<Vec<Device>, ()> {
+async fn get_scenes() -> Result<Vec<Scene>, MiioError> {
     let guard = MI_CLOUD_PROTOCOL.lock().await;
-    guard.get_device(&did, None).await.map_err(|_| ())
+    let mut scenes = vec![];
+    for home in guard.get_homes(None).await? {
+        scenes.extend(guard.get_scenes(&home, None).await?);
+    }
+    Ok(scenes)
 }
 
 #[tauri::command]
-async fn call_device(did: String, method: String, params: Option<String>) -> Result<Value, String> {
+async fn run_scene(scene_id: String) -> Result<(), MiioError> {
+    let guard = MI_CLOUD_PROTOCOL.lock().await;
+    guard.run_scene(&scene_id, None).await
+}
+
+#[tauri::command]
+async fn call_device(did: String, method: String, params: Option<String>) -> Result<Value, MiioError> {
     let guard = MI_CLOUD_PROTOCOL.lock().await;
     let params = params
-        .map(|params| serde_json::from_str::<Value>(params.as_str()).map_err(|err| err.to_string()))
+        .map(|params| {
+            serde_json::from_str::<Value>(params.as_str())
+                .map_err(|err| MiioError::Invalid(format!("Invalid params: {}", err)))
+        })
         .transpose()?;
-    guard
-        .call_device(&did, &method, params, None)
-        .await
-        .map_err(|err| err.to_string())
+    guard.call_device(&did, &method, params, None).await
+}
+
+#[tauri::command]
+async fn get_properties(props: Vec<PropertyRequest>) -> Result<Vec<PropertyResult>, MiioError> {
+    let guard = MI_CLOUD_PROTOCOL.lock().await;
+    guard.get_properties(&props, None).await
+}
+
+#[tauri::command]
+async fn set_properties(props: Vec<SetPropertyRequest>) -> Result<Vec<PropertyResult>, MiioError> {
+    let guard = MI_CLOUD_PROTOCOL.lock().await;
+    guard.set_properties(&props, None).await
+}
+
+#[tauri::command]
+async fn miot_action(action: ActionRequest) -> Result<ActionResult, MiioError> {
+    let guard = MI_CLOUD_PROTOCOL.lock().await;
+    guard.action(&action, None).await
+}
+
+#[tauri::command]
+async fn get_device_spec(app_handle: AppHandle, model: String) -> Result<Option<DeviceSpec>, MiioError> {
+    get_spec_cache(&app_handle).load(&model)
 }
 
 #[tauri::command]
@@ -366,14 +957,24 @@ async fn try_auto_login(app_handle: AppHandle) -> Result<bool, String> {
         
         // Import the secure session
         guard.import_secure_session(secure_session);
+        persist_refreshed_sessions(&app_handle, &mut guard);
         
         // Verify the session is still valid by checking session validity
         let is_session_valid = guard.is_session_valid();
         
         if is_session_valid {
-            // Test the session by trying to get devices to ensure it's actually valid
-            match guard.get_devices(None, None).await {
-                Ok(_) => {
+            // Test the session by trying to get devices; an expired service token
+            // is refreshed with the stored passToken before giving up
+            match list_devices(&guard, None).await {
+                Ok(devices) => {
+                    // Runs at startup also when hidden in the tray, so legacy shortcuts work right away
+                    if let Err(e) = migrate_saved_commands(&app_handle, &devices) {
+                        eprintln!("Failed to migrate saved commands: {}", e);
+                    }
+                    return Ok(true);
+                }
+                // Network trouble does not mean the session is gone
+                Err(err) if !err.is_auth_expired() => {
                     return Ok(true);
                 }
                 Err(_) => {
@@ -397,11 +998,15 @@ async fn try_auto_login(app_handle: AppHandle) -> Result<bool, String> {
         // Test the session validity
         if guard.is_logged_in() {
             match guard.get_devices(None, None).await {
-                Ok(_) => {
+                Ok(devices) => {
+                    if let Err(e) = migrate_saved_commands(&app_handle, &devices) {
+                        eprintln!("Failed to migrate saved commands: {}", e);
+                    }
                     // Migrate to secure session format
                     if let Some(secure_session) = guard.export_secure_session() {
                         save_secure_session(&app_handle, &secure_session)?;
                     }
+                    persist_refreshed_sessions(&app_handle, &mut guard);
                     
                     return Ok(true);
                 }
@@ -422,8 +1027,8 @@ async fn try_auto_login(app_handle: AppHandle) -> Result<bool, String> {
 
 #[tauri::command]
 async fn logout(app_handle: AppHandle) -> Result<(), String> {
-    // Reset the protocol
-    *MI_CLOUD_PROTOCOL.lock().await = MiCloudProtocol::new();
+    // Reset the protocol, keeping the network settings
+    *MI_CLOUD_PROTOCOL.lock().await = new_protocol(&app_handle);
     
     // Remove stored session
     let session_path = get_session_path(&app_handle);
@@ -469,9 +1074,10 @@ async fn is_session_restored() -> bool {
 }
 
 #[tauri::command]
-async fn save_command(app_handle: AppHandle, name: String, method: String, params: String, shortcut: Option<String>) -> Result<(), String> {
-    let command = SavedCommand { name: name.clone(), method, params, shortcut: shortcut.clone() };
+async fn save_command(app_handle: AppHandle, name: String, method: String, params: String, shortcut: Option<String>, scene_id: Option<String>, did: Option<String>, model: Option<String>, target: Option<CommandTarget>) -> Result<(), String> {
+    let command = SavedCommand { name: name.clone(), method, params, shortcut: shortcut.clone(), scene_id, did, model, target };
     save_command_to_file(&app_handle, &command, false)?;
+    refresh_tray_menu(&app_handle);
     
     // Register global shortcut if provided
     if let Some(sc) = shortcut {
@@ -485,13 +1091,14 @@ async fn save_command(app_handle: AppHandle, name: String, method: String, param
                 let shortcut: ShortcutWrapper = shortcut_str_parsed;
                 
                 // Register the shortcut with a handler function
-                match app_handle.global_shortcut().on_shortcut(shortcut, move |_app_handle, _shortcut, _event| {
+                match app_handle.global_shortcut().on_shortcut(shortcut, move |app_handle, _shortcut, _event| {
                     let cmd_clone2 = cmd_clone.clone();
+                    let app_handle = app_handle.clone();
                     
                     // Use std::thread::spawn + block_on for proper runtime context
                     std::thread::spawn(move || {
                         tauri::async_runtime::block_on(async move {
-                            if let Err(e) = execute_saved_command(&cmd_clone2).await {
+                            if let Err(e) = execute_saved_command(&app_handle, &cmd_clone2).await {
                                 eprintln!("Error executing command: {}", e);
                             }
                         });
@@ -515,7 +1122,7 @@ async fn save_command(app_handle: AppHandle, name: String, method: String, param
 }
 
 #[tauri::command]
-async fn update_command(app_handle: AppHandle, name: String, method: String, params: String, shortcut: Option<String>) -> Result<(), String> {
+async fn update_command(app_handle: AppHandle, name: String, method: String, params: String, shortcut: Option<String>, scene_id: Option<String>, did: Option<String>, model: Option<String>, target: Option<CommandTarget>) -> Result<(), String> {
     // First, get the old command to unregister its shortcut if needed
     let old_commands = load_all_commands(&app_handle).unwrap_or(SavedCommands { commands: vec![] });
     let old_command = old_commands.commands.iter().find(|c| c.name == name);
@@ -532,8 +1139,11 @@ async fn update_command(app_handle: AppHandle, name: String, method: String, par
     }
   
     // Create and save the updated command
-    let command = SavedCommand { name: name.clone(), method, params, shortcut: shortcut.clone() };
-    save_command_to_file(&app_handle, &command, true)?;    // Register the new shortcut if provided
+    let command = SavedCommand { name: name.clone(), method, params, shortcut: shortcut.clone(), scene_id, did, model, target };
+    save_command_to_file(&app_handle, &command, true)?;
+    refresh_tray_menu(&app_handle);
+    
+    // Register the new shortcut if provided
     if let Some(sc) = shortcut {
         let sc_clone = sc.clone();
         let cmd_clone = command.clone();
@@ -546,14 +1156,15 @@ async fn update_command(app_handle: AppHandle, name: String, method: String, par
                 let shortcut: ShortcutWrapper = shortcut_str_parsed;
                 
                 // Register the shortcut with a handler function
-                match app_handle.global_shortcut().on_shortcut(shortcut, move |_app_handle, _shortcut, _event| {
+                match app_handle.global_shortcut().on_shortcut(shortcut, move |app_handle, _shortcut, _event| {
                     println!("Shortcut triggered: {}", sc_clone);
                     let cmd_clone2 = cmd_clone.clone();
+                    let app_handle = app_handle.clone();
                     
                     // Use std::thread::spawn + block_on for proper runtime context
                     std::thread::spawn(move || {
                         tauri::async_runtime::block_on(async move {
-                            if let Err(e) = execute_saved_command(&cmd_clone2).await {
+                            if let Err(e) = execute_saved_command(&app_handle, &cmd_clone2).await {
                                 eprintln!("Error executing command: {}", e);
                             }
                         });
@@ -597,7 +1208,9 @@ async fn delete_command(app_handle: AppHandle, name: String) -> Result<(), Strin
     }
     
     // Delete the command from the file
-    delete_command_from_file(&app_handle, &name)
+    delete_command_from_file(&app_handle, &name)?;
+    refresh_tray_menu(&app_handle);
+    Ok(())
 }
 
 #[tauri::command]
@@ -607,6 +1220,150 @@ async fn get_saved_commands(app_handle: AppHandle) -> Vec<SavedCommand> {
         .unwrap_or_default()
 }
 
+#[tauri::command]
+async fn run_saved_command(app_handle: AppHandle, name: String) -> Result<CommandReport, String> {
+    let command = load_all_commands(&app_handle)
+        .and_then(|commands| commands.commands.into_iter().find(|c| c.name == name))
+        .ok_or_else(|| format!("Command with name '{}' not found", name))?;
+    execute_saved_command(&app_handle, &command).await
+}
+
+#[tauri::command]
+async fn get_macros(app_handle: AppHandle) -> Vec<CommandMacro> {
+    load_all_macros(&app_handle).macros
+}
+
+// Create a macro or replace the one with the same name
+#[tauri::command]
+async fn save_macro(app_handle: AppHandle, command_macro: CommandMacro) -> Result<(), String> {
+    if command_macro.steps.is_empty() {
+        return Err(format!("Macro '{}' has no steps", command_macro.name));
+    }
+    for (index, step) in command_macro.steps.iter().enumerate() {
+        parse_params(&step.params).map_err(|e| format!("Step {}: {}", index + 1, e))?;
+    }
+    
+    let mut saved_macros = load_all_macros(&app_handle);
+    match saved_macros.macros.iter().position(|m| m.name == command_macro.name) {
+        Some(index) => {
+            // Unregister the old shortcut, the new one is registered below
+            if let Some(old_sc) = &saved_macros.macros[index].shortcut {
+                if let Ok(shortcut_str_parsed) = old_sc.as_str().try_into() {
+                    let shortcut: ShortcutWrapper = shortcut_str_parsed;
+                    let _ = app_handle.global_shortcut().unregister(shortcut);
+                }
+            }
+            saved_macros.macros[index] = command_macro.clone();
+        }
+        None => saved_macros.macros.push(command_macro.clone()),
+    }
+    save_all_macros(&app_handle, &saved_macros)?;
+    
+    refresh_tray_menu(&app_handle);
+    register_macro_shortcut(&app_handle, &command_macro)
+}
+
+#[tauri::command]
+async fn delete_macro(app_handle: AppHandle, name: String) -> Result<(), String> {
+    let mut saved_macros = load_all_macros(&app_handle);
+    let index = saved_macros.macros
+        .iter()
+        .position(|m| m.name == name)
+        .ok_or_else(|| format!("Macro '{}' not found", name))?;
+    
+    let removed = saved_macros.macros.remove(index);
+    if let Some(sc) = &removed.shortcut {
+        if let Ok(shortcut_str_parsed) = sc.as_str().try_into() {
+            let shortcut: ShortcutWrapper = shortcut_str_parsed;
+            let _ = app_handle.global_shortcut().unregister(shortcut);
+        }
+    }
+    save_all_macros(&app_handle, &saved_macros)?;
+    
+    refresh_tray_menu(&app_handle);
+    Ok(())
+}
+
+#[tauri::command]
+async fn run_macro(app_handle: AppHandle, name: String) -> Result<MacroReport, String> {
+    let command_macro = load_all_macros(&app_handle)
+        .macros
+        .into_iter()
+        .find(|m| m.name == name)
+        .ok_or_else(|| format!("Macro '{}' not found", name))?;
+    execute_macro(&app_handle, &command_macro).await
+}
+
+#[tauri::command]
+async fn get_poll_targets() -> Vec<PollTarget> {
+    POLL_TARGETS.lock().unwrap().clone()
+}
+
+// Start polling a device or change what and how often it is polled
+#[tauri::command]
+async fn save_poll_target(app_handle: AppHandle, target: PollTarget) -> Result<(), String> {
+    if target.interval_secs < MIN_POLL_INTERVAL_SECS {
+        return Err(format!("Poll interval must be at least {} seconds", MIN_POLL_INTERVAL_SECS));
+    }
+    if target.props.is_empty() && target.miot_props.is_empty() {
+        return Err(format!("No properties to poll for device {}", target.did));
+    }
+    
+    let mut targets = POLL_TARGETS.lock().unwrap();
+    match targets.iter().position(|t| t.did == target.did) {
+        Some(index) => targets[index] = target,
+        None => targets.push(target),
+    }
+    save_poll_targets(&app_handle, &PollTargets { devices: targets.clone() })
+}
+
+#[tauri::command]
+async fn remove_poll_target(app_handle: AppHandle, did: String) -> Result<(), String> {
+    let mut targets = POLL_TARGETS.lock().unwrap();
+    let original_len = targets.len();
+    targets.retain(|t| t.did != did);
+    
+    if targets.len() == original_len {
+        return Err(format!("Device {} is not polled", did));
+    }
+    DEVICE_STATE.lock().unwrap().remove(&did);
+    save_poll_targets(&app_handle, &PollTargets { devices: targets.clone() })
+}
+
+// Last polled values of a device
+#[tauri::command]
+async fn get_device_state(did: String) -> HashMap<String, Value> {
+    DEVICE_STATE.lock().unwrap().get(&did).cloned().unwrap_or_default()
+}
+
+#[tauri::command]
+async fn get_device_groups(app_handle: AppHandle) -> Vec<DeviceGroup> {
+    load_device_groups(&app_handle).groups
+}
+
+// Create a device group or replace the devices of an existing one
+#[tauri::command]
+async fn save_device_group(app_handle: AppHandle, name: String, dids: Vec<String>) -> Result<(), String> {
+    let mut device_groups = load_device_groups(&app_handle);
+    match device_groups.groups.iter_mut().find(|g| g.name == name) {
+        Some(group) => group.dids = dids,
+        None => device_groups.groups.push(DeviceGroup { name, dids }),
+    }
+    save_device_groups(&app_handle, &device_groups)
+}
+
+#[tauri::command]
+async fn delete_device_group(app_handle: AppHandle, name: String) -> Result<(), String> {
+    let mut device_groups = load_device_groups(&app_handle);
+    let original_len = device_groups.groups.len();
+    device_groups.groups.retain(|g| g.name != name);
+    
+    if device_groups.groups.len() == original_len {
+        return Err(format!("Device group '{}' not found", name));
+    }
+    save_device_groups(&app_handle, &device_groups)
+}
+
 #[tauri::command]
 async fn validate_shortcut(app_handle: AppHandle, shortcut: String) -> Result<bool, String> {
     // Validate shortcut format and check for conflicts
@@ -620,6 +1377,8 @@ async fn validate_shortcut(app_handle: AppHandle, shortcut: String) -> Result<bo
     // Check if shortcut is already in use
     let is_duplicate = saved_commands.commands.iter().any(|cmd| {
         cmd.shortcut.as_ref().map_or(false, |s| s == &shortcut)
+    }) || load_all_macros(&app_handle).macros.iter().any(|m| {
+        m.shortcut.as_ref().map_or(false, |s| s == &shortcut)
     });
     
     if is_duplicate {
@@ -696,35 +1455,364 @@ async fn save_all_settings(app_handle: AppHandle, close_to_tray: Option<bool>, a
     save_app_settings(&app_handle, &settings)
 }
 
-// Execute a saved command from a shortcut
-async fn execute_saved_command(command: &SavedCommand) -> Result<(), String> {
-    // Get the current user and check if logged in
+// Save proxy, user agent and locale, applied to the Mi Cloud client right away
+#[tauri::command]
+async fn save_network_settings(app_handle: AppHandle, network: NetworkSettings) -> Result<(), String> {
+    apply_network_settings(&app_handle, &network).await.map_err(|e| e.to_string())?;
+    let mut settings = load_app_settings(&app_handle);
+    settings.network = Some(network);
+    save_app_settings(&app_handle, &settings)
+}
+
+#[tauri::command]
+async fn save_location(app_handle: AppHandle, location: Option<Location>) -> Result<(), String> {
+    if let Some(location) = &location {
+        if !(-90.0..=90.0).contains(&location.latitude) || !(-180.0..=180.0).contains(&location.longitude) {
+            return Err("Invalid location".to_string());
+        }
+    }
+    let mut settings = load_app_settings(&app_handle);
+    settings.location = location;
+    save_app_settings(&app_handle, &settings)
+}
+
+// Parse the JSON params of a saved command or macro step, empty means no params
+fn parse_params(params: &str) -> Result<Option<Value>, String> {
+    match params.trim() {
+        "" => Ok(None),
+        params => serde_json::from_str::<Value>(params)
+            .map(Some)
+            .map_err(|err| format!("Invalid params: {}", err)),
+    }
+}
+
+// Resolve the devices a saved command runs on. Group members that no longer exist
+// are returned as failed results instead of failing the whole command.
+async fn resolve_command_devices(app_handle: &AppHandle, guard: &MiCloudProtocol, command: &SavedCommand) -> Result<(Vec<Device>, Vec<DeviceResult>), String> {
+    match &command.target {
+        Some(CommandTarget::Group { name }) => {
+            let group = load_device_groups(app_handle)
+                .groups
+                .into_iter()
+                .find(|g| &g.name == name)
+                .ok_or_else(|| format!("Device group '{}' not found", name))?;
+            let devices = list_devices(guard, Some(&group.dids)).await.map_err(|e| e.to_string())?;
+            let missing = group.dids
+                .iter()
+                .filter(|did| !devices.iter().any(|d| &d.did == *did))
+                .map(|did| DeviceResult {
+                    did: did.clone(),
+                    name: did.clone(),
+                    success: false,
+                    result: None,
+                    error: Some(format!("Device {} no longer exists", did)),
+                })
+                .collect();
+            Ok((devices, missing))
+        }
+        Some(CommandTarget::Filter(filter)) => {
+            let homes = if filter.needs_homes() {
+                guard.get_homes(None).await.map_err(|e| e.to_string())?
+            } else {
+                vec![]
+            };
+            let devices = list_devices(guard, None).await.map_err(|e| e.to_string())?;
+            Ok((filter.select(&homes, devices), vec![]))
+        }
+        // Not migrated yet: legacy commands ran on the first device
+        None if command.is_legacy() => {
+            let devices = list_devices(guard, None).await.map_err(|e| e.to_string())?;
+            if let Err(e) = migrate_saved_commands(app_handle, &devices) {
+                eprintln!("Failed to migrate saved commands: {}", e);
+            }
+            let device = devices.into_iter().next().ok_or("No devices found")?;
+            Ok((vec![device], vec![]))
+        }
+        None => {
+            let did = command.did.clone().ok_or("No target device, save it again from the device")?;
+            
+            // Make sure the target device still exists and is what the command was saved for
+            let device = guard.get_device(&did, None)
+                .await
+                .map_err(|e| format!("Failed to get device {}: {}", did, e))?
+                .into_iter()
+                .next()
+                .ok_or_else(|| format!("Device {} no longer exists", did))?;
+            if let Some(model) = &command.model {
+                if &device.model != model {
+                    return Err(format!("Device {} is a {}, but the command was saved for {}", did, device.model, model));
+                }
+            }
+            Ok((vec![device], vec![]))
+        }
+    }
+}
+
+// Execute a saved command on all of its target devices at once and emit the
+// per-device report as `command-executed`
+async fn execute_saved_command(app_handle: &AppHandle, command: &SavedCommand) -> Result<CommandReport, String> {
     let guard = MI_CLOUD_PROTOCOL.lock().await;
     if !guard.is_session_valid() {
-        drop(guard);
         return Err(format!("Cannot execute command '{}': Not logged in", command.name));
     }
+    
+    let mut report = CommandReport { command: command.name.clone(), results: vec![] };
+    if let Some(scene_id) = &command.scene_id {
+        guard.run_scene(scene_id, None)
+            .await
+            .map_err(|err| format!("Cannot execute command '{}': {}", command.name, err))?;
+        let _ = app_handle.emit("command-executed", &report);
+        return Ok(report);
+    }
+    
+    let (devices, missing) = resolve_command_devices(app_handle, &guard, command)
+        .await
+        .map_err(|err| format!("Cannot execute command '{}': {}", command.name, err))?;
+    if devices.is_empty() && missing.is_empty() {
+        return Err(format!("Cannot execute command '{}': No devices match the target", command.name));
+    }
+    let params = parse_params(&command.params)
+        .map_err(|err| format!("Cannot execute command '{}': {}", command.name, err))?;
+    
+    // Call every device concurrently
+    let calls = devices
+        .iter()
+        .map(|device| guard.call_device(&device.did, &command.method, params.clone(), None));
+    let results = join_all(calls).await;
     drop(guard);
     
-    // Get the first available device for execution
-    let devices = match get_devices().await {
-        Ok(devices) => devices,
-        Err(_) => {
-            return Err(format!("Cannot execute command '{}': Failed to get devices", command.name));
+    report.results = devices
+        .into_iter()
+        .zip(results)
+        .map(|(device, result)| match result {
+            Ok(value) => DeviceResult { did: device.did, name: device.name, success: true, result: Some(value), error: None },
+            Err(err) => DeviceResult { did: device.did, name: device.name, success: false, result: None, error: Some(err.to_string()) },
+        })
+        .chain(missing)
+        .collect();
+    
+    let _ = app_handle.emit("command-executed", &report);
+    Ok(report)
+}
+
+// Run the steps of a macro in order. The cloud client is only locked for the calls
+// themselves, so other commands can run while a step waits for its delay.
+async fn execute_macro(app_handle: &AppHandle, command_macro: &CommandMacro) -> Result<MacroReport, String> {
+    if !MI_CLOUD_PROTOCOL.lock().await.is_session_valid() {
+        return Err(format!("Cannot execute macro '{}': Not logged in", command_macro.name));
+    }
+    
+    let mut report = MacroReport { name: command_macro.name.clone(), results: vec![], aborted: false };
+    for (index, step) in command_macro.steps.iter().enumerate() {
+        if step.delay_ms > 0 {
+            tokio::time::sleep(Duration::from_millis(step.delay_ms)).await;
+        }
+        
+        let result = match parse_params(&step.params) {
+            Ok(params) => {
+                let guard = MI_CLOUD_PROTOCOL.lock().await;
+                guard.call_device(&step.did, &step.method, params, None)
+                    .await
+                    .map_err(|err| err.to_string())
+            }
+            Err(err) => Err(err),
+        };
+        let failed = result.is_err();
+        report.results.push(StepResult {
+            step: index,
+            did: step.did.clone(),
+            method: step.method.clone(),
+            success: !failed,
+            result: result.as_ref().ok().cloned(),
+            error: result.err(),
+        });
+        
+        if failed && !step.continue_on_error {
+            report.aborted = true;
+            break;
         }
+    }
+    
+    let _ = app_handle.emit("macro-executed", &report);
+    Ok(report)
+}
+
+// Register the global shortcut of a macro, if it has one
+fn register_macro_shortcut(app_handle: &AppHandle, command_macro: &CommandMacro) -> Result<(), String> {
+    let sc = match &command_macro.shortcut {
+        Some(sc) => sc,
+        None => return Ok(()),
     };
+    let shortcut: ShortcutWrapper = sc
+        .as_str()
+        .try_into()
+        .map_err(|e| format!("Invalid shortcut format '{}': {:?}", sc, e))?;
+    let macro_clone = command_macro.clone();
+    
+    app_handle.global_shortcut().on_shortcut(shortcut, move |app_handle, _shortcut, event| {
+        // Only run once per key press, not again on release
+        if event.state() != ShortcutState::Pressed {
+            return;
+        }
+        let macro_clone2 = macro_clone.clone();
+        let app_handle = app_handle.clone();
+        
+        std::thread::spawn(move || {
+            tauri::async_runtime::block_on(async move {
+                if let Err(e) = execute_macro(&app_handle, &macro_clone2).await {
+                    eprintln!("Error executing macro: {}", e);
+                }
+            });
+        });
+    })
+    .map_err(|e| format!("Failed to register shortcut '{}': {}", sc, e))
+}
+
+// Build the tray menu, with an entry for every saved command and macro
+fn build_tray_menu(app_handle: &AppHandle) -> tauri::Result<Menu<tauri::Wry>> {
+    let commands = load_all_commands(app_handle).map(|c| c.commands).unwrap_or_default();
+    let macros = load_all_macros(app_handle).macros;
+    
+    let mut builder = MenuBuilder::new(app_handle).text("open", "Open");
+    if !commands.is_empty() || !macros.is_empty() {
+        builder = builder.separator();
+        for command in &commands {
+            builder = builder.text(format!("command:{}", command.name), &command.name);
+        }
+        for command_macro in &macros {
+            builder = builder.text(format!("macro:{}", command_macro.name), format!("{} (macro)", command_macro.name));
+        }
+        builder = builder.separator();
+    }
+    builder.text("close", "Close").build()
+}
+
+// Rebuild the tray menu after commands or macros changed
+fn refresh_tray_menu(app_handle: &AppHandle) {
+    if let Some(tray) = app_handle.tray_by_id("main") {
+        match build_tray_menu(app_handle) {
+            Ok(menu) => {
+                let _ = tray.set_menu(Some(menu));
+            }
+            Err(e) => eprintln!("Failed to rebuild tray menu: {}", e),
+        }
+    }
+}
+
+// Run a saved command or macro picked from the tray menu
+fn run_from_tray(app_handle: &AppHandle, menu_id: &str) {
+    let app_handle = app_handle.clone();
+    let menu_id = menu_id.to_string();
+    
+    std::thread::spawn(move || {
+        tauri::async_runtime::block_on(async move {
+            let result = if let Some(name) = menu_id.strip_prefix("command:") {
+                run_saved_command(app_handle, name.to_string()).await.map(|_| ())
+            } else if let Some(name) = menu_id.strip_prefix("macro:") {
+                run_macro(app_handle, name.to_string()).await.map(|_| ())
+            } else {
+                Ok(())
+            };
+            if let Err(e) = result {
+                eprintln!("Error executing '{}' from tray: {}", menu_id, e);
+            }
+        });
+    });
+}
+
+// Read the configured properties of a device, keyed by property name or "<siid>.<piid>"
+async fn read_polled_properties(target: &PollTarget) -> Result<HashMap<String, Value>, MiioError> {
+    let guard = MI_CLOUD_PROTOCOL.lock().await;
+    if !guard.is_session_valid() {
+        return Err(MiioError::NotLoggedIn);
+    }
     
-    if devices.is_empty() {
-        return Err(format!("Cannot execute command '{}': No devices available", command.name));
+    let mut values = HashMap::new();
+    if !target.props.is_empty() {
+        let result = guard.call_device(&target.did, "get_prop", Some(serde_json::json!(target.props)), None).await?;
+        // Values come back in the order they were asked for
+        if let Some(list) = result.as_array() {
+            for (name, value) in target.props.iter().zip(list) {
+                values.insert(name.clone(), value.clone());
+            }
+        }
     }
+    if !target.miot_props.is_empty() {
+        let requests: Vec<PropertyRequest> = target.miot_props
+            .iter()
+            .map(|p| PropertyRequest { did: target.did.clone(), siid: p.siid, piid: p.piid })
+            .collect();
+        for result in guard.get_properties(&requests, None).await? {
+            if let (0, Some(value)) = (result.code, result.value) {
+                values.insert(format!("{}.{}", result.siid, result.piid), value);
+            }
+        }
+    }
+    Ok(values)
+}
+
+// Poll one device and emit `device-state-changed` with every value that differs from the last poll
+async fn poll_device(app_handle: AppHandle, target: PollTarget) {
+    let values = match read_polled_properties(&target).await {
+        Ok(values) => values,
+        // Polling resumes once logged in
+        Err(MiioError::NotLoggedIn) => return,
+        Err(e) => {
+            eprintln!("Polling device {} failed: {}", target.did, e);
+            return;
+        }
+    };
     
-    // Use the first device
-    let device = &devices[0];
-    let did = device.did.to_string();
+    let changes: Vec<PropertyChange> = {
+        let mut state = DEVICE_STATE.lock().unwrap();
+        let snapshot = state.entry(target.did.clone()).or_default();
+        values
+            .into_iter()
+            .filter_map(|(property, new)| {
+                let old = snapshot.insert(property.clone(), new.clone());
+                (old.as_ref() != Some(&new)).then_some(PropertyChange { property, old, new })
+            })
+            .collect()
+    };
     
-    // Execute the command
-    call_device(did, command.method.clone(), Some(command.params.clone())).await?;
-    Ok(())
+    if !changes.is_empty() {
+        rules::on_state_change(&app_handle, &target.did, &changes);
+        mqtt::publish_state(&target.did, &changes);
+        let _ = app_handle.emit("device-state-changed", &DeviceStateChange { did: target.did, changes });
+    }
+}
+
+// Poller loop, spawned once from `setup`. Each device is polled on its own interval,
+// in the background so a slow device doesn't delay the others.
+async fn run_poller(app_handle: AppHandle) {
+    let mut next_poll: HashMap<String, Instant> = HashMap::new();
+    let mut ticker = tokio::time::interval(Duration::from_secs(1));
+    
+    loop {
+        ticker.tick().await;
+        let now = Instant::now();
+        let due: Vec<PollTarget> = POLL_TARGETS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|t| !next_poll.get(&t.did).is_some_and(|next| *next > now))
+            .cloned()
+            .collect();
+        
+        for target in due {
+            // A device that answers slower than its interval is skipped until its poll finished
+            if !POLLS_IN_FLIGHT.lock().unwrap().insert(target.did.clone()) {
+                continue;
+            }
+            next_poll.insert(target.did.clone(), now + Duration::from_secs(target.interval_secs));
+            let app_handle = app_handle.clone();
+            tauri::async_runtime::spawn(async move {
+                let did = target.did.clone();
+                poll_device(app_handle, target).await;
+                POLLS_IN_FLIGHT.lock().unwrap().remove(&did);
+            });
+        }
+    }
 }
 
 // Helper function to register all saved command shortcuts
@@ -745,14 +1833,8 @@ fn register_saved_shortcuts(app_handle: &AppHandle) {
                             
                             std::thread::spawn(move || {
                                 tauri::async_runtime::block_on(async move {
-                                    match execute_saved_command(&command_clone2).await {
-                                        Ok(_) => {
-                                            // Emit an event to the frontend
-                                            let _ = app_handle_clone2.emit("command-executed", &command_clone2);
-                                        },
-                                        Err(e) => {
-                                            eprintln!("Error executing command: {}", e);
-                                        }
+                                    if let Err(e) = execute_saved_command(&app_handle_clone2, &command_clone2).await {
+                                        eprintln!("Error executing command: {}", e);
                                     }
                                 });
                             });
@@ -766,6 +1848,12 @@ fn register_saved_shortcuts(app_handle: &AppHandle) {
             }
         }
     }
+    
+    for command_macro in load_all_macros(app_handle).macros {
+        if let Err(e) = register_macro_shortcut(app_handle, &command_macro) {
+            eprintln!("{}", e);
+        }
+    }
 }
 
 fn main() {
@@ -796,20 +1884,45 @@ fn main() {
                 }
             }
             
+            // Configure the Mi Cloud client before anything talks to the cloud
+            if let Some(network) = &settings.network {
+                if let Err(e) = tauri::async_runtime::block_on(apply_network_settings(&app_handle, network)) {
+                    eprintln!("Ignoring network settings: {}", e);
+                }
+            }
+            
             // Register saved command shortcuts
-            register_saved_shortcuts(&app_handle);            
+            register_saved_shortcuts(&app_handle);
+            
+            // Run scheduled commands in the background, also while hidden in the tray
+            tauri::async_runtime::spawn(scheduler::run(app_handle.clone()));
+            
+            // Serve the local REST API if it was turned on
+            if let Some(api_settings) = settings.rest_api.clone().filter(|r| r.enabled) {
+                let app_handle_clone = app_handle.clone();
+                tauri::async_runtime::spawn(async move {
+                    if let Err(e) = rest_api::start(app_handle_clone, &api_settings).await {
+                        eprintln!("{}", e);
+                    }
+                });
+            }
+            
+            // Bridge device state and commands to the MQTT broker if it was turned on
+            if let Some(mqtt_settings) = settings.mqtt.clone().filter(|m| m.enabled) {
+                mqtt::start(app_handle.clone(), &mqtt_settings);
+            }
+            
+            // Poll the configured devices for state changes
+            *POLL_TARGETS.lock().unwrap() = load_poll_targets(&app_handle).devices;
+            tauri::async_runtime::spawn(run_poller(app_handle.clone()));
+            
             // Setup system tray
             let app_handle_clone = app_handle.clone();
             
             // Create tray menu
-            let open_item = MenuItem::with_id(app, "open", "Open", true, None::<&str>)?;
-            let close_item = MenuItem::with_id(app, "close", "Close", true, None::<&str>)?;
-            let tray_menu = MenuBuilder::new(app)
-                .item(&open_item)
-                .item(&close_item)
-                .build()?;
+            let tray_menu = build_tray_menu(&app_handle)?;
             
-            let _tray = TrayIconBuilder::new()
+            let _tray = TrayIconBuilder::with_id("main")
                 .icon(app.default_window_icon().unwrap().clone())
                 .tooltip("Mi Home Toolkit")
                 .menu(&tray_menu)
@@ -835,7 +1948,7 @@ fn main() {
                         "close" => {
                             app.exit(0);
                         }
-                        _ => {}
+                        id => run_from_tray(app, id),
                     }
                 })
                 .build(app)?;
@@ -867,11 +1980,24 @@ fn main() {
         })
         .invoke_handler(tauri::generate_handler![
             login,
+            submit_login_captcha,
+            send_login_2fa_code,
+            verify_login_2fa,
             get_countries,
             set_country,
             get_device,
             get_devices,
+            get_homes,
+            get_devices_all_regions,
+            get_devices_by_home,
+            export_devices,
+            get_scenes,
+            run_scene,
             call_device,
+            get_properties,
+            set_properties,
+            miot_action,
+            get_device_spec,
             is_logged_in,
             try_auto_login,
             logout,
@@ -882,12 +2008,34 @@ fn main() {
             update_command,
             delete_command,
             get_saved_commands,
+            run_saved_command,
+            get_macros,
+            save_macro,
+            delete_macro,
+            run_macro,
+            get_poll_targets,
+            save_poll_target,
+            remove_poll_target,
+            get_device_state,
+            rules::get_rules,
+            rules::save_rule,
+            rules::delete_rule,
+            scheduler::get_schedules,
+            scheduler::save_schedule,
+            scheduler::delete_schedule,
+            get_device_groups,
+            save_device_group,
+            delete_device_group,
             validate_shortcut,
             get_app_settings,
             save_close_to_tray_preference,
             save_auto_start_preference,
             save_auto_hide_preference,
-            save_all_settings
+            save_all_settings,
+            save_location,
+            save_network_settings,
+            rest_api::save_rest_api_settings,
+            mqtt::save_mqtt_settings
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -0,0 +1,388 @@
+// MQTT bridge: publishes the online state and polled properties of every device and
+// runs device calls and saved commands received over MQTT.
+//
+//   mihome/bridge/status             "online" / "offline" (retained, last will)
+//   mihome/<did>/online              "true" / "false" (retained)
+//   mihome/<did>/<property>          JSON value of a polled property (retained)
+//   mihome/<did>/call                {"method": ..., "params": ...}, result on mihome/<did>/call/result
+//   mihome/<did>/set/<property>      value to set, as JSON or a plain string, result on .../result
+//   mihome/command/<name>/run        any payload, report on mihome/command/<name>/result
+//
+// With Home Assistant turned on, devices are also announced on homeassistant/.../config
+// (see `miio::home_assistant`) and the properties behind their entities get polled.
+
+use lazy_static::lazy_static;
+use miio::{discovery_entities, MiioError, SetPropertyRequest};
+use rand::{distributions::Alphanumeric, Rng};
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Packet, QoS};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::time::Duration;
+use tauri::AppHandle;
+use tokio::sync::oneshot;
+
+use crate::{
+    execute_saved_command, get_spec_cache, list_devices, load_all_commands, load_app_settings, poll_device,
+    save_app_settings, save_poll_targets, PollTarget, PollTargets, PolledProperty, PropertyChange, MI_CLOUD_PROTOCOL,
+    POLL_TARGETS,
+};
+
+lazy_static! {
+    // Client of the running bridge, used to publish from outside the event loop
+    static ref CLIENT: std::sync::Mutex<Option<AsyncClient>> = std::sync::Mutex::new(None);
+    // Stops the running bridge
+    static ref SHUTDOWN: std::sync::Mutex<Option<oneshot::Sender<()>>> = std::sync::Mutex::new(None);
+}
+
+pub const TOPIC_PREFIX: &str = "mihome";
+// How often the online state of every device is refreshed from the cloud
+const ONLINE_REFRESH_SECS: u64 = 60;
+// Wait before reconnecting after the connection to the broker failed
+const RECONNECT_DELAY_SECS: u64 = 5;
+// Poll interval for devices that are only polled because they were announced to Home Assistant
+const DISCOVERY_POLL_INTERVAL_SECS: u64 = 30;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MqttSettings {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    // Announce devices to Home Assistant with MQTT discovery
+    #[serde(default)]
+    pub home_assistant: bool,
+}
+
+// What a message received on one of the subscribed topics asks for
+#[derive(Debug, PartialEq)]
+enum Request<'a> {
+    RunCommand { name: &'a str },
+    Call { did: &'a str },
+    SetProperty { did: &'a str, property: &'a str },
+}
+
+#[derive(Deserialize)]
+struct CallRequest {
+    method: String,
+    #[serde(default)]
+    params: Option<Value>,
+}
+
+pub fn bridge_status_topic() -> String {
+    format!("{}/bridge/status", TOPIC_PREFIX)
+}
+
+pub fn device_topic(did: &str, leaf: &str) -> String {
+    format!("{}/{}/{}", TOPIC_PREFIX, did, leaf)
+}
+
+// Publish without waiting; silently dropped when the bridge isn't running
+pub fn publish(topic: String, retain: bool, payload: impl Into<Vec<u8>>) {
+    if let Some(client) = CLIENT.lock().unwrap().as_ref() {
+        if let Err(e) = client.try_publish(topic, QoS::AtLeastOnce, retain, payload) {
+            eprintln!("MQTT publish failed: {}", e);
+        }
+    }
+}
+
+// Publish from a task, waiting while the queue to the broker is full instead of dropping
+async fn publish_queued(topic: String, retain: bool, payload: impl Into<Vec<u8>>) {
+    let client = CLIENT.lock().unwrap().clone();
+    if let Some(client) = client {
+        if let Err(e) = client.publish(topic, QoS::AtLeastOnce, retain, payload).await {
+            eprintln!("MQTT publish failed: {}", e);
+        }
+    }
+}
+
+// Called by the poller with the changed properties of a device
+pub fn publish_state(did: &str, changes: &[PropertyChange]) {
+    for change in changes {
+        publish(device_topic(did, &change.property), true, change.new.to_string());
+    }
+}
+
+async fn publish_online_states() {
+    let devices = {
+        let guard = MI_CLOUD_PROTOCOL.lock().await;
+        if !guard.is_session_valid() {
+            return;
+        }
+        match list_devices(&guard, None).await {
+            Ok(devices) => devices,
+            Err(e) => {
+                eprintln!("MQTT: failed to get devices: {}", e);
+                return;
+            }
+        }
+    };
+    for device in devices {
+        publish_queued(device_topic(&device.did, "online"), true, device.isOnline.to_string()).await;
+    }
+}
+
+// Announce every device Home Assistant can use, and poll the properties behind its entities
+async fn publish_discovery(app_handle: AppHandle) {
+    let devices = {
+        let guard = MI_CLOUD_PROTOCOL.lock().await;
+        if !guard.is_session_valid() {
+            return;
+        }
+        match list_devices(&guard, None).await {
+            Ok(devices) => devices,
+            Err(e) => {
+                eprintln!("MQTT: failed to get devices: {}", e);
+                return;
+            }
+        }
+    };
+
+    let spec_cache = get_spec_cache(&app_handle);
+    let mut configs = vec![];
+    {
+        let mut targets = POLL_TARGETS.lock().unwrap();
+        let mut targets_changed = false;
+        for device in &devices {
+            let spec = spec_cache.load_for(device).ok().flatten();
+            for entity in discovery_entities(device, spec.as_ref(), TOPIC_PREFIX) {
+                configs.push((entity.topic, entity.config.to_string()));
+
+                let index = match targets.iter().position(|t| t.did == device.did) {
+                    Some(index) => index,
+                    None => {
+                        targets.push(PollTarget {
+                            did: device.did.clone(),
+                            interval_secs: DISCOVERY_POLL_INTERVAL_SECS,
+                            props: vec![],
+                            miot_props: vec![],
+                        });
+                        targets.len() - 1
+                    }
+                };
+                let target = &mut targets[index];
+                for prop in entity.props {
+                    if !target.props.contains(&prop) {
+                        target.props.push(prop);
+                        targets_changed = true;
+                    }
+                }
+                for (siid, piid) in entity.miot_props {
+                    if !target.miot_props.iter().any(|p| p.siid == siid && p.piid == piid) {
+                        target.miot_props.push(PolledProperty { siid, piid });
+                        targets_changed = true;
+                    }
+                }
+            }
+        }
+        if targets_changed {
+            if let Err(e) = save_poll_targets(&app_handle, &PollTargets { devices: targets.clone() }) {
+                eprintln!("Failed to save poll targets: {}", e);
+            }
+        }
+    }
+
+    for (topic, config) in configs {
+        publish_queued(topic, true, config).await;
+    }
+}
+
+// Set a MIoT property ("<siid>.<piid>") or call `set_<property>` on a legacy device
+async fn set_property(did: &str, property: &str, value: Value) -> Result<Value, MiioError> {
+    let guard = MI_CLOUD_PROTOCOL.lock().await;
+    let miot = property.split_once('.').and_then(|(siid, piid)| Some((siid.parse().ok()?, piid.parse().ok()?)));
+    match miot {
+        Some((siid, piid)) => {
+            let request = SetPropertyRequest { did: did.to_string(), siid, piid, value };
+            let results = guard.set_properties(&[request], None).await?;
+            Ok(serde_json::to_value(results).unwrap_or_default())
+        }
+        None => guard.call_device(did, &format!("set_{}", property), Some(json!([value])), None).await,
+    }
+}
+
+fn parse_topic(topic: &str) -> Option<Request<'_>> {
+    let parts: Vec<&str> = topic.split('/').collect();
+    match parts.as_slice() {
+        [TOPIC_PREFIX, "command", name, "run"] => Some(Request::RunCommand { name }),
+        [TOPIC_PREFIX, did, "call"] => Some(Request::Call { did }),
+        [TOPIC_PREFIX, did, "set", property] => Some(Request::SetProperty { did, property }),
+        _ => None,
+    }
+}
+
+// Home Assistant sends plain strings such as `on`, everything else sends JSON
+fn parse_value(payload: &[u8]) -> Value {
+    serde_json::from_slice(payload).unwrap_or_else(|_| Value::String(String::from_utf8_lossy(payload).into_owned()))
+}
+
+async fn handle_message(app_handle: AppHandle, topic: String, payload: Vec<u8>) {
+    match parse_topic(&topic) {
+        Some(Request::RunCommand { name }) => {
+            let command = load_all_commands(&app_handle).and_then(|c| c.commands.into_iter().find(|c| c.name == name));
+            let result = match command {
+                Some(command) => execute_saved_command(&app_handle, &command)
+                    .await
+                    .map(|report| serde_json::to_value(report).unwrap_or_default()),
+                None => Err(format!("Command with name '{}' not found", name)),
+            };
+            let response = match result {
+                Ok(report) => report,
+                Err(e) => json!({ "error": e }),
+            };
+            publish(format!("{}/command/{}/result", TOPIC_PREFIX, name), false, response.to_string());
+        }
+        Some(Request::Call { did }) => {
+            let response = match serde_json::from_slice::<CallRequest>(&payload) {
+                Ok(call) => {
+                    let guard = MI_CLOUD_PROTOCOL.lock().await;
+                    match guard.call_device(did, &call.method, call.params, None).await {
+                        Ok(result) => json!({ "result": result }),
+                        Err(e) => json!({ "error": e }),
+                    }
+                }
+                Err(e) => json!({ "error": format!("Invalid call: {}", e) }),
+            };
+            publish(device_topic(did, "call/result"), false, response.to_string());
+        }
+        Some(Request::SetProperty { did, property }) => {
+            let response = match set_property(did, property, parse_value(&payload)).await {
+                Ok(result) => json!({ "result": result }),
+                Err(e) => json!({ "error": e }),
+            };
+            publish(device_topic(did, &format!("set/{}/result", property)), false, response.to_string());
+
+            // Poll right away so the new state shows up without waiting for the interval
+            let target = POLL_TARGETS.lock().unwrap().iter().find(|t| t.did == did).cloned();
+            if let Some(target) = target {
+                poll_device(app_handle, target).await;
+            }
+        }
+        None => {}
+    }
+}
+
+// Start the bridge, replacing a running one
+pub fn start(app_handle: AppHandle, settings: &MqttSettings) {
+    stop();
+
+    // Brokers drop the older connection of a client id, so two installs must not share one.
+    // Kept within the 23 characters MQTT 3.1.1 brokers have to accept.
+    let suffix: String = rand::thread_rng().sample_iter(&Alphanumeric).take(6).map(char::from).collect();
+    let mut options = MqttOptions::new(format!("mi-home-toolkit-{}", suffix), settings.host.clone(), settings.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_last_will(LastWill::new(bridge_status_topic(), "offline", QoS::AtLeastOnce, true));
+    if let Some(username) = &settings.username {
+        options.set_credentials(username.clone(), settings.password.clone().unwrap_or_default());
+    }
+    let (client, mut event_loop) = AsyncClient::new(options, 100);
+    let (shutdown_tx, mut shutdown_rx) = oneshot::channel();
+    *CLIENT.lock().unwrap() = Some(client.clone());
+    *SHUTDOWN.lock().unwrap() = Some(shutdown_tx);
+    let home_assistant = settings.home_assistant;
+
+    tauri::async_runtime::spawn(async move {
+        // Runs separately, as interrupting `poll` while it connects drops the connection.
+        // Discovery is repeated too, to pick up new devices and a login after connecting.
+        let refresh_app_handle = app_handle.clone();
+        let online_refresh = tauri::async_runtime::spawn(async move {
+            let period = Duration::from_secs(ONLINE_REFRESH_SECS);
+            let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            loop {
+                interval.tick().await;
+                if home_assistant {
+                    publish_discovery(refresh_app_handle.clone()).await;
+                }
+                publish_online_states().await;
+            }
+        });
+        loop {
+            tokio::select! {
+                _ = &mut shutdown_rx => {
+                    online_refresh.abort();
+                    let _ = client.publish(bridge_status_topic(), QoS::AtLeastOnce, true, "offline").await;
+                    let _ = client.disconnect().await;
+                    // Keep polling until the disconnect went out, so the status is flushed
+                    let flush = async { while event_loop.poll().await.is_ok() {} };
+                    let _ = tokio::time::timeout(Duration::from_secs(2), flush).await;
+                    break;
+                }
+                event = event_loop.poll() => match event {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        // Subscriptions don't survive a reconnect with a clean session
+                        let _ = client.subscribe(format!("{}/+/call", TOPIC_PREFIX), QoS::AtLeastOnce).await;
+                        let _ = client.subscribe(format!("{}/command/+/run", TOPIC_PREFIX), QoS::AtLeastOnce).await;
+                        let _ = client.subscribe(format!("{}/+/set/+", TOPIC_PREFIX), QoS::AtLeastOnce).await;
+                        publish(bridge_status_topic(), true, "online");
+                        if home_assistant {
+                            tauri::async_runtime::spawn(publish_discovery(app_handle.clone()));
+                        }
+                        tauri::async_runtime::spawn(publish_online_states());
+                    }
+                    Ok(Event::Incoming(Packet::Publish(message))) => {
+                        tauri::async_runtime::spawn(handle_message(app_handle.clone(), message.topic, message.payload.to_vec()));
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        eprintln!("MQTT connection error: {}", e);
+                        tokio::time::sleep(Duration::from_secs(RECONNECT_DELAY_SECS)).await;
+                    }
+                }
+            }
+        }
+    });
+}
+
+pub fn stop() {
+    CLIENT.lock().unwrap().take();
+    if let Some(shutdown_tx) = SHUTDOWN.lock().unwrap().take() {
+        let _ = shutdown_tx.send(());
+    }
+}
+
+#[tauri::command]
+pub async fn save_mqtt_settings(app_handle: AppHandle, mqtt: MqttSettings) -> Result<(), String> {
+    if mqtt.enabled {
+        start(app_handle.clone(), &mqtt);
+    } else {
+        stop();
+    }
+
+    let mut settings = load_app_settings(&app_handle);
+    settings.mqtt = Some(mqtt);
+    save_app_settings(&app_handle, &settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_request_topics() {
+        assert_eq!(parse_topic("mihome/command/Lamp on/run"), Some(Request::RunCommand { name: "Lamp on" }));
+        assert_eq!(parse_topic("mihome/123/call"), Some(Request::Call { did: "123" }));
+        assert_eq!(parse_topic("mihome/123/set/2.1"), Some(Request::SetProperty { did: "123", property: "2.1" }));
+        assert_eq!(parse_topic("mihome/123/set/power"), Some(Request::SetProperty { did: "123", property: "power" }));
+    }
+
+    #[test]
+    fn ignores_other_topics() {
+        // Results and states are published on the same tree
+        assert_eq!(parse_topic("mihome/123/call/result"), None);
+        assert_eq!(parse_topic("mihome/123/set/power/result"), None);
+        assert_eq!(parse_topic("mihome/command/Lamp on/result"), None);
+        assert_eq!(parse_topic("mihome/123/power"), None);
+        assert_eq!(parse_topic("mihome/bridge/status"), None);
+        assert_eq!(parse_topic("other/123/call"), None);
+    }
+
+    #[test]
+    fn parses_set_values() {
+        assert_eq!(parse_value(b"true"), json!(true));
+        assert_eq!(parse_value(b"42"), json!(42));
+        assert_eq!(parse_value(br#""on""#), json!("on"));
+        assert_eq!(parse_value(b"on"), json!("on"));
+    }
+}
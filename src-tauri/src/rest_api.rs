@@ -0,0 +1,205 @@
+// Optional REST API on localhost, so scripts can list devices and run the same saved
+// commands as the global shortcuts. Off by default; every request has to send the
+// token from the settings as `Authorization: Bearer <token>`.
+
+use axum::{
+    extract::{Path, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use lazy_static::lazy_static;
+use miio::{Device, MiioError};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::net::Ipv4Addr;
+use tauri::{async_runtime::JoinHandle, AppHandle};
+use tokio::sync::{oneshot, Mutex};
+
+use crate::{
+    execute_saved_command, list_devices, load_all_commands, load_app_settings, save_app_settings, CommandReport,
+    SavedCommand, MI_CLOUD_PROTOCOL,
+};
+
+lazy_static! {
+    // Shutdown signal and task of the running server
+    static ref SERVER: Mutex<Option<(oneshot::Sender<()>, JoinHandle<()>)>> = Mutex::new(None);
+}
+
+const DEFAULT_PORT: u16 = 8765;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RestApiSettings {
+    pub enabled: bool,
+    pub port: u16,
+    pub token: String,
+}
+
+#[derive(Clone)]
+struct ApiState {
+    app_handle: AppHandle,
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct CallRequest {
+    method: String,
+    #[serde(default)]
+    params: Option<Value>,
+}
+
+// Error response; miio errors keep their `{kind, code, message}` body
+struct ApiError(StatusCode, Value);
+
+impl ApiError {
+    fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        ApiError(status, json!({ "message": message.into() }))
+    }
+}
+
+impl From<MiioError> for ApiError {
+    fn from(err: MiioError) -> Self {
+        let status = match err {
+            MiioError::Invalid(_) => StatusCode::BAD_REQUEST,
+            MiioError::NotLoggedIn | MiioError::AuthExpired => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::BAD_GATEWAY,
+        };
+        ApiError(status, serde_json::to_value(&err).unwrap_or_default())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(self.1)).into_response()
+    }
+}
+
+// Compares in constant time so the token can't be guessed byte by byte
+fn token_matches(given: &str, expected: &str) -> bool {
+    given.len() == expected.len() && given.bytes().zip(expected.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+async fn require_token(State(state): State<ApiState>, request: Request, next: Next) -> Response {
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| token_matches(token, &state.token));
+    if !authorized {
+        return ApiError::new(StatusCode::UNAUTHORIZED, "Missing or invalid token").into_response();
+    }
+    next.run(request).await
+}
+
+async fn get_devices() -> Result<Json<Vec<Device>>, ApiError> {
+    let guard = MI_CLOUD_PROTOCOL.lock().await;
+    Ok(Json(list_devices(&guard, None).await?))
+}
+
+async fn call_device(Path(did): Path<String>, Json(call): Json<CallRequest>) -> Result<Json<Value>, ApiError> {
+    let guard = MI_CLOUD_PROTOCOL.lock().await;
+    Ok(Json(guard.call_device(&did, &call.method, call.params, None).await?))
+}
+
+async fn get_commands(State(state): State<ApiState>) -> Json<Vec<SavedCommand>> {
+    Json(load_all_commands(&state.app_handle).map(|c| c.commands).unwrap_or_default())
+}
+
+async fn run_command(State(state): State<ApiState>, Path(name): Path<String>) -> Result<Json<CommandReport>, ApiError> {
+    let command = load_all_commands(&state.app_handle)
+        .and_then(|c| c.commands.into_iter().find(|c| c.name == name))
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, format!("Command with name '{}' not found", name)))?;
+    execute_saved_command(&state.app_handle, &command)
+        .await
+        .map(Json)
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e))
+}
+
+// Start the server on 127.0.0.1, replacing a running one
+pub async fn start(app_handle: AppHandle, settings: &RestApiSettings) -> Result<(), String> {
+    // Held until the new server is registered, so two restarts can't race for the port
+    let mut server = SERVER.lock().await;
+    shut_down(server.take()).await;
+
+    let state = ApiState { app_handle, token: settings.token.clone() };
+    let router = Router::new()
+        .route("/devices", get(get_devices))
+        .route("/devices/{did}/call", post(call_device))
+        .route("/commands", get(get_commands))
+        .route("/commands/{name}/run", post(run_command))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_token))
+        .with_state(state);
+
+    let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, settings.port))
+        .await
+        .map_err(|e| format!("Failed to start the REST API on port {}: {}", settings.port, e))?;
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+
+    let task = tauri::async_runtime::spawn(async move {
+        let server = axum::serve(listener, router).with_graceful_shutdown(async {
+            let _ = shutdown_rx.await;
+        });
+        if let Err(e) = server.await {
+            eprintln!("REST API stopped: {}", e);
+        }
+    });
+    *server = Some((shutdown_tx, task));
+    Ok(())
+}
+
+pub async fn stop() {
+    let server = SERVER.lock().await.take();
+    shut_down(server).await;
+}
+
+// Signal the server and wait until it has finished its requests and released the port
+async fn shut_down(server: Option<(oneshot::Sender<()>, JoinHandle<()>)>) {
+    if let Some((shutdown_tx, task)) = server {
+        let _ = shutdown_tx.send(());
+        if let Err(e) = task.await {
+            eprintln!("REST API did not shut down cleanly: {}", e);
+        }
+    }
+}
+
+fn generate_token() -> String {
+    rand::thread_rng().sample_iter(&Alphanumeric).take(40).map(char::from).collect()
+}
+
+// Turn the REST API on or off. A token is created the first time it's enabled.
+#[tauri::command]
+pub async fn save_rest_api_settings(
+    app_handle: AppHandle,
+    enabled: bool,
+    port: Option<u16>,
+    regenerate_token: Option<bool>,
+) -> Result<RestApiSettings, String> {
+    let mut settings = load_app_settings(&app_handle);
+    let mut rest_api = settings.rest_api.clone().unwrap_or_else(|| RestApiSettings {
+        enabled: false,
+        port: DEFAULT_PORT,
+        token: generate_token(),
+    });
+    rest_api.enabled = enabled;
+    if let Some(port) = port {
+        rest_api.port = port;
+    }
+    if regenerate_token == Some(true) {
+        rest_api.token = generate_token();
+    }
+
+    // Saved first, so a port that is taken right now is still used on the next start
+    settings.rest_api = Some(rest_api.clone());
+    save_app_settings(&app_handle, &settings)?;
+
+    if rest_api.enabled {
+        start(app_handle.clone(), &rest_api).await?;
+    } else {
+        stop().await;
+    }
+    Ok(rest_api)
+}
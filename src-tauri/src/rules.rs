@@ -0,0 +1,381 @@
+// Local automations: when a polled device property changes in a given way, run a
+// saved command. Rules are evaluated on the diffs of the poller, so the property
+// has to be polled for its device.
+
+use chrono::{DateTime, Duration as ChronoDuration, Local, NaiveTime, Utc};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use tauri::{AppHandle, Emitter};
+
+use crate::{execute_saved_command, get_rules_path, load_all_commands, PropertyChange, POLL_TARGETS};
+
+lazy_static! {
+    // Serializes read-modify-write cycles of the rules file
+    static ref RULES_FILE: std::sync::Mutex<()> = std::sync::Mutex::new(());
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CrossDirection {
+    // From below the value to the value or above it
+    Rising,
+    // From above the value to the value or below it
+    Falling,
+    Either,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Condition {
+    // The value changed in any way
+    Changes,
+    // The value became `value`
+    Equals { value: Value },
+    // A numeric value passed `value`
+    Crosses { value: f64, direction: CrossDirection },
+}
+
+impl Condition {
+    // Whether going from `old` to `new` satisfies the condition. Nothing matches on
+    // the first poll of a device, when there is no old value to compare with.
+    fn matches(&self, old: Option<&Value>, new: &Value) -> bool {
+        let old = match old {
+            Some(old) => old,
+            None => return false,
+        };
+        match self {
+            Condition::Changes => !values_equal(old, new),
+            Condition::Equals { value } => values_equal(new, value) && !values_equal(old, value),
+            Condition::Crosses { value, direction } => match (old.as_f64(), new.as_f64()) {
+                (Some(old), Some(new)) => {
+                    let rising = old < *value && new >= *value;
+                    let falling = old > *value && new <= *value;
+                    match direction {
+                        CrossDirection::Rising => rising,
+                        CrossDirection::Falling => falling,
+                        CrossDirection::Either => rising || falling,
+                    }
+                }
+                _ => false,
+            },
+        }
+    }
+}
+
+// Like `==`, but 1 and 1.0 are the same; devices aren't consistent about it
+fn values_equal(a: &Value, b: &Value) -> bool {
+    match (a.as_f64(), b.as_f64()) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+// Local time of day range, e.g. 22:00 to 06:00 (wrapping past midnight)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct TimeWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl TimeWindow {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Rule {
+    pub name: String,
+    pub did: String,
+    // Legacy property name or "<siid>.<piid>", as configured in the poller
+    pub property: String,
+    pub condition: Condition,
+    // Only fire within this time of day
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window: Option<TimeWindow>,
+    // Saved command to run
+    pub command: String,
+    // Minimum time between two runs, so a value flapping around a threshold doesn't spam the device
+    #[serde(default)]
+    pub cooldown_secs: u64,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub last_fired: Option<DateTime<Utc>>,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+impl Rule {
+    fn should_fire(&self, change: &PropertyChange, now: DateTime<Utc>) -> bool {
+        if !self.enabled || change.property != self.property {
+            return false;
+        }
+        if let Some(window) = &self.window {
+            if !window.contains(now.with_timezone(&Local).time()) {
+                return false;
+            }
+        }
+        if let Some(last_fired) = self.last_fired {
+            if now - last_fired < ChronoDuration::seconds(self.cooldown_secs as i64) {
+                return false;
+            }
+        }
+        self.condition.matches(change.old.as_ref(), &change.new)
+    }
+}
+
+// Struct for the rules JSON file
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct Rules {
+    rules: Vec<Rule>,
+}
+
+// Payload of the `rule-triggered` event
+#[derive(Serialize, Debug, Clone)]
+struct RuleRun {
+    rule: String,
+    command: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+fn load_rules(app_handle: &AppHandle) -> Rules {
+    let path = get_rules_path(app_handle);
+    if !path.exists() {
+        return Rules::default();
+    }
+
+    match fs::read_to_string(path) {
+        Ok(json) => serde_json::from_str(&json).unwrap_or_default(),
+        Err(_) => Rules::default(),
+    }
+}
+
+fn save_rules(app_handle: &AppHandle, rules: &Rules) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(rules).map_err(|e| e.to_string())?;
+    fs::write(get_rules_path(app_handle), &json).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+// Mark the rules that fire on `changes` of a device and return them
+fn fire_rules(rules: &mut [Rule], did: &str, changes: &[PropertyChange], now: DateTime<Utc>) -> Vec<Rule> {
+    let mut fired = vec![];
+    for rule in rules.iter_mut().filter(|r| r.did == did) {
+        if changes.iter().any(|change| rule.should_fire(change, now)) {
+            rule.last_fired = Some(now);
+            fired.push(rule.clone());
+        }
+    }
+    fired
+}
+
+// Called by the poller with the changed properties of a device
+pub fn on_state_change(app_handle: &AppHandle, did: &str, changes: &[PropertyChange]) {
+    let fired = {
+        let _guard = RULES_FILE.lock().unwrap();
+        let mut rules = load_rules(app_handle);
+        let fired = fire_rules(&mut rules.rules, did, changes, Utc::now());
+        if !fired.is_empty() {
+            if let Err(e) = save_rules(app_handle, &rules) {
+                eprintln!("Failed to save rules: {}", e);
+            }
+        }
+        fired
+    };
+
+    for rule in fired {
+        let app_handle = app_handle.clone();
+        tauri::async_runtime::spawn(async move {
+            let command = load_all_commands(&app_handle).and_then(|c| c.commands.into_iter().find(|c| c.name == rule.command));
+            let result = match command {
+                Some(command) => execute_saved_command(&app_handle, &command).await.map(|_| ()),
+                None => Err(format!("Command with name '{}' not found", rule.command)),
+            };
+            if let Err(e) = &result {
+                eprintln!("Rule '{}' failed: {}", rule.name, e);
+            }
+            let run = RuleRun { rule: rule.name, command: rule.command, error: result.err() };
+            let _ = app_handle.emit("rule-triggered", &run);
+        });
+    }
+}
+
+#[tauri::command]
+pub async fn get_rules(app_handle: AppHandle) -> Vec<Rule> {
+    let _guard = RULES_FILE.lock().unwrap();
+    load_rules(&app_handle).rules
+}
+
+// Create a rule or replace the one with the same name
+#[tauri::command]
+pub async fn save_rule(app_handle: AppHandle, rule: Rule) -> Result<(), String> {
+    let is_polled = POLL_TARGETS.lock().unwrap().iter().any(|t| {
+        t.did == rule.did
+            && (t.props.contains(&rule.property)
+                || t.miot_props.iter().any(|p| format!("{}.{}", p.siid, p.piid) == rule.property))
+    });
+    if !is_polled {
+        return Err(format!("Property {} of device {} is not polled", rule.property, rule.did));
+    }
+    let command_exists = load_all_commands(&app_handle).is_some_and(|c| c.commands.iter().any(|c| c.name == rule.command));
+    if !command_exists {
+        return Err(format!("Command with name '{}' not found", rule.command));
+    }
+
+    let _guard = RULES_FILE.lock().unwrap();
+    let mut rules = load_rules(&app_handle);
+    match rules.rules.iter().position(|r| r.name == rule.name) {
+        Some(index) => rules.rules[index] = rule,
+        None => rules.rules.push(rule),
+    }
+    save_rules(&app_handle, &rules)
+}
+
+#[tauri::command]
+pub async fn delete_rule(app_handle: AppHandle, name: String) -> Result<(), String> {
+    let _guard = RULES_FILE.lock().unwrap();
+    let mut rules = load_rules(&app_handle);
+    let original_len = rules.rules.len();
+    rules.rules.retain(|r| r.name != name);
+
+    if rules.rules.len() == original_len {
+        return Err(format!("Rule '{}' not found", name));
+    }
+    save_rules(&app_handle, &rules)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    fn crosses(value: f64, direction: CrossDirection) -> Condition {
+        Condition::Crosses { value, direction }
+    }
+
+    fn rule(condition: Condition, cooldown_secs: u64) -> Rule {
+        Rule {
+            name: "test".to_string(),
+            did: "1".to_string(),
+            property: "temperature".to_string(),
+            condition,
+            window: None,
+            command: "fan".to_string(),
+            cooldown_secs,
+            enabled: true,
+            last_fired: None,
+        }
+    }
+
+    fn change(old: Option<Value>, new: Value) -> PropertyChange {
+        PropertyChange { property: "temperature".to_string(), old, new }
+    }
+
+    fn time(h: u32, m: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(h, m, 0).unwrap()
+    }
+
+    #[test]
+    fn crossings() {
+        let rising = crosses(25.0, CrossDirection::Rising);
+        assert!(rising.matches(Some(&json!(24)), &json!(25)));
+        assert!(rising.matches(Some(&json!(24.5)), &json!(30)));
+        assert!(!rising.matches(Some(&json!(25)), &json!(26)));
+        assert!(!rising.matches(Some(&json!(26)), &json!(24)));
+
+        let falling = crosses(25.0, CrossDirection::Falling);
+        assert!(falling.matches(Some(&json!(26)), &json!(25)));
+        assert!(!falling.matches(Some(&json!(25)), &json!(24)));
+        assert!(!falling.matches(Some(&json!(24)), &json!(26)));
+
+        let either = crosses(25.0, CrossDirection::Either);
+        assert!(either.matches(Some(&json!(24)), &json!(26)));
+        assert!(either.matches(Some(&json!(26)), &json!(24)));
+        assert!(!either.matches(Some(&json!(20)), &json!(24)));
+        assert!(!either.matches(Some(&json!("on")), &json!(30)));
+    }
+
+    #[test]
+    fn equals_fires_on_the_transition() {
+        let equals = Condition::Equals { value: json!("on") };
+        assert!(equals.matches(Some(&json!("off")), &json!("on")));
+        assert!(!equals.matches(Some(&json!("on")), &json!("on")));
+        assert!(!equals.matches(Some(&json!("on")), &json!("off")));
+
+        // Devices report the same number as 1 or 1.0
+        let equals = Condition::Equals { value: json!(1) };
+        assert!(equals.matches(Some(&json!(0)), &json!(1.0)));
+        assert!(!equals.matches(Some(&json!(1.0)), &json!(1)));
+        assert!(!Condition::Changes.matches(Some(&json!(1)), &json!(1.0)));
+        assert!(Condition::Changes.matches(Some(&json!(1)), &json!(2)));
+    }
+
+    #[test]
+    fn nothing_fires_on_the_first_poll() {
+        assert!(!Condition::Changes.matches(None, &json!(1)));
+        assert!(!Condition::Equals { value: json!(1) }.matches(None, &json!(1)));
+        assert!(!crosses(0.0, CrossDirection::Either).matches(None, &json!(1)));
+    }
+
+    #[test]
+    fn time_windows() {
+        let day = TimeWindow { start: time(8, 0), end: time(20, 0) };
+        assert!(day.contains(time(8, 0)));
+        assert!(day.contains(time(12, 0)));
+        assert!(!day.contains(time(20, 0)));
+        assert!(!day.contains(time(3, 0)));
+
+        let night = TimeWindow { start: time(22, 0), end: time(6, 0) };
+        assert!(night.contains(time(22, 0)));
+        assert!(night.contains(time(23, 59)));
+        assert!(night.contains(time(0, 0)));
+        assert!(night.contains(time(5, 59)));
+        assert!(!night.contains(time(6, 0)));
+        assert!(!night.contains(time(12, 0)));
+    }
+
+    #[test]
+    fn cooldown_suppresses_repeated_firing() {
+        let now = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+        let mut rules = vec![rule(Condition::Changes, 60)];
+        let changes = [change(Some(json!(20)), json!(21))];
+
+        assert_eq!(fire_rules(&mut rules, "1", &changes, now).len(), 1);
+        assert_eq!(rules[0].last_fired, Some(now));
+        assert!(fire_rules(&mut rules, "1", &changes, now + ChronoDuration::seconds(59)).is_empty());
+        assert_eq!(rules[0].last_fired, Some(now));
+
+        let later = now + ChronoDuration::seconds(60);
+        assert_eq!(fire_rules(&mut rules, "1", &changes, later).len(), 1);
+        assert_eq!(rules[0].last_fired, Some(later));
+    }
+
+    #[test]
+    fn only_matching_rules_fire() {
+        let now = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+        let mut disabled = rule(Condition::Changes, 0);
+        disabled.enabled = false;
+        let mut rules = vec![rule(Condition::Changes, 0), disabled];
+        let changes = [change(Some(json!(20)), json!(21))];
+
+        assert!(fire_rules(&mut rules, "2", &changes, now).is_empty());
+        let other_property = [PropertyChange { property: "humidity".to_string(), old: Some(json!(1)), new: json!(2) }];
+        assert!(fire_rules(&mut rules, "1", &other_property, now).is_empty());
+        assert!(fire_rules(&mut rules, "1", &[change(None, json!(21))], now).is_empty());
+
+        let fired = fire_rules(&mut rules, "1", &changes, now);
+        assert_eq!(fired.len(), 1);
+        assert!(fired[0].enabled);
+        assert_eq!(rules[1].last_fired, None);
+    }
+}
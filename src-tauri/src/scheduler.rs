@@ -0,0 +1,568 @@
+// Background scheduler running saved commands and macros from cron expressions,
+// fixed intervals or once at a given time. It runs for the whole lifetime of the
+// app, so schedules keep firing while the window is hidden in the tray.
+
+use chrono::{DateTime, Duration as ChronoDuration, Local, Utc};
+use croner::Cron;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::{fs, time::Duration};
+use tauri::{AppHandle, Emitter};
+use tokio::sync::Notify;
+
+use crate::{
+    execute_macro, execute_saved_command, get_schedules_path, list_devices, load_all_commands, load_all_macros, load_app_settings,
+    sun::{next_sun_event, SunEvent},
+    MI_CLOUD_PROTOCOL,
+};
+
+lazy_static! {
+    // Serializes read-modify-write cycles of the schedules file
+    static ref SCHEDULES_FILE: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    // Wakes the scheduler loop up after schedules were edited
+    static ref SCHEDULES_CHANGED: Notify = Notify::new();
+}
+
+// A run this late counts as missed, e.g. because the app was closed or the computer was asleep
+const MISSED_AFTER_SECS: i64 = 60;
+// Longest the loop sleeps, so clock changes and resuming from sleep are noticed
+const MAX_SLEEP_SECS: i64 = 60;
+// Missed runs of a single catch-up are counted up to this, e.g. for a 1 second interval
+const MAX_COUNTED_MISSED: u32 = 1000;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Trigger {
+    // Cron expression in local time, 5 fields or 6 with seconds first
+    Cron { expression: String },
+    Interval { seconds: u64 },
+    Once { at: DateTime<Utc> },
+    // Relative to sunrise or sunset, e.g. 30 minutes before sunset. The coordinates
+    // are taken from `did` or the configured location when the schedule is saved.
+    Sun {
+        event: SunEvent,
+        // Negative for before the event
+        #[serde(default)]
+        offset_minutes: i64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        did: Option<String>,
+        #[serde(default)]
+        latitude: Option<f64>,
+        #[serde(default)]
+        longitude: Option<f64>,
+    },
+}
+
+impl Trigger {
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            Trigger::Cron { expression } => parse_cron(expression).map(|_| ()),
+            Trigger::Interval { seconds: 0 } => Err("Interval must be at least one second".to_string()),
+            Trigger::Sun { latitude: Some(latitude), longitude: Some(longitude), .. } => {
+                if (-90.0..=90.0).contains(latitude) && (-180.0..=180.0).contains(longitude) {
+                    Ok(())
+                } else {
+                    Err("Invalid location".to_string())
+                }
+            }
+            Trigger::Sun { .. } => Err("Sun schedules need a location".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    // First run strictly after `after`
+    fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Trigger::Cron { expression } => parse_cron(expression)
+                .ok()?
+                .find_next_occurrence(&after.with_timezone(&Local), false)
+                .ok()
+                .map(|next| next.with_timezone(&Utc)),
+            Trigger::Interval { seconds } => Some(after + ChronoDuration::seconds(*seconds as i64)),
+            Trigger::Once { at } => (*at > after).then_some(*at),
+            Trigger::Sun { event, offset_minutes, latitude, longitude, .. } => next_sun_event(
+                *event,
+                (*latitude)?,
+                (*longitude)?,
+                ChronoDuration::minutes(*offset_minutes),
+                after,
+            ),
+        }
+    }
+}
+
+fn parse_cron(expression: &str) -> Result<Cron, String> {
+    Cron::new(expression)
+        .with_seconds_optional()
+        .parse()
+        .map_err(|e| format!("Invalid cron expression '{}': {}", expression, e))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ScheduleAction {
+    Command { name: String },
+    Macro { name: String },
+}
+
+// What to do with a run that was due while the app was not running
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MissedRuns {
+    // Report it as `schedule-missed` and wait for the next one
+    #[default]
+    Skip,
+    // Run once right away, however many runs were missed
+    RunOnce,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Schedule {
+    pub name: String,
+    pub trigger: Trigger,
+    pub action: ScheduleAction,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub missed_runs: MissedRuns,
+    #[serde(default)]
+    pub last_run: Option<DateTime<Utc>>,
+    // Latest run that was due while the app was closed or the computer asleep,
+    // and how many runs were missed like that in total
+    #[serde(default)]
+    pub last_missed: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub missed_count: u32,
+    // Filled in by the scheduler, reset whenever the schedule is saved
+    #[serde(default)]
+    pub next_run: Option<DateTime<Utc>>,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+impl Schedule {
+    fn upcoming(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match &self.trigger {
+            // A one-shot whose time passed while the app was closed is still due (or missed)
+            Trigger::Once { at } => Some(*at),
+            trigger => trigger.next_after(now),
+        }
+    }
+}
+
+// Struct for the schedules JSON file
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct Schedules {
+    schedules: Vec<Schedule>,
+}
+
+// Payload of the `schedule-executed` and `schedule-missed` events
+#[derive(Serialize, Debug, Clone)]
+struct ScheduleRun {
+    name: String,
+    scheduled_for: DateTime<Utc>,
+    // Whether the run was late enough to count as missed
+    missed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+struct Firing {
+    action: ScheduleAction,
+    run: ScheduleRun,
+    skipped: bool,
+}
+
+fn load_schedules(app_handle: &AppHandle) -> Schedules {
+    let path = get_schedules_path(app_handle);
+    if !path.exists() {
+        return Schedules::default();
+    }
+
+    match fs::read_to_string(path) {
+        Ok(json) => serde_json::from_str(&json).unwrap_or_default(),
+        Err(_) => Schedules::default(),
+    }
+}
+
+fn save_schedules(app_handle: &AppHandle, schedules: &Schedules) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(schedules).map_err(|e| e.to_string())?;
+    fs::write(get_schedules_path(app_handle), &json).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+// Fill in the coordinates of a sun trigger, from its device or else the configured
+// location. Done once on save so the scheduler itself never needs the cloud.
+async fn resolve_sun_location(app_handle: &AppHandle, trigger: &mut Trigger) -> Result<(), String> {
+    let (did, latitude, longitude) = match trigger {
+        Trigger::Sun { did, latitude, longitude, .. } => (did, latitude, longitude),
+        _ => return Ok(()),
+    };
+
+    if let Some(did) = did {
+        let guard = MI_CLOUD_PROTOCOL.lock().await;
+        let devices = list_devices(&guard, Some(&[did.clone()])).await.map_err(|e| e.to_string())?;
+        let device = devices.first().ok_or_else(|| format!("Device {} no longer exists", did))?;
+        // Devices without a known location report 0/0
+        let coordinates = match (device.latitude.parse::<f64>(), device.longitude.parse::<f64>()) {
+            (Ok(lat), Ok(lon)) if lat != 0.0 || lon != 0.0 => (lat, lon),
+            _ => return Err(format!("Device {} has no location", did)),
+        };
+        *latitude = Some(coordinates.0);
+        *longitude = Some(coordinates.1);
+    } else if latitude.is_none() || longitude.is_none() {
+        let location = load_app_settings(app_handle)
+            .location
+            .ok_or("No location configured, set one in the settings or pick a device")?;
+        *latitude = Some(location.latitude);
+        *longitude = Some(location.longitude);
+    }
+    Ok(())
+}
+
+// Move every due schedule to its next run and return what has to be run or
+// reported as missed. Returns whether any schedule changed.
+fn tick(schedules: &mut [Schedule], now: DateTime<Utc>) -> (bool, Vec<Firing>) {
+    let mut changed = false;
+    let mut firings = vec![];
+
+    for schedule in schedules.iter_mut().filter(|s| s.enabled) {
+        let next = match schedule.next_run {
+            Some(next) => next,
+            None => match schedule.upcoming(now) {
+                Some(next) => {
+                    schedule.next_run = Some(next);
+                    changed = true;
+                    next
+                }
+                None => continue,
+            },
+        };
+        if next > now {
+            continue;
+        }
+
+        let missed = now - next > ChronoDuration::seconds(MISSED_AFTER_SECS);
+        let skipped = missed && schedule.missed_runs == MissedRuns::Skip;
+        if !skipped {
+            schedule.last_run = Some(now);
+        }
+        if missed {
+            let (count, latest) = missed_runs(&schedule.trigger, next, now);
+            schedule.missed_count = schedule.missed_count.saturating_add(count);
+            schedule.last_missed = Some(latest);
+        }
+        schedule.next_run = match schedule.trigger {
+            Trigger::Once { .. } => {
+                schedule.enabled = false;
+                None
+            }
+            ref trigger => trigger.next_after(now),
+        };
+        changed = true;
+
+        firings.push(Firing {
+            action: schedule.action.clone(),
+            run: ScheduleRun { name: schedule.name.clone(), scheduled_for: next, missed, error: None },
+            skipped,
+        });
+    }
+
+    (changed, firings)
+}
+
+// Number of runs from `first` on that were overdue at `now`, and the latest of them
+fn missed_runs(trigger: &Trigger, first: DateTime<Utc>, now: DateTime<Utc>) -> (u32, DateTime<Utc>) {
+    let overdue = now - ChronoDuration::seconds(MISSED_AFTER_SECS);
+    let (mut count, mut latest) = (1, first);
+    while count < MAX_COUNTED_MISSED {
+        match trigger.next_after(latest) {
+            Some(next) if next < overdue => {
+                count += 1;
+                latest = next;
+            }
+            _ => break,
+        }
+    }
+    (count, latest)
+}
+
+// How long to sleep until the earliest upcoming run
+fn sleep_duration(schedules: &[Schedule], now: DateTime<Utc>) -> Duration {
+    let secs = schedules
+        .iter()
+        .filter(|s| s.enabled)
+        .filter_map(|s| s.next_run)
+        .map(|next| (next - now).num_seconds())
+        .min()
+        .unwrap_or(MAX_SLEEP_SECS)
+        .clamp(0, MAX_SLEEP_SECS);
+    // Wake up just after the run is due, not just before
+    Duration::from_secs(secs as u64) + Duration::from_millis(100)
+}
+
+async fn fire(app_handle: AppHandle, firing: Firing) {
+    let mut run = firing.run;
+    if firing.skipped {
+        let _ = app_handle.emit("schedule-missed", &run);
+        return;
+    }
+
+    let result = match &firing.action {
+        ScheduleAction::Command { name } => {
+            let command = load_all_commands(&app_handle).and_then(|c| c.commands.into_iter().find(|c| &c.name == name));
+            match command {
+                Some(command) => execute_saved_command(&app_handle, &command).await.map(|_| ()),
+                None => Err(format!("Command with name '{}' not found", name)),
+            }
+        }
+        ScheduleAction::Macro { name } => {
+            let command_macro = load_all_macros(&app_handle).macros.into_iter().find(|m| &m.name == name);
+            match command_macro {
+                Some(command_macro) => execute_macro(&app_handle, &command_macro).await.map(|_| ()),
+                None => Err(format!("Macro '{}' not found", name)),
+            }
+        }
+    };
+    if let Err(e) = &result {
+        eprintln!("Schedule '{}' failed: {}", run.name, e);
+    }
+    run.error = result.err();
+    let _ = app_handle.emit("schedule-executed", &run);
+}
+
+// Scheduler loop, spawned once from `setup`
+pub async fn run(app_handle: AppHandle) {
+    loop {
+        let now = Utc::now();
+        let (firings, sleep) = {
+            let _guard = SCHEDULES_FILE.lock().unwrap();
+            let mut schedules = load_schedules(&app_handle);
+            let (changed, firings) = tick(&mut schedules.schedules, now);
+            if changed {
+                if let Err(e) = save_schedules(&app_handle, &schedules) {
+                    eprintln!("Failed to save schedules: {}", e);
+                }
+            }
+            (firings, sleep_duration(&schedules.schedules, now))
+        };
+
+        // Runs happen in the background so a slow macro doesn't hold up other schedules
+        for firing in firings {
+            tauri::async_runtime::spawn(fire(app_handle.clone(), firing));
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(sleep) => {}
+            _ = SCHEDULES_CHANGED.notified() => {}
+        }
+    }
+}
+
+#[tauri::command]
+pub async fn get_schedules(app_handle: AppHandle) -> Vec<Schedule> {
+    let _guard = SCHEDULES_FILE.lock().unwrap();
+    load_schedules(&app_handle).schedules
+}
+
+// Create a schedule or replace the one with the same name
+#[tauri::command]
+pub async fn save_schedule(app_handle: AppHandle, mut schedule: Schedule) -> Result<(), String> {
+    resolve_sun_location(&app_handle, &mut schedule.trigger).await?;
+    schedule.trigger.validate()?;
+    match &schedule.action {
+        ScheduleAction::Command { name } => {
+            let exists = load_all_commands(&app_handle).is_some_and(|c| c.commands.iter().any(|c| &c.name == name));
+            if !exists {
+                return Err(format!("Command with name '{}' not found", name));
+            }
+        }
+        ScheduleAction::Macro { name } => {
+            if !load_all_macros(&app_handle).macros.iter().any(|m| &m.name == name) {
+                return Err(format!("Macro '{}' not found", name));
+            }
+        }
+    }
+    // Recomputed by the scheduler from the new trigger
+    schedule.next_run = None;
+
+    {
+        let _guard = SCHEDULES_FILE.lock().unwrap();
+        let mut schedules = load_schedules(&app_handle);
+        match schedules.schedules.iter().position(|s| s.name == schedule.name) {
+            Some(index) => schedules.schedules[index] = schedule,
+            None => schedules.schedules.push(schedule),
+        }
+        save_schedules(&app_handle, &schedules)?;
+    }
+    SCHEDULES_CHANGED.notify_one();
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn delete_schedule(app_handle: AppHandle, name: String) -> Result<(), String> {
+    {
+        let _guard = SCHEDULES_FILE.lock().unwrap();
+        let mut schedules = load_schedules(&app_handle);
+        let original_len = schedules.schedules.len();
+        schedules.schedules.retain(|s| s.name != name);
+
+        if schedules.schedules.len() == original_len {
+            return Err(format!("Schedule '{}' not found", name));
+        }
+        save_schedules(&app_handle, &schedules)?;
+    }
+    SCHEDULES_CHANGED.notify_one();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Timelike};
+
+    fn at(h: u32, m: u32, s: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, 1, h, m, s).unwrap()
+    }
+
+    fn schedule(trigger: Trigger, missed_runs: MissedRuns) -> Schedule {
+        Schedule {
+            name: "test".to_string(),
+            trigger,
+            action: ScheduleAction::Command { name: "lamp".to_string() },
+            enabled: true,
+            missed_runs,
+            last_run: None,
+            last_missed: None,
+            missed_count: 0,
+            next_run: None,
+        }
+    }
+
+    #[test]
+    fn cron_trigger() {
+        // Seconds resolution keeps the test independent of the local time zone
+        let trigger = Trigger::Cron { expression: "*/10 * * * * *".to_string() };
+        assert_eq!(trigger.next_after(at(10, 7, 3)), Some(at(10, 7, 10)));
+        assert_eq!(trigger.next_after(at(10, 7, 10)), Some(at(10, 7, 20)));
+
+        let next = Trigger::Cron { expression: "30 * * * *".to_string() }.next_after(at(10, 7, 3)).unwrap();
+        assert!(next > at(10, 7, 3) && next.second() == 0);
+
+        assert!(Trigger::Cron { expression: "not a cron".to_string() }.validate().is_err());
+    }
+
+    #[test]
+    fn interval_and_once_triggers() {
+        let interval = Trigger::Interval { seconds: 90 };
+        assert_eq!(interval.next_after(at(10, 0, 0)), Some(at(10, 1, 30)));
+        assert!(Trigger::Interval { seconds: 0 }.validate().is_err());
+
+        let once = Trigger::Once { at: at(12, 0, 0) };
+        assert_eq!(once.next_after(at(10, 0, 0)), Some(at(12, 0, 0)));
+        assert_eq!(once.next_after(at(12, 0, 0)), None);
+        // Still due when its time passed while the app was closed
+        assert_eq!(schedule(once, MissedRuns::Skip).upcoming(at(13, 0, 0)), Some(at(12, 0, 0)));
+    }
+
+    #[test]
+    fn tick_runs_due_schedules() {
+        let mut schedules = vec![schedule(Trigger::Interval { seconds: 60 }, MissedRuns::Skip)];
+
+        // The first tick only plans the next run
+        let (changed, firings) = tick(&mut schedules, at(10, 0, 0));
+        assert!(changed && firings.is_empty());
+        assert_eq!(schedules[0].next_run, Some(at(10, 1, 0)));
+        let (changed, firings) = tick(&mut schedules, at(10, 0, 30));
+        assert!(!changed && firings.is_empty());
+
+        let (_, firings) = tick(&mut schedules, at(10, 1, 0));
+        assert_eq!(firings.len(), 1);
+        assert!(!firings[0].skipped && !firings[0].run.missed);
+        assert_eq!(firings[0].run.scheduled_for, at(10, 1, 0));
+        assert_eq!(schedules[0].last_run, Some(at(10, 1, 0)));
+        assert_eq!(schedules[0].next_run, Some(at(10, 2, 0)));
+        assert_eq!(schedules[0].missed_count, 0);
+
+        schedules[0].enabled = false;
+        let (changed, firings) = tick(&mut schedules, at(11, 0, 0));
+        assert!(!changed && firings.is_empty());
+    }
+
+    #[test]
+    fn missed_runs_are_skipped_and_recorded() {
+        let mut schedules = vec![schedule(Trigger::Interval { seconds: 60 }, MissedRuns::Skip)];
+        schedules[0].next_run = Some(at(10, 0, 0));
+        schedules[0].last_run = Some(at(9, 59, 0));
+
+        // Closed from 10:00 to 10:10: the runs at 10:00 to 10:08 are overdue
+        let (changed, firings) = tick(&mut schedules, at(10, 10, 0));
+        assert!(changed);
+        assert_eq!(firings.len(), 1);
+        assert!(firings[0].skipped && firings[0].run.missed);
+        assert_eq!(schedules[0].last_run, Some(at(9, 59, 0)));
+        assert_eq!(schedules[0].last_missed, Some(at(10, 8, 0)));
+        assert_eq!(schedules[0].missed_count, 9);
+        assert_eq!(schedules[0].next_run, Some(at(10, 11, 0)));
+
+        // A run that is only a little late is not missed
+        let (_, firings) = tick(&mut schedules, at(10, 11, 30));
+        assert!(!firings[0].skipped && !firings[0].run.missed);
+        assert_eq!(schedules[0].missed_count, 9);
+    }
+
+    #[test]
+    fn missed_runs_run_once() {
+        let mut schedules = vec![schedule(Trigger::Interval { seconds: 60 }, MissedRuns::RunOnce)];
+        schedules[0].next_run = Some(at(10, 0, 0));
+
+        let (_, firings) = tick(&mut schedules, at(10, 10, 0));
+        assert_eq!(firings.len(), 1);
+        assert!(!firings[0].skipped && firings[0].run.missed);
+        assert_eq!(schedules[0].last_run, Some(at(10, 10, 0)));
+        assert_eq!(schedules[0].missed_count, 9);
+        assert_eq!(schedules[0].next_run, Some(at(10, 11, 0)));
+    }
+
+    #[test]
+    fn one_shots_disable_themselves() {
+        let mut schedules = vec![
+            schedule(Trigger::Once { at: at(10, 0, 0) }, MissedRuns::Skip),
+            schedule(Trigger::Once { at: at(8, 0, 0) }, MissedRuns::Skip),
+        ];
+
+        let (_, firings) = tick(&mut schedules, at(10, 0, 5));
+        assert_eq!(firings.len(), 2);
+        assert!(!firings[0].skipped);
+        assert!(firings[1].skipped);
+        assert_eq!(schedules[1].last_missed, Some(at(8, 0, 0)));
+        assert_eq!(schedules[1].missed_count, 1);
+        for schedule in &schedules {
+            assert!(!schedule.enabled);
+            assert_eq!(schedule.next_run, None);
+        }
+
+        let (changed, firings) = tick(&mut schedules, at(12, 0, 0));
+        assert!(!changed && firings.is_empty());
+    }
+
+    #[test]
+    fn sleeps_until_the_next_run() {
+        let mut schedules = vec![
+            schedule(Trigger::Interval { seconds: 60 }, MissedRuns::Skip),
+            schedule(Trigger::Interval { seconds: 60 }, MissedRuns::Skip),
+        ];
+        assert_eq!(sleep_duration(&schedules, at(10, 0, 0)), Duration::from_millis(60_100));
+
+        schedules[0].next_run = Some(at(10, 0, 20));
+        schedules[1].next_run = Some(at(10, 0, 10));
+        assert_eq!(sleep_duration(&schedules, at(10, 0, 0)), Duration::from_millis(10_100));
+        schedules[1].enabled = false;
+        assert_eq!(sleep_duration(&schedules, at(10, 0, 0)), Duration::from_millis(20_100));
+        // Overdue runs are picked up right away, far ones are checked on again after a minute
+        assert_eq!(sleep_duration(&schedules, at(10, 5, 0)), Duration::from_millis(100));
+        assert_eq!(sleep_duration(&schedules, at(9, 0, 0)), Duration::from_millis(60_100));
+    }
+}
@@ -0,0 +1,120 @@
+// Offline sunrise and sunset times, using the sunrise equation
+// (https://en.wikipedia.org/wiki/Sunrise_equation). Accurate to a minute or two,
+// which is plenty for switching lights.
+
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SunEvent {
+    Sunrise,
+    Sunset,
+}
+
+// Julian date of 2000-01-01 12:00 UTC
+const J2000: f64 = 2451545.0;
+// Julian date of the unix epoch
+const UNIX_EPOCH_JD: f64 = 2440587.5;
+
+// Sunrise and sunset around the given day at a location (latitude north and
+// longitude east positive). None when the sun doesn't rise or doesn't set that day.
+pub fn sun_times(day: NaiveDate, latitude: f64, longitude: f64) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let epoch = NaiveDate::from_ymd_opt(2000, 1, 1).unwrap();
+    let n = (day - epoch).num_days() as f64;
+
+    // Mean solar time
+    let j_star = n + 0.0009 - longitude / 360.0;
+    // Solar mean anomaly
+    let m = (357.5291 + 0.98560028 * j_star).rem_euclid(360.0);
+    let m_rad = m.to_radians();
+    // Equation of the center
+    let c = 1.9148 * m_rad.sin() + 0.02 * (2.0 * m_rad).sin() + 0.0003 * (3.0 * m_rad).sin();
+    // Ecliptic longitude
+    let lambda = (m + c + 180.0 + 102.9372).rem_euclid(360.0).to_radians();
+    let transit = J2000 + j_star + 0.0053 * m_rad.sin() - 0.0069 * (2.0 * lambda).sin();
+    // Declination of the sun
+    let sin_declination = lambda.sin() * 23.4397_f64.to_radians().sin();
+    let cos_declination = sin_declination.asin().cos();
+
+    // Hour angle at which the sun's upper edge touches the horizon, with refraction
+    let lat = latitude.to_radians();
+    let cos_hour_angle = ((-0.833_f64).to_radians().sin() - lat.sin() * sin_declination) / (lat.cos() * cos_declination);
+    if !(-1.0..=1.0).contains(&cos_hour_angle) {
+        return None;
+    }
+    let hour_angle = cos_hour_angle.acos().to_degrees();
+
+    let sunrise = julian_to_utc(transit - hour_angle / 360.0)?;
+    let sunset = julian_to_utc(transit + hour_angle / 360.0)?;
+    Some((sunrise, sunset))
+}
+
+fn julian_to_utc(julian: f64) -> Option<DateTime<Utc>> {
+    let millis = ((julian - UNIX_EPOCH_JD) * 86_400_000.0).round() as i64;
+    DateTime::from_timestamp_millis(millis)
+}
+
+// First `event` shifted by `offset` that falls strictly after `after`. Looks up to a
+// year ahead, so locations in polar night or midnight sun still get their next one.
+pub fn next_sun_event(event: SunEvent, latitude: f64, longitude: f64, offset: Duration, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let start = after.date_naive() - Duration::days(1);
+    (0..370)
+        .map(|days| start + Duration::days(days))
+        .filter_map(|day| sun_times(day, latitude, longitude))
+        .map(|(sunrise, sunset)| match event {
+            SunEvent::Sunrise => sunrise,
+            SunEvent::Sunset => sunset,
+        } + offset)
+        .find(|time| *time > after)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const BERLIN: (f64, f64) = (52.52, 13.405);
+    const TROMSO: (f64, f64) = (69.65, 18.96);
+
+    fn day(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    fn assert_close(actual: DateTime<Utc>, expected: DateTime<Utc>) {
+        assert!((actual - expected).num_seconds().abs() <= 120, "{} is not close to {}", actual, expected);
+    }
+
+    #[test]
+    fn sun_times_for_a_known_day() {
+        // Berlin on the summer solstice: 04:43 and 21:33 local summer time
+        let (sunrise, sunset) = sun_times(day(2024, 6, 21), BERLIN.0, BERLIN.1).unwrap();
+        assert_close(sunrise, Utc.with_ymd_and_hms(2024, 6, 21, 2, 43, 0).unwrap());
+        assert_close(sunset, Utc.with_ymd_and_hms(2024, 6, 21, 19, 33, 0).unwrap());
+    }
+
+    #[test]
+    fn polar_night_and_midnight_sun() {
+        assert_eq!(sun_times(day(2024, 12, 21), TROMSO.0, TROMSO.1), None);
+        assert_eq!(sun_times(day(2024, 6, 21), TROMSO.0, TROMSO.1), None);
+
+        // The next sunrise after the polar night is found in the new year
+        let after = Utc.with_ymd_and_hms(2024, 12, 21, 12, 0, 0).unwrap();
+        let sunrise = next_sun_event(SunEvent::Sunrise, TROMSO.0, TROMSO.1, Duration::zero(), after).unwrap();
+        assert!(sunrise > Utc.with_ymd_and_hms(2025, 1, 10, 0, 0, 0).unwrap(), "{}", sunrise);
+        assert!(sunrise < Utc.with_ymd_and_hms(2025, 1, 20, 0, 0, 0).unwrap(), "{}", sunrise);
+    }
+
+    #[test]
+    fn negative_offset_across_midnight() {
+        // Three hours before sunrise falls on the previous day in UTC
+        let offset = Duration::hours(-3);
+        let after = Utc.with_ymd_and_hms(2024, 6, 20, 23, 0, 0).unwrap();
+        let next = next_sun_event(SunEvent::Sunrise, BERLIN.0, BERLIN.1, offset, after).unwrap();
+        assert_close(next, Utc.with_ymd_and_hms(2024, 6, 20, 23, 43, 0).unwrap());
+
+        // Once it passed, the one before the following sunrise is next
+        let next = next_sun_event(SunEvent::Sunrise, BERLIN.0, BERLIN.1, offset, next).unwrap();
+        assert_close(next, Utc.with_ymd_and_hms(2024, 6, 21, 23, 43, 0).unwrap());
+    }
+}
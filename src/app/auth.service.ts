@@ -3,6 +3,7 @@ import { Router } from '@angular/router'
 import { MiService } from './mi.service'
 import { BehaviorSubject, map } from 'rxjs'
 import { toSignal } from '@angular/core/rxjs-interop'
+import { LoginOutcome } from './types'
 
 type User = { email: string; country?: string }
 
@@ -55,10 +56,39 @@ export class AuthService {
     return res
   }
 
+  // Login interrupted by a captcha or a two-factor challenge
+  private pendingLogin: { email: string; country?: string; should_save_credentials: boolean } | null = null
+
   async login(creds: { email: string; password: string; country?: string; should_save_credentials?: boolean }) {
-    const res = await this.miService.login(creds)
-    this.user$.next(creds)
-    return res
+    this.pendingLogin = {
+      email: creds.email,
+      country: creds.country,
+      should_save_credentials: creds.should_save_credentials ?? true,
+    }
+    return this.handleOutcome(await this.miService.login(creds))
+  }
+
+  async submitCaptcha(code: string) {
+    const outcome = await this.miService.submitLoginCaptcha(code, this.pendingLogin?.should_save_credentials ?? true)
+    return this.handleOutcome(outcome)
+  }
+
+  sendTwoFactorCode() {
+    return this.miService.sendLogin2faCode()
+  }
+
+  async verifyTwoFactor(code: string) {
+    const outcome = await this.miService.verifyLogin2fa(code, this.pendingLogin?.should_save_credentials ?? true)
+    return this.handleOutcome(outcome)
+  }
+
+  // Only a completed login sets the user; challenges keep the login pending
+  private handleOutcome(outcome: LoginOutcome) {
+    if (outcome.type === 'logged_in' && this.pendingLogin) {
+      this.user$.next({ email: this.pendingLogin.email, country: this.pendingLogin.country })
+      this.pendingLogin = null
+    }
+    return outcome
   }
   async logout() {
     await this.miService.logout();
@@ -9,6 +9,7 @@ import {
 } from '@angular/core'
 import { injectMutation, injectQuery } from '@tanstack/angular-query-experimental'
 import { MiService } from '../../mi.service'
+import { MiioError } from '../../types'
 import { CommonModule } from '@angular/common'
 import { DialogDirective } from '../dialog.directive'
 import { FormBuilder, FormsModule, ReactiveFormsModule, Validators } from '@angular/forms'
@@ -182,7 +183,7 @@ import { FormBuilder, FormsModule, ReactiveFormsModule, Validators } from '@angu
 })
 export class ExecuteCommandDialogComponent {
   fb = inject(FormBuilder)
-  device = model<{ did: number | string; name: string } | null>(null)
+  device = model<{ did: number | string; name: string; model?: string } | null>(null)
   did = computed(() => this.device()?.did)
   visible = computed(() => !!this.device())
   success = output()
@@ -209,10 +210,10 @@ export class ExecuteCommandDialogComponent {
     staleTime: 1000 * 60 * 5, // 5 minutes
   }))  // Mutation to save commands
   saveCommandMutation = injectMutation(() => ({
-    mutationFn: (data: { name: string; method: string; params: string; shortcut?: string; update?: boolean }) =>
+    mutationFn: (data: { name: string; method: string; params: string; shortcut?: string; did?: string; model?: string; update?: boolean }) =>
       data.update 
-        ? this.miService.updateCommand(data.name, data.method, data.params, data.shortcut)
-        : this.miService.saveCommand(data.name, data.method, data.params, data.shortcut),
+        ? this.miService.updateCommand(data.name, data.method, data.params, data.shortcut, undefined, data.did, data.model)
+        : this.miService.saveCommand(data.name, data.method, data.params, data.shortcut, undefined, data.did, data.model),
     onSuccess: (_, variables) => {
       this.savedCommandsQuery.refetch()
       
@@ -285,7 +286,7 @@ export class ExecuteCommandDialogComponent {
     const result = this.form.controls.result
 
     if (isPending) return result.setValue('Loading...')
-    if (isError) return result.setValue(error || 'Error')
+    if (isError) return result.setValue((error as MiioError | null)?.message || 'Error')
     return result.setValue(JSON.stringify(data))
   })
   executeCommand() {
@@ -309,6 +310,8 @@ export class ExecuteCommandDialogComponent {
       method, 
       params: params || '',
       shortcut: shortcut || undefined,
+      did: this.did()?.toString(),
+      model: this.device()?.model,
       update: isUpdate
     })
   }  loadSavedCommand(commandName: string) {
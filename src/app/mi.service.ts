@@ -1,6 +1,6 @@
 import { computed, Injectable, resource } from '@angular/core'
 import { invoke } from '@tauri-apps/api/core'
-import { GetDevicesResponse } from './types'
+import { CommandMacro, CommandReport, CommandTarget, DeviceGroup, DevicesByHome, ExportFormat, GetDevicesResponse, Home, LoginOutcome, MacroReport, PollTarget, Rule, Scene, Schedule, TwoFactorMethod } from './types'
 
 // Define the SavedCredentials type
 interface SavedCredentials {
@@ -14,13 +14,17 @@ export interface SavedCommand {
   method: string
   params: string
   shortcut?: string
+  scene_id?: string
+  did?: string
+  model?: string
+  target?: CommandTarget
 }
 
 @Injectable({
   providedIn: 'root',
 })
 export class MiService {  login(creds: { email: string; password: string; country?: string; should_save_credentials?: boolean }) {
-    return invoke('login', { 
+    return invoke<LoginOutcome>('login', { 
       email: creds.email,
       password: creds.password,
       country: creds.country,
@@ -28,6 +32,18 @@ export class MiService {  login(creds: { email: string; password: string; countr
     })
   }
 
+  submitLoginCaptcha(code: string, shouldSaveCredentials: boolean) {
+    return invoke<LoginOutcome>('submit_login_captcha', { code, shouldSaveCredentials })
+  }
+
+  sendLogin2faCode() {
+    return invoke<TwoFactorMethod>('send_login_2fa_code')
+  }
+
+  verifyLogin2fa(code: string, shouldSaveCredentials: boolean) {
+    return invoke<LoginOutcome>('verify_login_2fa', { code, shouldSaveCredentials })
+  }
+
   logout() {
     return invoke('logout')
   }
@@ -58,12 +74,37 @@ export class MiService {  login(creds: { email: string; password: string; countr
     return invoke<GetDevicesResponse>('get_devices')
   }
 
+  getDevicesAllRegions() {
+    return invoke<GetDevicesResponse>('get_devices_all_regions')
+  }
+
   getDevice(did: string) {
     return invoke<GetDevicesResponse>('get_device', { did }).then((res) =>
       res.at(0)
     )
   }
 
+  getHomes() {
+    return invoke<Home[]>('get_homes')
+  }
+
+  getDevicesByHome() {
+    return invoke<DevicesByHome>('get_devices_by_home')
+  }
+
+  // Resolves to the number of exported devices
+  exportDevices(format: ExportFormat, path: string, filter?: { home?: string; region?: string }) {
+    return invoke<number>('export_devices', { format, path, home: filter?.home, region: filter?.region })
+  }
+
+  getScenes() {
+    return invoke<Scene[]>('get_scenes')
+  }
+
+  runScene(sceneId: string) {
+    return invoke('run_scene', { sceneId })
+  }
+
   callDevice(data: { did: string; method: string; params?: string | null }) {
     const { did, method } = data
     let { params } = data
@@ -82,12 +123,12 @@ export class MiService {  login(creds: { email: string; password: string; countr
   }
   getSavedCredentials() {
     return invoke<SavedCredentials | null>('get_saved_credentials')
-  }  saveCommand(name: string, method: string, params: string, shortcut?: string) {
-    return invoke('save_command', { name, method, params, shortcut })
+  }  saveCommand(name: string, method: string, params: string, shortcut?: string, sceneId?: string, did?: string, model?: string, target?: CommandTarget) {
+    return invoke('save_command', { name, method, params, shortcut, sceneId, did, model, target })
   }
 
-  updateCommand(name: string, method: string, params: string, shortcut?: string) {
-    return invoke('update_command', { name, method, params, shortcut })
+  updateCommand(name: string, method: string, params: string, shortcut?: string, sceneId?: string, did?: string, model?: string, target?: CommandTarget) {
+    return invoke('update_command', { name, method, params, shortcut, sceneId, did, model, target })
   }
 
   deleteCommand(name: string) {
@@ -101,4 +142,76 @@ export class MiService {  login(creds: { email: string; password: string; countr
   getSavedCommands() {
     return invoke<SavedCommand[]>('get_saved_commands')
   }
+
+  runSavedCommand(name: string) {
+    return invoke<CommandReport>('run_saved_command', { name })
+  }
+
+  getMacros() {
+    return invoke<CommandMacro[]>('get_macros')
+  }
+
+  saveMacro(commandMacro: CommandMacro) {
+    return invoke('save_macro', { commandMacro })
+  }
+
+  deleteMacro(name: string) {
+    return invoke('delete_macro', { name })
+  }
+
+  runMacro(name: string) {
+    return invoke<MacroReport>('run_macro', { name })
+  }
+
+  getPollTargets() {
+    return invoke<PollTarget[]>('get_poll_targets')
+  }
+
+  savePollTarget(target: PollTarget) {
+    return invoke('save_poll_target', { target })
+  }
+
+  removePollTarget(did: string) {
+    return invoke('remove_poll_target', { did })
+  }
+
+  getDeviceState(did: string) {
+    return invoke<Record<string, any>>('get_device_state', { did })
+  }
+
+  getRules() {
+    return invoke<Rule[]>('get_rules')
+  }
+
+  saveRule(rule: Rule) {
+    return invoke('save_rule', { rule })
+  }
+
+  deleteRule(name: string) {
+    return invoke('delete_rule', { name })
+  }
+
+  getSchedules() {
+    return invoke<Schedule[]>('get_schedules')
+  }
+
+  saveSchedule(schedule: Schedule) {
+    return invoke('save_schedule', { schedule })
+  }
+
+  deleteSchedule(name: string) {
+    return invoke('delete_schedule', { name })
+  }
+
+  getDeviceGroups() {
+    return invoke<DeviceGroup[]>('get_device_groups')
+  }
+
+  saveDeviceGroup(name: string, dids: string[]) {
+    return invoke('save_device_group', { name, dids })
+  }
+
+  deleteDeviceGroup(name: string) {
+    return invoke('delete_device_group', { name })
+  }
 }
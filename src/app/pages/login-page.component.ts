@@ -1,5 +1,5 @@
 import { CommonModule } from '@angular/common'
-import { Component, computed, effect, inject } from '@angular/core'
+import { Component, computed, effect, inject, signal } from '@angular/core'
 import { IconComponent } from '../icon/icon.component'
 import {
   FormBuilder,
@@ -11,6 +11,7 @@ import { AuthService } from '../auth.service'
 import { Router } from '@angular/router'
 import { injectMutation } from '@tanstack/angular-query-experimental'
 import { MiService } from '../mi.service'
+import { LoginOutcome, TwoFactorMethod } from '../types'
 
 @Component({
   template: `
@@ -24,6 +25,49 @@ import { MiService } from '../mi.service'
         </div>
       }
       
+      @if (challenge(); as challenge) {
+        <form class="flex flex-col gap-2 w-80" (ngSubmit)="submitCode($event)">
+          @if (challenge.type === 'captcha_required') {
+            <img
+              [src]="'data:image/jpeg;base64,' + challenge.image"
+              alt="Captcha"
+              class="rounded-lg bg-white h-16 object-contain"
+            />
+            <span class="text-sm">Enter the characters shown above</span>
+          } @else if (twoFactorMethod()) {
+            <span class="text-sm">Enter the verification code sent to your {{ twoFactorMethod() }}</span>
+          } @else {
+            <span class="text-sm">Xiaomi needs to verify it's you before logging in.</span>
+            <button
+              type="button"
+              class="btn w-full"
+              [disabled]="loading()"
+              (click)="sendCodeMutation.mutate()"
+            >
+              Send verification code
+            </button>
+          }
+
+          @if (challenge.type === 'captcha_required' || twoFactorMethod()) {
+            <label class="input flex items-center gap-2">
+              <input [formControl]="code" type="text" placeholder="Code" autocomplete="one-time-code" />
+            </label>
+            <button
+              [disabled]="code.invalid || loading()"
+              type="submit"
+              class="btn w-full"
+            >
+              @if (loading()) {
+                <span class="loading loading-spinner loading-sm"></span>
+              }
+              Verify
+            </button>
+          }
+          <button type="button" class="btn btn-ghost w-full" (click)="cancelChallenge()">
+            Back
+          </button>
+        </form>
+      } @else {
       <form
         class="flex flex-col gap-2 w-80"
         (ngSubmit)="login($event)"
@@ -70,25 +114,27 @@ import { MiService } from '../mi.service'
       }
     </button>
 
-    @if (loginMutation.isError()) {
+  </form>
+      }
+
+    @if (error()) {
       <div class="toast toast-center">
         <div role="alert" class="alert alert-error">
           <div></div>
           <div>
             <h3 class="font-bold">Login failed</h3>
-            <div class="text-xs">{{ loginMutation.error() }}</div>
+            <div class="text-xs">{{ error() }}</div>
           </div>
           <button
             type="button"
             class="btn btn-sm btn-circle btn-ghost"
-            (click)="loginMutation.reset()"
+            (click)="resetErrors()"
           >
             ✕
           </button>        
         </div>
       </div>
     }
-  </form>
     </div>`,
   styles: `
     :host {
@@ -113,9 +159,30 @@ export class LoginPageComponent {
       country?: string
       should_save_credentials: boolean
     }) => this.authService.login(credentials),
-    onSuccess: () => this.router.navigateByUrl('devices'),
+    onSuccess: (outcome: LoginOutcome) => this.handleOutcome(outcome),
+  }))
+  // Captcha or two-factor code answering the current challenge
+  challengeMutation = injectMutation(() => ({
+    mutationFn: (code: string) =>
+      this.challenge()?.type === 'captcha_required'
+        ? this.authService.submitCaptcha(code)
+        : this.authService.verifyTwoFactor(code),
+    onSuccess: (outcome: LoginOutcome) => this.handleOutcome(outcome),
+  }))
+  sendCodeMutation = injectMutation(() => ({
+    mutationFn: () => this.authService.sendTwoFactorCode(),
+    onSuccess: (method: TwoFactorMethod) => this.twoFactorMethod.set(method),
   }))
-  loading = computed(() => this.loginMutation.isPending())
+  challenge = signal<LoginOutcome | null>(null)
+  twoFactorMethod = signal<TwoFactorMethod | null>(null)
+  loading = computed(() =>
+    this.loginMutation.isPending() ||
+    this.challengeMutation.isPending() ||
+    this.sendCodeMutation.isPending()
+  )
+  error = computed(() =>
+    this.loginMutation.error() ?? this.challengeMutation.error() ?? this.sendCodeMutation.error()
+  )
   countries = this.miService.countries.value
   form = this.fb.nonNullable.group({
     email: this.fb.nonNullable.control('', [Validators.required]),
@@ -123,6 +190,7 @@ export class LoginPageComponent {
     country: this.fb.nonNullable.control('cn', [Validators.required]),
     saveCredentials: this.fb.nonNullable.control(true)
   })
+  code = this.fb.nonNullable.control('', [Validators.required])
   constructor() {
     // Try to fill the form with saved credentials if available
     this.miService.getSavedCredentials().then((creds: { username: string; country: string } | null) => {
@@ -137,9 +205,15 @@ export class LoginPageComponent {
       }
     }).catch((err: Error) => console.error('Error loading saved credentials:', err));
   }
-  disabledFormEffect = effect(() =>
-    this.loading() ? this.form.disable() : this.form.enable()
-  );  
+  disabledFormEffect = effect(() => {
+    if (this.loading()) {
+      this.form.disable()
+      this.code.disable()
+    } else {
+      this.form.enable()
+      this.code.enable()
+    }
+  });  
     async login(event: SubmitEvent) {
     event.preventDefault()
     if (this.form.invalid) return
@@ -152,4 +226,34 @@ export class LoginPageComponent {
       should_save_credentials: saveCredentials ?? true 
     })
   }
+
+  submitCode(event: SubmitEvent) {
+    event.preventDefault()
+    if (this.code.invalid) return
+    this.challengeMutation.mutate(this.code.value.trim())
+  }
+
+  // A new captcha image comes with every wrong answer, so the outcome replaces the challenge
+  private handleOutcome(outcome: LoginOutcome) {
+    this.code.reset()
+    if (outcome.type === 'logged_in') {
+      this.challenge.set(null)
+      this.router.navigateByUrl('devices')
+    } else {
+      this.challenge.set(outcome)
+    }
+  }
+
+  cancelChallenge() {
+    this.challenge.set(null)
+    this.twoFactorMethod.set(null)
+    this.code.reset()
+    this.resetErrors()
+  }
+
+  resetErrors() {
+    this.loginMutation.reset()
+    this.challengeMutation.reset()
+    this.sendCodeMutation.reset()
+  }
 }
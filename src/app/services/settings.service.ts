@@ -5,6 +5,37 @@ export interface AppSettings {
   close_to_tray?: boolean
   auto_start?: boolean
   auto_hide_to_tray?: boolean
+  location?: Location | null
+  rest_api?: RestApiSettings | null
+  mqtt?: MqttSettings | null
+  network?: NetworkSettings | null
+}
+
+export interface RestApiSettings {
+  enabled: boolean
+  port: number
+  token: string
+}
+
+export interface MqttSettings {
+  enabled: boolean
+  host: string
+  port: number
+  username?: string | null
+  password?: string | null
+  home_assistant?: boolean
+}
+
+export interface Location {
+  latitude: number
+  longitude: number
+}
+
+// Options of the Mi Cloud client, empty ones keep the defaults
+export interface NetworkSettings {
+  proxy?: string | null
+  user_agent?: string | null
+  locale?: string | null
 }
 
 @Injectable({
@@ -39,4 +70,20 @@ export class SettingsService {
       autoHideToTray: settings.autoHideToTray
     })
   }
+
+  async saveLocation(location: Location | null): Promise<void> {
+    return invoke('save_location', { location })
+  }
+
+  async saveNetworkSettings(network: NetworkSettings): Promise<void> {
+    return invoke('save_network_settings', { network })
+  }
+
+  async saveRestApiSettings(enabled: boolean, port?: number, regenerateToken?: boolean): Promise<RestApiSettings> {
+    return invoke('save_rest_api_settings', { enabled, port, regenerateToken })
+  }
+
+  async saveMqttSettings(mqtt: MqttSettings): Promise<void> {
+    return invoke('save_mqtt_settings', { mqtt })
+  }
 }
@@ -26,6 +26,197 @@ export type Device = {
   ssid: string
   token: string
   uid: number
+  // Only set by getDevicesAllRegions
+  region?: string
 }
 
 export type GetDevicesResponse = Device[]
+
+export type LoginOutcome =
+  | { type: 'logged_in' }
+  // `image` is the captcha picture, base64 encoded
+  | { type: 'captcha_required'; captcha_url: string; image: string }
+  | { type: 'two_factor_required'; notification_url: string }
+
+export type TwoFactorMethod = 'phone' | 'email'
+
+export type Room = {
+  id: string
+  name: string
+  dids: string[]
+}
+
+export type Home = {
+  id: string
+  name: string
+  uid: number | null
+  dids: string[]
+  rooms: Room[]
+}
+
+export type DevicesByHome = {
+  homes: {
+    id: string
+    name: string
+    rooms: { id: string; name: string; devices: Device[] }[]
+    devices: Device[]
+  }[]
+  other: Device[]
+}
+
+export type Scene = {
+  id: string
+  name: string
+  home_id: string
+  kind: 'manual' | 'automation'
+  enabled: boolean
+}
+
+export type ExportFormat = 'json' | 'csv' | 'python_miio_yaml' | 'home_assistant_yaml'
+
+export type MiioError = {
+  kind:
+    | 'transport'
+    | 'http'
+    | 'not_logged_in'
+    | 'auth_expired'
+    | 'unsupported_region'
+    | 'cloud'
+    | 'parse'
+    | 'login'
+    | 'invalid'
+    | 'io'
+  code: number | null
+  message: string
+}
+
+export type DeviceFilter = {
+  model?: string
+  home?: string
+  room?: string
+}
+
+export type CommandTarget = ({ type: 'group'; name: string }) | ({ type: 'filter' } & DeviceFilter)
+
+export type DeviceGroup = {
+  name: string
+  dids: string[]
+}
+
+export type DeviceResult = {
+  did: string
+  name: string
+  success: boolean
+  result?: any
+  error?: string
+}
+
+export type CommandReport = {
+  command: string
+  results: DeviceResult[]
+}
+
+export type MacroStep = {
+  did: string
+  method: string
+  params: string
+  delay_ms?: number
+  continue_on_error?: boolean
+}
+
+export type CommandMacro = {
+  name: string
+  steps: MacroStep[]
+  shortcut?: string
+}
+
+export type StepResult = {
+  step: number
+  did: string
+  method: string
+  success: boolean
+  result?: any
+  error?: string
+}
+
+export type MacroReport = {
+  name: string
+  results: StepResult[]
+  aborted: boolean
+}
+
+export type ScheduleTrigger =
+  | { type: 'cron'; expression: string }
+  | { type: 'interval'; seconds: number }
+  | { type: 'once'; at: string }
+  | {
+      type: 'sun'
+      event: 'sunrise' | 'sunset'
+      offset_minutes?: number
+      did?: string
+      latitude?: number
+      longitude?: number
+    }
+
+export type ScheduleAction = { type: 'command'; name: string } | { type: 'macro'; name: string }
+
+export type Schedule = {
+  name: string
+  trigger: ScheduleTrigger
+  action: ScheduleAction
+  enabled?: boolean
+  missed_runs?: 'skip' | 'run_once'
+  last_run?: string | null
+  // Latest run missed while the app was closed, and how many were missed in total
+  last_missed?: string | null
+  missed_count?: number
+  next_run?: string | null
+}
+
+export type ScheduleRun = {
+  name: string
+  scheduled_for: string
+  missed: boolean
+  error?: string
+}
+
+export type PollTarget = {
+  did: string
+  interval_secs: number
+  props?: string[]
+  miot_props?: { siid: number; piid: number }[]
+}
+
+export type PropertyChange = {
+  property: string
+  old: any | null
+  new: any
+}
+
+export type DeviceStateChange = {
+  did: string
+  changes: PropertyChange[]
+}
+
+export type RuleCondition =
+  | { type: 'changes' }
+  | { type: 'equals'; value: any }
+  | { type: 'crosses'; value: number; direction: 'rising' | 'falling' | 'either' }
+
+export type Rule = {
+  name: string
+  did: string
+  property: string
+  condition: RuleCondition
+  window?: { start: string; end: string }
+  command: string
+  cooldown_secs?: number
+  enabled?: boolean
+  last_fired?: string | null
+}
+
+export type RuleRun = {
+  rule: string
+  command: string
+  error?: string
+}